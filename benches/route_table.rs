@@ -0,0 +1,109 @@
+// Measures what setting up a connection costs the server with a small and a large route table.
+// The table is shared between connections, so the allocations per connection should not grow
+// with the number of routes.
+//
+//     cargo bench --bench route_table
+
+use std::{
+    alloc::{GlobalAlloc, Layout, System},
+    collections::HashMap,
+    net::SocketAddr,
+    sync::atomic::{AtomicUsize, Ordering},
+    time::Instant,
+};
+
+use async_std::{io::ReadExt, net::TcpStream, task};
+use futures_lite::AsyncWriteExt;
+use rust_http_framework::*;
+
+const ROUTE_COUNTS: [usize; 3] = [1, 50, 500];
+const CONNECTIONS: usize = 2_000;
+
+struct CountingAllocator;
+
+static ALLOCATIONS: AtomicUsize = AtomicUsize::new(0);
+
+unsafe impl GlobalAlloc for CountingAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+}
+
+#[global_allocator]
+static GLOBAL: CountingAllocator = CountingAllocator;
+
+fn handler(_req: &Request) -> Response {
+    Response::new(StatusCodes::OK, HashMap::new(), "")
+}
+
+fn build_routes(count: usize) -> Vec<Route> {
+    (0..count)
+        .map(|idx| Route {
+            handler,
+            method: HTTPMethod::GET,
+            uri_parser: UriParser::from(&format!("/resources{}/:id/items/:item_id", idx)),
+        })
+        .collect()
+}
+
+// Opens a connection, sends a request and reads the response until the server closes the
+// connection. No route handles DELETE, so the routes are skipped on their method without matching
+// their paths, which would allocate for every route and hide the cost of the connection itself.
+async fn connect(addr: SocketAddr) {
+    let mut stream = TcpStream::connect(addr).await.unwrap();
+    stream
+        .write_all(b"DELETE /resources0/1/items/2 HTTP/1.1\r\nHost: bench\r\nConnection: close\r\n\r\n")
+        .await
+        .unwrap();
+
+    let mut response = Vec::new();
+    stream.read_to_end(&mut response).await.unwrap();
+    assert!(response.starts_with(b"HTTP/1.1 404"));
+}
+
+// Prints the allocations and time per connection, counting both the client and the server
+async fn measure(route_count: usize) {
+    let config = ServerConfig::builder().listen("127.0.0.1:0").handle_signals(false).build().unwrap();
+    let mut server = Server::from_config(config).unwrap();
+    server.add_routes(&mut build_routes(route_count));
+
+    let server = server.spawn().await.unwrap();
+    let addr = server.local_addr();
+
+    // Warm up, so that the thread pools are running before counting
+    for _ in 0..100 {
+        connect(addr).await;
+    }
+
+    let allocations = ALLOCATIONS.load(Ordering::Relaxed);
+    let start = Instant::now();
+
+    for _ in 0..CONNECTIONS {
+        connect(addr).await;
+    }
+
+    let elapsed = start.elapsed();
+    let allocations = ALLOCATIONS.load(Ordering::Relaxed) - allocations;
+
+    println!(
+        "{:>4} routes {:>8.1} allocations/connection {:>8.1} µs/connection",
+        route_count,
+        allocations as f64 / CONNECTIONS as f64,
+        elapsed.as_micros() as f64 / CONNECTIONS as f64
+    );
+
+    server.shutdown().await.unwrap();
+}
+
+fn main() {
+    task::block_on(async {
+        for route_count in ROUTE_COUNTS {
+            measure(route_count).await;
+        }
+    });
+}
@@ -0,0 +1,57 @@
+// Feeds arbitrary bytes to RequestParser, split into chunks of arbitrary sizes, and checks that
+// parsing incrementally gives the same result as parsing everything at once.
+//
+//     cargo +nightly fuzz run request_parser
+
+#![no_main]
+
+use http_types::{ParseStatus, ParserLimits, RequestParser};
+use libfuzzer_sys::fuzz_target;
+
+fn limits() -> ParserLimits {
+    // Small limits so that the fuzzer reaches them
+    ParserLimits {
+        max_request_line_size: 64,
+        max_header_size: 256,
+        max_header_count: 8,
+    }
+}
+
+fuzz_target!(|data: &[u8]| {
+    let Some((&chunk_size, input)) = data.split_first() else {
+        return;
+    };
+    let chunk_size = chunk_size as usize + 1;
+
+    let mut whole = RequestParser::new(limits());
+    let expected = whole.parse(input);
+
+    let mut incremental = RequestParser::new(limits());
+    let mut result = Ok(ParseStatus::Partial);
+    let mut end = 0;
+
+    while end < input.len() && result == Ok(ParseStatus::Partial) {
+        end = (end + chunk_size).min(input.len());
+        result = incremental.parse(&input[..end]);
+    }
+
+    // Errors are reported as soon as they are certain, so a chunk may fail before the whole input
+    // does. A complete head is found in the same place either way.
+    match (expected, result) {
+        (Ok(ParseStatus::Complete(a)), Ok(ParseStatus::Complete(b))) => assert_eq!(a, b),
+        (Ok(ParseStatus::Partial), Ok(ParseStatus::Partial)) => {}
+        (Err(a), Err(b)) => assert_eq!(a, b),
+        (expected, result) => panic!("{:?} != {:?}", expected, result),
+    }
+
+    if let Ok(ParseStatus::Complete(size)) = expected {
+        let head = whole.head(input).unwrap();
+        let other = incremental.head(&input[..end]).unwrap();
+
+        assert_eq!(head.size(), size);
+        assert_eq!(head.method(), other.method());
+        assert_eq!(head.target(), other.target());
+        assert_eq!(head.version(), other.version());
+        assert!(head.headers().eq(other.headers()));
+    }
+});
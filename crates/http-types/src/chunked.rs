@@ -0,0 +1,292 @@
+use std::fmt::Display;
+
+use crate::{message::is_field_value, status_codes::StatusCodes};
+
+// Longest chunk size line accepted, extensions included. Extensions are ignored, so there is
+// no reason to let them take up more than this.
+const MAX_CHUNK_LINE_SIZE: usize = 4096;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ChunkedStatus {
+    // The body is complete and took up this many bytes of the last input.
+    // Anything after it belongs to the next request.
+    Complete(usize),
+    // More data is needed to finish the body
+    Partial,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ChunkedError {
+    InvalidChunkSize,
+    ChunkLineTooLong,
+    // The data of a chunk is not followed by CRLF
+    InvalidChunk,
+    InvalidTrailer,
+    BodyTooLarge,
+    TrailersTooLarge,
+}
+
+impl ChunkedError {
+    // The status code to answer the request with
+    pub fn status(&self) -> StatusCodes {
+        match self {
+            ChunkedError::BodyTooLarge => StatusCodes::RequestEntityTooLarge,
+            ChunkedError::TrailersTooLarge => StatusCodes::RequestHeaderFieldsTooLarge,
+            _ => StatusCodes::BadRequest,
+        }
+    }
+}
+
+impl Display for ChunkedError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let message = match self {
+            ChunkedError::InvalidChunkSize => "invalid chunk size",
+            ChunkedError::ChunkLineTooLong => "chunk size line too long",
+            ChunkedError::InvalidChunk => "chunk data not followed by a line break",
+            ChunkedError::InvalidTrailer => "malformed trailer line",
+            ChunkedError::BodyTooLarge => "chunked body too large",
+            ChunkedError::TrailersTooLarge => "trailers too large",
+        };
+
+        write!(f, "{}", message)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum State {
+    Size { size: usize, digits: usize },
+    // Everything between the size and the end of its line
+    Extension { size: usize },
+    SizeLf { size: usize },
+    Data { remaining: usize },
+    DataCr,
+    DataLf,
+    // At the start of a trailer line, or of the empty line that ends the body
+    TrailerStart,
+    Trailer,
+    TrailerLf,
+    EndLf,
+    Done,
+}
+
+// Decodes a body sent with the chunked transfer coding, RFC 9112, section 7.1. Feed it the bytes
+// as they arrive, it keeps the decoded data and stops at the end of the body. Line breaks have
+// to be CRLF, since a bare LF is read differently by different implementations. Trailers are
+// checked and dropped.
+#[derive(Debug, Clone)]
+pub struct ChunkedDecoder {
+    state: State,
+    max_body_size: usize,
+    max_trailer_size: usize,
+    // Decoded data that was not taken yet, and the size of the whole body so far
+    body: Vec<u8>,
+    body_size: usize,
+    // Length of the chunk size line being read
+    line_size: usize,
+    trailer_size: usize,
+}
+
+impl ChunkedDecoder {
+    pub fn new(max_body_size: usize, max_trailer_size: usize) -> ChunkedDecoder {
+        ChunkedDecoder {
+            state: State::Size { size: 0, digits: 0 },
+            max_body_size,
+            max_trailer_size,
+            body: Vec::new(),
+            body_size: 0,
+            line_size: 0,
+            trailer_size: 0,
+        }
+    }
+
+    // Decodes the next bytes of the body. Unlike RequestParser, every call takes only the bytes
+    // that arrived since the previous one.
+    pub fn decode(&mut self, input: &[u8]) -> Result<ChunkedStatus, ChunkedError> {
+        let mut position = 0;
+
+        while position < input.len() && self.state != State::Done {
+            // Chunk data is copied as a whole rather than byte by byte
+            if let State::Data { remaining } = self.state {
+                let take = remaining.min(input.len() - position);
+                self.body.extend_from_slice(&input[position..position + take]);
+                self.body_size += take;
+                position += take;
+
+                self.state = match remaining - take {
+                    0 => State::DataCr,
+                    remaining => State::Data { remaining },
+                };
+
+                continue;
+            }
+
+            self.decode_byte(input[position])?;
+            position += 1;
+        }
+
+        match self.state {
+            State::Done => Ok(ChunkedStatus::Complete(position)),
+            _ => Ok(ChunkedStatus::Partial),
+        }
+    }
+
+    // The decoded body, complete once decode returned Complete
+    pub fn into_body(self) -> Vec<u8> {
+        self.body
+    }
+
+    // Takes the data decoded since the last call, for bodies that are handled as they arrive.
+    // into_body then only returns what was decoded after it.
+    pub fn take_decoded(&mut self) -> Vec<u8> {
+        std::mem::take(&mut self.body)
+    }
+
+    fn decode_byte(&mut self, byte: u8) -> Result<(), ChunkedError> {
+        if matches!(self.state, State::Size { .. } | State::Extension { .. }) {
+            self.line_size += 1;
+
+            if self.line_size > MAX_CHUNK_LINE_SIZE {
+                return Err(ChunkedError::ChunkLineTooLong);
+            }
+        }
+
+        if matches!(self.state, State::TrailerStart | State::Trailer | State::TrailerLf | State::EndLf) {
+            self.trailer_size += 1;
+
+            if self.trailer_size > self.max_trailer_size {
+                return Err(ChunkedError::TrailersTooLarge);
+            }
+        }
+
+        self.state = match (self.state, byte) {
+            (State::Size { size, digits }, _) if byte.is_ascii_hexdigit() => {
+                let digit = (byte as char).to_digit(16).unwrap_or_default() as usize;
+
+                // Checked on every digit, so that the size can not overflow either
+                if size > (self.max_body_size - self.body_size) / 16 {
+                    return Err(ChunkedError::BodyTooLarge);
+                }
+
+                let size = size * 16 + digit;
+                if size > self.max_body_size - self.body_size {
+                    return Err(ChunkedError::BodyTooLarge);
+                }
+
+                State::Size { size, digits: digits + 1 }
+            }
+            (State::Size { size, digits }, b';' | b' ' | b'\t') if digits > 0 => State::Extension { size },
+            (State::Size { size, digits }, b'\r') if digits > 0 => State::SizeLf { size },
+            (State::Size { .. }, _) => return Err(ChunkedError::InvalidChunkSize),
+
+            (State::Extension { size }, b'\r') => State::SizeLf { size },
+            (State::Extension { size }, _) if is_field_value(&[byte]) => State::Extension { size },
+            (State::Extension { .. }, _) => return Err(ChunkedError::InvalidChunkSize),
+
+            (State::SizeLf { size: 0 }, b'\n') => State::TrailerStart,
+            (State::SizeLf { size }, b'\n') => State::Data { remaining: size },
+            (State::SizeLf { .. }, _) => return Err(ChunkedError::InvalidChunkSize),
+
+            (State::DataCr, b'\r') => State::DataLf,
+            (State::DataLf, b'\n') => {
+                self.line_size = 0;
+                State::Size { size: 0, digits: 0 }
+            }
+            (State::DataCr | State::DataLf, _) => return Err(ChunkedError::InvalidChunk),
+
+            (State::TrailerStart, b'\r') => State::EndLf,
+            (State::Trailer, b'\r') => State::TrailerLf,
+            (State::TrailerStart | State::Trailer, _) if is_field_value(&[byte]) => State::Trailer,
+            (State::TrailerLf, b'\n') => State::TrailerStart,
+            (State::EndLf, b'\n') => State::Done,
+            (State::TrailerStart | State::Trailer | State::TrailerLf | State::EndLf, _) => {
+                return Err(ChunkedError::InvalidTrailer);
+            }
+
+            (State::Data { .. } | State::Done, _) => self.state,
+        };
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn decoder() -> ChunkedDecoder {
+        ChunkedDecoder::new(1024, 256)
+    }
+
+    #[test]
+    fn decodes_chunks() {
+        let input = b"5\r\nhello\r\n7;name=value\r\n, world\r\n0\r\n\r\nGET";
+        let mut decoder = decoder();
+
+        assert_eq!(decoder.decode(input), Ok(ChunkedStatus::Complete(input.len() - 3)));
+        assert_eq!(decoder.into_body(), b"hello, world");
+    }
+
+    #[test]
+    fn decodes_across_every_split_point() {
+        let input = b"a\r\n0123456789\r\n1\r\n!\r\n0\r\nExpires: never\r\n\r\n";
+
+        for split in 0..input.len() {
+            let mut decoder = decoder();
+
+            assert_eq!(decoder.decode(&input[..split]), Ok(ChunkedStatus::Partial), "{}", split);
+            assert_eq!(decoder.decode(&input[split..]), Ok(ChunkedStatus::Complete(input.len() - split)));
+            assert_eq!(decoder.into_body(), b"0123456789!");
+        }
+    }
+
+    #[test]
+    fn hands_out_data_as_it_is_decoded() {
+        let mut decoder = ChunkedDecoder::new(12, 256);
+
+        assert_eq!(decoder.decode(b"5\r\nhello\r\n4\r\n, w"), Ok(ChunkedStatus::Partial));
+        assert_eq!(decoder.take_decoded(), b"hello, w");
+        assert_eq!(decoder.decode(b"o\r\n"), Ok(ChunkedStatus::Partial));
+        assert_eq!(decoder.take_decoded(), b"o");
+
+        // Taken data still counts towards the size limit
+        assert_eq!(decoder.decode(b"4\r\n"), Err(ChunkedError::BodyTooLarge));
+    }
+
+    #[test]
+    fn requires_crlf() {
+        assert_eq!(decoder().decode(b"5\nhello\r\n"), Err(ChunkedError::InvalidChunkSize));
+        assert_eq!(decoder().decode(b"5\r\nhello\n0"), Err(ChunkedError::InvalidChunk));
+        assert_eq!(decoder().decode(b"5\r\nhelloXX"), Err(ChunkedError::InvalidChunk));
+        assert_eq!(decoder().decode(b"0\r\n\n"), Err(ChunkedError::InvalidTrailer));
+    }
+
+    #[test]
+    fn rejects_invalid_sizes() {
+        assert_eq!(decoder().decode(b"\r\n"), Err(ChunkedError::InvalidChunkSize));
+        assert_eq!(decoder().decode(b"-1\r\n"), Err(ChunkedError::InvalidChunkSize));
+        assert_eq!(decoder().decode(b"0x5\r\n"), Err(ChunkedError::InvalidChunkSize));
+    }
+
+    #[test]
+    fn enforces_the_body_size() {
+        assert_eq!(decoder().decode(b"401\r\n"), Err(ChunkedError::BodyTooLarge));
+        assert_eq!(decoder().decode(b"ffffffffffffffffffff\r\n"), Err(ChunkedError::BodyTooLarge));
+
+        let mut decoder = decoder();
+        let chunk = [b"200\r\n".as_slice(), &[b'a'; 0x200], b"\r\n"].concat();
+
+        assert_eq!(decoder.decode(&chunk), Ok(ChunkedStatus::Partial));
+        assert_eq!(decoder.decode(&chunk), Ok(ChunkedStatus::Partial));
+        assert_eq!(decoder.decode(b"1\r\n"), Err(ChunkedError::BodyTooLarge));
+        assert_eq!(ChunkedError::BodyTooLarge.status().as_code(), 413);
+    }
+
+    #[test]
+    fn enforces_the_line_and_trailer_sizes() {
+        let line = [b"1;".as_slice(), &[b'a'; MAX_CHUNK_LINE_SIZE]].concat();
+        assert_eq!(decoder().decode(&line), Err(ChunkedError::ChunkLineTooLong));
+
+        let trailer = [b"0\r\nX: ".as_slice(), &[b'a'; 256]].concat();
+        assert_eq!(decoder().decode(&trailer), Err(ChunkedError::TrailersTooLarge));
+    }
+}
@@ -0,0 +1,29 @@
+use std::net::IpAddr;
+
+// The certificate a client authenticated with over mutual TLS. It was verified against the
+// CAs configured on the server before the request was read, so handlers can authorize by it.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ClientCertificate {
+    // The distinguished name, e.g. "CN=billing, O=Example Corp"
+    pub subject: String,
+    pub common_name: Option<String>,
+    // Subject alternative names, by type
+    pub dns_names: Vec<String>,
+    // e.g. SPIFFE IDs like "spiffe://example.org/billing"
+    pub uris: Vec<String>,
+    pub emails: Vec<String>,
+    pub ip_addresses: Vec<IpAddr>,
+    pub serial_number: String,
+    // The whole certificate, DER encoded, for checks the fields above don't cover
+    pub der: Vec<u8>,
+}
+
+impl ClientCertificate {
+    // Whether the certificate was issued for the identity, either as its common name or
+    // as one of its DNS or URI alternative names
+    pub fn has_identity(&self, identity: &str) -> bool {
+        self.common_name.as_deref() == Some(identity)
+            || self.dns_names.iter().any(|name| name.eq_ignore_ascii_case(identity))
+            || self.uris.iter().any(|uri| uri == identity)
+    }
+}
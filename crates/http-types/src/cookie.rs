@@ -0,0 +1,178 @@
+use std::time::SystemTime;
+
+use crate::http_date::format_http_date;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SameSite {
+    Strict,
+    Lax,
+    None,
+}
+
+impl SameSite {
+    pub fn as_str(&self) -> &str {
+        match self {
+            SameSite::Strict => "Strict",
+            SameSite::Lax => "Lax",
+            SameSite::None => "None",
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Cookie {
+    pub name: String,
+    pub value: String,
+    pub path: Option<String>,
+    pub domain: Option<String>,
+    pub max_age: Option<i64>,
+    pub expires: Option<SystemTime>,
+    pub secure: bool,
+    pub http_only: bool,
+    pub same_site: Option<SameSite>,
+    pub partitioned: bool,
+}
+
+impl Cookie {
+    pub fn new(name: &str, value: &str) -> Cookie {
+        Cookie {
+            name: name.to_owned(),
+            value: value.to_owned(),
+            path: None,
+            domain: None,
+            max_age: None,
+            expires: None,
+            secure: false,
+            http_only: false,
+            same_site: None,
+            partitioned: false,
+        }
+    }
+
+    // A cookie that tells the client to delete the cookie with the given name
+    pub fn removal(name: &str) -> Cookie {
+        Cookie {
+            max_age: Some(0),
+            expires: Some(SystemTime::UNIX_EPOCH),
+            ..Cookie::new(name, "")
+        }
+    }
+
+    // Checks the name and value against the grammar in RFC 6265, section 4.1.1,
+    // so that they can not break out of the Set-Cookie header.
+    pub fn is_valid(&self) -> bool {
+        let name_is_valid = !self.name.is_empty() && self.name.bytes().all(is_token_byte);
+
+        let value = match self.value.strip_prefix('"').and_then(|v| v.strip_suffix('"')) {
+            Some(unquoted) => unquoted,
+            None => &self.value,
+        };
+        let value_is_valid = value.bytes().all(is_cookie_octet);
+
+        let attribute_is_valid = |attribute: &Option<String>| match attribute {
+            Some(attribute) => !attribute.bytes().any(|b| b == b';' || b.is_ascii_control()),
+            None => true,
+        };
+
+        name_is_valid && value_is_valid && attribute_is_valid(&self.path) && attribute_is_valid(&self.domain)
+    }
+
+    // The value of the Set-Cookie header for this cookie
+    pub fn to_header_value(&self) -> String {
+        let mut header_value = format!("{}={}", self.name, self.value);
+
+        if let Some(path) = &self.path {
+            header_value.push_str(&format!("; Path={}", path));
+        }
+        if let Some(domain) = &self.domain {
+            header_value.push_str(&format!("; Domain={}", domain));
+        }
+        if let Some(max_age) = self.max_age {
+            header_value.push_str(&format!("; Max-Age={}", max_age));
+        }
+        if let Some(expires) = self.expires {
+            header_value.push_str(&format!("; Expires={}", format_http_date(expires)));
+        }
+        if self.secure {
+            header_value.push_str("; Secure");
+        }
+        if self.http_only {
+            header_value.push_str("; HttpOnly");
+        }
+        if let Some(same_site) = self.same_site {
+            header_value.push_str(&format!("; SameSite={}", same_site.as_str()));
+        }
+        if self.partitioned {
+            header_value.push_str("; Partitioned");
+        }
+
+        header_value
+    }
+}
+
+// The cookies sent by the client in the cookie header
+#[derive(Debug, Clone, Default)]
+pub struct CookieJar {
+    cookies: Vec<Cookie>,
+}
+
+impl CookieJar {
+    pub fn new() -> CookieJar {
+        CookieJar { cookies: Vec::new() }
+    }
+
+    // Parses a cookie header such as "session=abc; theme=dark". Malformed pairs are ignored.
+    pub fn from(cookie_header: &str) -> CookieJar {
+        let mut cookies = Vec::new();
+
+        for pair in cookie_header.split(';') {
+            let Some((name, value)) = pair.split_once('=') else {
+                continue;
+            };
+
+            let name = name.trim();
+            let value = value.trim();
+            let value = match value.strip_prefix('"').and_then(|v| v.strip_suffix('"')) {
+                Some(unquoted) => unquoted,
+                None => value,
+            };
+
+            if name.is_empty() {
+                continue;
+            }
+
+            cookies.push(Cookie::new(name, value));
+        }
+
+        CookieJar { cookies }
+    }
+
+    // Gets the first cookie with the given name
+    pub fn get(&self, name: &str) -> Option<&Cookie> {
+        self.cookies.iter().find(|cookie| cookie.name == name)
+    }
+
+    pub fn add(&mut self, cookie: Cookie) {
+        self.cookies.push(cookie);
+    }
+
+    pub fn iter(&self) -> std::slice::Iter<'_, Cookie> {
+        self.cookies.iter()
+    }
+
+    pub fn len(&self) -> usize {
+        self.cookies.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.cookies.is_empty()
+    }
+}
+
+fn is_token_byte(byte: u8) -> bool {
+    byte.is_ascii_graphic() && !b"()<>@,;:\\\"/[]?={}".contains(&byte)
+}
+
+fn is_cookie_octet(byte: u8) -> bool {
+    matches!(byte, 0x21 | 0x23..=0x2B | 0x2D..=0x3A | 0x3C..=0x5B | 0x5D..=0x7E)
+}
@@ -0,0 +1,314 @@
+use std::sync::{Arc, OnceLock};
+
+use aes_gcm::{
+    aead::{rand_core::RngCore, Aead, AeadCore, KeyInit, OsRng, Payload},
+    Aes256Gcm, Nonce,
+};
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+
+use crate::cookie::Cookie;
+
+type HmacSha256 = Hmac<Sha256>;
+
+const NONCE_SIZE: usize = 12;
+
+// A master key from which the signing and encryption keys are derived
+#[derive(Clone)]
+pub struct CookieKey {
+    signing: [u8; 32],
+    encryption: [u8; 32],
+}
+
+impl CookieKey {
+    // The master key must be at least 32 bytes long
+    pub fn from(master: &[u8]) -> Result<CookieKey, String> {
+        if master.len() < 32 {
+            return Err(format!("Cookie keys must be at least 32 bytes long, got {}", master.len()));
+        }
+
+        Ok(CookieKey {
+            signing: CookieKey::derive(master, b"cookie-signing"),
+            encryption: CookieKey::derive(master, b"cookie-encryption"),
+        })
+    }
+
+    pub fn generate() -> CookieKey {
+        let mut master = [0u8; 32];
+        OsRng.fill_bytes(&mut master);
+
+        CookieKey {
+            signing: CookieKey::derive(&master, b"cookie-signing"),
+            encryption: CookieKey::derive(&master, b"cookie-encryption"),
+        }
+    }
+
+    fn derive(master: &[u8], purpose: &[u8]) -> [u8; 32] {
+        let mut mac = <HmacSha256 as Mac>::new_from_slice(master).expect("HMAC accepts keys of any size");
+        mac.update(purpose);
+
+        mac.finalize().into_bytes().into()
+    }
+
+    // The MAC covers the name as well, so that a value can not be moved to another cookie
+    fn mac(&self, name: &str, value: &str) -> HmacSha256 {
+        let mut mac = <HmacSha256 as Mac>::new_from_slice(&self.signing).expect("HMAC accepts keys of any size");
+        mac.update(name.as_bytes());
+        mac.update(b"=");
+        mac.update(value.as_bytes());
+
+        mac
+    }
+
+    fn verify(&self, name: &str, signed_value: &str) -> Option<String> {
+        let (tag, value) = signed_value.split_once('.')?;
+        let tag = URL_SAFE_NO_PAD.decode(tag).ok()?;
+
+        self.mac(name, value).verify_slice(&tag).ok()?;
+
+        Some(value.to_owned())
+    }
+
+    fn decrypt(&self, name: &str, encrypted_value: &str) -> Option<String> {
+        let data = URL_SAFE_NO_PAD.decode(encrypted_value).ok()?;
+
+        if data.len() < NONCE_SIZE {
+            return None;
+        }
+
+        let (nonce, ciphertext) = data.split_at(NONCE_SIZE);
+        let cipher = Aes256Gcm::new_from_slice(&self.encryption).ok()?;
+        let payload = Payload {
+            msg: ciphertext,
+            aad: name.as_bytes(),
+        };
+        let plaintext = cipher.decrypt(Nonce::from_slice(nonce), payload).ok()?;
+
+        String::from_utf8(plaintext).ok()
+    }
+}
+
+// The keys used to sign and encrypt cookies. Cookies are always written with the current key,
+// while previous keys are still accepted when reading so that keys can be rotated.
+#[derive(Clone)]
+pub struct CookieKeys {
+    pub current: CookieKey,
+    pub previous: Vec<CookieKey>,
+}
+
+// Never print the keys themselves
+impl std::fmt::Debug for CookieKeys {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("CookieKeys")
+            .field("previous", &self.previous.len())
+            .finish_non_exhaustive()
+    }
+}
+
+impl CookieKeys {
+    pub fn new(current: CookieKey) -> CookieKeys {
+        CookieKeys {
+            current,
+            previous: Vec::new(),
+        }
+    }
+
+    pub fn with_previous(current: CookieKey, previous: Vec<CookieKey>) -> CookieKeys {
+        CookieKeys { current, previous }
+    }
+
+    // Random keys shared by the whole process, used when no keys are configured.
+    // Cookies written with them do not survive a restart.
+    pub fn ephemeral() -> Arc<CookieKeys> {
+        static EPHEMERAL: OnceLock<Arc<CookieKeys>> = OnceLock::new();
+
+        EPHEMERAL
+            .get_or_init(|| Arc::new(CookieKeys::new(CookieKey::generate())))
+            .clone()
+    }
+
+    // Appends a signature to the value. The value itself stays readable by the client.
+    pub fn sign(&self, mut cookie: Cookie) -> Cookie {
+        let tag = self.current.mac(&cookie.name, &cookie.value).finalize().into_bytes();
+
+        cookie.value = format!("{}.{}", URL_SAFE_NO_PAD.encode(tag), cookie.value);
+        cookie
+    }
+
+    // Returns the cookie with its original value, or None if the signature is not valid for any key
+    pub fn verify(&self, cookie: &Cookie) -> Option<Cookie> {
+        let value = self.keys().find_map(|key| key.verify(&cookie.name, &cookie.value))?;
+
+        Some(Cookie {
+            value,
+            ..cookie.clone()
+        })
+    }
+
+    // Encrypts and authenticates the value, so the client can neither read nor modify it
+    pub fn encrypt(&self, mut cookie: Cookie) -> Cookie {
+        let cipher = Aes256Gcm::new_from_slice(&self.current.encryption).expect("key is 32 bytes long");
+        let nonce = Aes256Gcm::generate_nonce(&mut OsRng);
+        let payload = Payload {
+            msg: cookie.value.as_bytes(),
+            aad: cookie.name.as_bytes(),
+        };
+        let ciphertext = cipher.encrypt(&nonce, payload).expect("encryption of in-memory data can not fail");
+
+        let mut data = nonce.to_vec();
+        data.extend_from_slice(&ciphertext);
+
+        cookie.value = URL_SAFE_NO_PAD.encode(data);
+        cookie
+    }
+
+    // Returns the cookie with its decrypted value, or None if it can not be decrypted with any key
+    pub fn decrypt(&self, cookie: &Cookie) -> Option<Cookie> {
+        let value = self.keys().find_map(|key| key.decrypt(&cookie.name, &cookie.value))?;
+
+        Some(Cookie {
+            value,
+            ..cookie.clone()
+        })
+    }
+
+    fn keys(&self) -> impl Iterator<Item = &CookieKey> {
+        std::iter::once(&self.current).chain(self.previous.iter())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn key(byte: u8) -> CookieKey {
+        CookieKey::from(&[byte; 32]).unwrap()
+    }
+
+    // Flips a bit of the byte at idx of the base64 encoded data in the value
+    fn tamper(value: &str, idx: usize) -> String {
+        let mut data = URL_SAFE_NO_PAD.decode(value).unwrap();
+        data[idx] ^= 0x01;
+        URL_SAFE_NO_PAD.encode(data)
+    }
+
+    #[test]
+    fn rejects_short_master_keys() {
+        assert!(CookieKey::from(&[0; 31]).is_err());
+        assert!(CookieKey::from(&[0; 64]).is_ok());
+    }
+
+    #[test]
+    fn signs_and_verifies() {
+        let keys = CookieKeys::new(key(1));
+        let signed = keys.sign(Cookie::new("user", "42"));
+
+        assert!(signed.value.ends_with(".42"));
+        assert_eq!(keys.verify(&signed).unwrap().value, "42");
+    }
+
+    #[test]
+    fn rejects_tampered_signatures() {
+        let keys = CookieKeys::new(key(1));
+        let signed = keys.sign(Cookie::new("user", "42"));
+        let (tag, _) = signed.value.split_once('.').unwrap();
+
+        // A modified value under the original signature
+        let forged = Cookie::new("user", &format!("{}.43", tag));
+        assert!(keys.verify(&forged).is_none());
+
+        // A modified signature
+        let forged = Cookie::new("user", &format!("{}.42", tamper(tag, 0)));
+        assert!(keys.verify(&forged).is_none());
+
+        for value in ["42", ".42", "not-base64!.42", ""] {
+            assert!(keys.verify(&Cookie::new("user", value)).is_none(), "{}", value);
+        }
+
+        // Signed with a key that is not configured
+        let other = CookieKeys::new(key(2)).sign(Cookie::new("user", "42"));
+        assert!(keys.verify(&other).is_none());
+    }
+
+    #[test]
+    fn encrypts_and_decrypts() {
+        let keys = CookieKeys::new(key(1));
+        let encrypted = keys.encrypt(Cookie::new("user", "secret-value"));
+
+        assert!(!encrypted.value.contains("secret-value"));
+        assert_eq!(keys.decrypt(&encrypted).unwrap().value, "secret-value");
+
+        // A new nonce every time
+        assert_ne!(keys.encrypt(Cookie::new("user", "secret-value")).value, encrypted.value);
+    }
+
+    #[test]
+    fn rejects_tampered_ciphertexts() {
+        let keys = CookieKeys::new(key(1));
+        let encrypted = keys.encrypt(Cookie::new("user", "42"));
+        let len = URL_SAFE_NO_PAD.decode(&encrypted.value).unwrap().len();
+
+        // In the nonce, the ciphertext and the authentication tag
+        for idx in [0, NONCE_SIZE, len - 1] {
+            let forged = Cookie::new("user", &tamper(&encrypted.value, idx));
+            assert!(keys.decrypt(&forged).is_none(), "{}", idx);
+        }
+
+        for value in ["", "not-base64!", "AAAA"] {
+            assert!(keys.decrypt(&Cookie::new("user", value)).is_none(), "{}", value);
+        }
+
+        let other = CookieKeys::new(key(2)).encrypt(Cookie::new("user", "42"));
+        assert!(keys.decrypt(&other).is_none());
+    }
+
+    #[test]
+    fn accepts_previous_keys() {
+        let old = CookieKeys::new(key(1));
+        let signed = old.sign(Cookie::new("user", "42"));
+        let encrypted = old.encrypt(Cookie::new("user", "42"));
+
+        let rotated = CookieKeys::with_previous(key(2), vec![key(3), key(1)]);
+        assert_eq!(rotated.verify(&signed).unwrap().value, "42");
+        assert_eq!(rotated.decrypt(&encrypted).unwrap().value, "42");
+
+        // New cookies are written with the current key only
+        let resigned = rotated.sign(Cookie::new("user", "42"));
+        assert!(old.verify(&resigned).is_none());
+        assert!(CookieKeys::new(key(2)).verify(&resigned).is_some());
+
+        // Dropping the old key from previous makes its cookies invalid
+        let retired = CookieKeys::with_previous(key(2), vec![key(3)]);
+        assert!(retired.verify(&signed).is_none());
+        assert!(retired.decrypt(&encrypted).is_none());
+    }
+
+    #[test]
+    fn binds_values_to_the_cookie_name() {
+        let keys = CookieKeys::new(key(1));
+
+        let signed = keys.sign(Cookie::new("role", "admin"));
+        let moved = Cookie::new("other", &signed.value);
+        assert!(keys.verify(&moved).is_none());
+
+        let encrypted = keys.encrypt(Cookie::new("role", "admin"));
+        let moved = Cookie::new("other", &encrypted.value);
+        assert!(keys.decrypt(&moved).is_none());
+    }
+
+    #[test]
+    fn keeps_ephemeral_keys_for_the_process() {
+        let keys = CookieKeys::ephemeral();
+        assert!(Arc::ptr_eq(&keys, &CookieKeys::ephemeral()));
+
+        let signed = keys.sign(Cookie::new("user", "42"));
+        let encrypted = keys.encrypt(Cookie::new("user", "42"));
+
+        assert_eq!(CookieKeys::ephemeral().verify(&signed).unwrap().value, "42");
+        assert_eq!(CookieKeys::ephemeral().decrypt(&encrypted).unwrap().value, "42");
+
+        // Generated keys are random
+        assert!(CookieKeys::new(CookieKey::generate()).verify(&signed).is_none());
+    }
+}
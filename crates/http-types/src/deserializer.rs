@@ -0,0 +1,433 @@
+use std::{collections::HashMap, fmt::Display};
+
+use serde::{
+    de::{
+        value::{MapDeserializer, SeqDeserializer},
+        DeserializeOwned, Error, IntoDeserializer, Visitor,
+    },
+    forward_to_deserialize_any, Deserializer,
+};
+
+use crate::{response::Response, status_codes::StatusCodes};
+
+// Error produced when a query string, path params or body can not be deserialized.
+// The message names the offending field when there is one, so it can be sent back as-is.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DeserializeError {
+    message: String,
+}
+
+impl DeserializeError {
+    pub fn message(&self) -> &str {
+        &self.message
+    }
+
+    pub fn into_response(self) -> Response {
+        Response::new(StatusCodes::BadRequest, HashMap::new(), &self.message)
+    }
+}
+
+impl Display for DeserializeError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.message)
+    }
+}
+
+impl std::error::Error for DeserializeError {}
+
+impl Error for DeserializeError {
+    fn custom<T: Display>(msg: T) -> Self {
+        DeserializeError {
+            message: msg.to_string(),
+        }
+    }
+}
+
+impl<E: Display> From<serde_path_to_error::Error<E>> for DeserializeError {
+    fn from(error: serde_path_to_error::Error<E>) -> Self {
+        let path = error.path().to_string();
+
+        // The path is "." when the error is not tied to a specific field
+        let message = if path == "." {
+            error.inner().to_string()
+        } else {
+            format!("{}: {}", path, error.inner())
+        };
+
+        DeserializeError { message }
+    }
+}
+
+// Deserializes a list of keys, each with one or more string values, into T.
+// Keys with several values can be deserialized into sequences; otherwise the first value is used.
+pub fn deserialize_params<'a, T, I>(params: I) -> Result<T, DeserializeError>
+where
+    T: DeserializeOwned,
+    I: Iterator<Item = (&'a str, &'a [String])>,
+{
+    let deserializer = MapDeserializer::<_, DeserializeError>::new(
+        params.map(|(key, values)| (key, ValuesDeserializer(values))),
+    );
+
+    Ok(serde_path_to_error::deserialize(deserializer)?)
+}
+
+struct ValuesDeserializer<'a>(&'a [String]);
+
+struct ValueDeserializer<'a>(&'a str);
+
+impl<'de, 'a> IntoDeserializer<'de, DeserializeError> for ValuesDeserializer<'a> {
+    type Deserializer = Self;
+
+    fn into_deserializer(self) -> Self::Deserializer {
+        self
+    }
+}
+
+impl<'de, 'a> IntoDeserializer<'de, DeserializeError> for ValueDeserializer<'a> {
+    type Deserializer = Self;
+
+    fn into_deserializer(self) -> Self::Deserializer {
+        self
+    }
+}
+
+macro_rules! forward_to_first_value {
+    ($($method:ident)*) => {
+        $(
+            fn $method<V>(self, visitor: V) -> Result<V::Value, Self::Error>
+            where
+                V: Visitor<'de>,
+            {
+                self.first().$method(visitor)
+            }
+        )*
+    };
+}
+
+impl<'a> ValuesDeserializer<'a> {
+    fn first(&self) -> ValueDeserializer<'a> {
+        ValueDeserializer(self.0.first().map_or("", |value| value.as_str()))
+    }
+}
+
+impl<'de, 'a> Deserializer<'de> for ValuesDeserializer<'a> {
+    type Error = DeserializeError;
+
+    forward_to_first_value! {
+        deserialize_any deserialize_bool
+        deserialize_i8 deserialize_i16 deserialize_i32 deserialize_i64
+        deserialize_u8 deserialize_u16 deserialize_u32 deserialize_u64
+        deserialize_f32 deserialize_f64 deserialize_char
+        deserialize_str deserialize_string deserialize_bytes deserialize_byte_buf
+        deserialize_option deserialize_unit deserialize_map
+        deserialize_identifier deserialize_ignored_any
+    }
+
+    fn deserialize_seq<V>(self, visitor: V) -> Result<V::Value, Self::Error>
+    where
+        V: Visitor<'de>,
+    {
+        let values = self.0.iter().map(|value| ValueDeserializer(value));
+
+        SeqDeserializer::new(values).deserialize_seq(visitor)
+    }
+
+    fn deserialize_tuple<V>(self, _len: usize, visitor: V) -> Result<V::Value, Self::Error>
+    where
+        V: Visitor<'de>,
+    {
+        self.deserialize_seq(visitor)
+    }
+
+    fn deserialize_tuple_struct<V>(
+        self,
+        _name: &'static str,
+        _len: usize,
+        visitor: V,
+    ) -> Result<V::Value, Self::Error>
+    where
+        V: Visitor<'de>,
+    {
+        self.deserialize_seq(visitor)
+    }
+
+    fn deserialize_unit_struct<V>(self, name: &'static str, visitor: V) -> Result<V::Value, Self::Error>
+    where
+        V: Visitor<'de>,
+    {
+        self.first().deserialize_unit_struct(name, visitor)
+    }
+
+    fn deserialize_newtype_struct<V>(self, name: &'static str, visitor: V) -> Result<V::Value, Self::Error>
+    where
+        V: Visitor<'de>,
+    {
+        self.first().deserialize_newtype_struct(name, visitor)
+    }
+
+    fn deserialize_struct<V>(
+        self,
+        name: &'static str,
+        fields: &'static [&'static str],
+        visitor: V,
+    ) -> Result<V::Value, Self::Error>
+    where
+        V: Visitor<'de>,
+    {
+        self.first().deserialize_struct(name, fields, visitor)
+    }
+
+    fn deserialize_enum<V>(
+        self,
+        name: &'static str,
+        variants: &'static [&'static str],
+        visitor: V,
+    ) -> Result<V::Value, Self::Error>
+    where
+        V: Visitor<'de>,
+    {
+        self.first().deserialize_enum(name, variants, visitor)
+    }
+}
+
+macro_rules! deserialize_parsed {
+    ($($method:ident => $visit:ident,)*) => {
+        $(
+            fn $method<V>(self, visitor: V) -> Result<V::Value, Self::Error>
+            where
+                V: Visitor<'de>,
+            {
+                match self.0.parse() {
+                    Ok(value) => visitor.$visit(value),
+                    Err(error) => Err(DeserializeError::custom(format!("{} (got \"{}\")", error, self.0))),
+                }
+            }
+        )*
+    };
+}
+
+impl<'de, 'a> Deserializer<'de> for ValueDeserializer<'a> {
+    type Error = DeserializeError;
+
+    fn deserialize_any<V>(self, visitor: V) -> Result<V::Value, Self::Error>
+    where
+        V: Visitor<'de>,
+    {
+        visitor.visit_str(self.0)
+    }
+
+    deserialize_parsed! {
+        deserialize_bool => visit_bool,
+        deserialize_i8 => visit_i8,
+        deserialize_i16 => visit_i16,
+        deserialize_i32 => visit_i32,
+        deserialize_i64 => visit_i64,
+        deserialize_u8 => visit_u8,
+        deserialize_u16 => visit_u16,
+        deserialize_u32 => visit_u32,
+        deserialize_u64 => visit_u64,
+        deserialize_f32 => visit_f32,
+        deserialize_f64 => visit_f64,
+        deserialize_char => visit_char,
+    }
+
+    // An empty value (e.g. "?page=") is treated as missing
+    fn deserialize_option<V>(self, visitor: V) -> Result<V::Value, Self::Error>
+    where
+        V: Visitor<'de>,
+    {
+        if self.0.is_empty() {
+            visitor.visit_none()
+        } else {
+            visitor.visit_some(self)
+        }
+    }
+
+    fn deserialize_unit<V>(self, visitor: V) -> Result<V::Value, Self::Error>
+    where
+        V: Visitor<'de>,
+    {
+        visitor.visit_unit()
+    }
+
+    fn deserialize_newtype_struct<V>(self, _name: &'static str, visitor: V) -> Result<V::Value, Self::Error>
+    where
+        V: Visitor<'de>,
+    {
+        visitor.visit_newtype_struct(self)
+    }
+
+    fn deserialize_enum<V>(
+        self,
+        _name: &'static str,
+        _variants: &'static [&'static str],
+        visitor: V,
+    ) -> Result<V::Value, Self::Error>
+    where
+        V: Visitor<'de>,
+    {
+        visitor.visit_enum(IntoDeserializer::<DeserializeError>::into_deserializer(self.0))
+    }
+
+    forward_to_deserialize_any! {
+        str string bytes byte_buf unit_struct seq tuple
+        tuple_struct map struct identifier ignored_any
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde::Deserialize;
+
+    use super::*;
+    use crate::{method::HTTPMethod, request::Request, search_params::SearchParams};
+
+    #[derive(Debug, Deserialize, PartialEq)]
+    struct Search {
+        q: String,
+        page: Option<u32>,
+        #[serde(default)]
+        tags: Vec<String>,
+        #[serde(default)]
+        exact: bool,
+        #[serde(default)]
+        sort: Option<Sort>,
+    }
+
+    #[derive(Debug, Deserialize, PartialEq)]
+    #[serde(rename_all = "lowercase")]
+    enum Sort {
+        Newest,
+        Oldest,
+    }
+
+    #[derive(Debug, Deserialize, PartialEq)]
+    struct User {
+        name: String,
+        age: u32,
+    }
+
+    #[derive(Debug, Deserialize, PartialEq)]
+    struct Signup {
+        user: User,
+        invites: Vec<User>,
+    }
+
+    fn deserialize<T: DeserializeOwned>(params: &[(&str, &[&str])]) -> Result<T, DeserializeError> {
+        let params: Vec<(&str, Vec<String>)> = params
+            .iter()
+            .map(|(key, values)| (*key, values.iter().map(|value| value.to_string()).collect()))
+            .collect();
+
+        deserialize_params(params.iter().map(|(key, values)| (*key, values.as_slice())))
+    }
+
+    fn request(body: &str) -> Request {
+        Request::new(HTTPMethod::POST, "/".to_owned(), HashMap::new(), body.as_bytes().to_vec(), SearchParams::from("").unwrap())
+    }
+
+    #[test]
+    fn deserializes_params() {
+        let search: Search = deserialize(&[("q", &["rust"]), ("page", &["2"]), ("exact", &["true"]), ("sort", &["oldest"])]).unwrap();
+
+        assert_eq!(
+            search,
+            Search {
+                q: "rust".to_owned(),
+                page: Some(2),
+                tags: Vec::new(),
+                exact: true,
+                sort: Some(Sort::Oldest),
+            }
+        );
+    }
+
+    #[test]
+    fn collects_repeated_keys() {
+        let search: Search = deserialize(&[("q", &["a", "b"]), ("tags", &["web", "http"])]).unwrap();
+
+        // Only sequences take every value, other fields the first one
+        assert_eq!(search.q, "a");
+        assert_eq!(search.tags, ["web", "http"]);
+
+        let search: Search = deserialize(&[("q", &["a"]), ("tags", &["web"])]).unwrap();
+        assert_eq!(search.tags, ["web"]);
+    }
+
+    #[test]
+    fn treats_empty_values_as_missing() {
+        let search: Search = deserialize(&[("q", &[""]), ("page", &[""])]).unwrap();
+
+        assert_eq!(search.q, "");
+        assert_eq!(search.page, None);
+    }
+
+    #[test]
+    fn names_the_field_that_failed_to_parse() {
+        let error = deserialize::<Search>(&[("q", &["a"]), ("page", &["two"])]).unwrap_err();
+        assert_eq!(error.message(), "page: invalid digit found in string (got \"two\")");
+
+        let error = deserialize::<Search>(&[("q", &["a"]), ("page", &["-1"])]).unwrap_err();
+        assert_eq!(error.message(), "page: invalid digit found in string (got \"-1\")");
+
+        let error = deserialize::<Search>(&[("q", &["a"]), ("exact", &["yes"])]).unwrap_err();
+        assert_eq!(error.message(), "exact: provided string was not `true` or `false` (got \"yes\")");
+
+        let error = deserialize::<Search>(&[("q", &["a"]), ("sort", &["best"])]).unwrap_err();
+        assert_eq!(error.message(), "sort: unknown variant `best`, expected `newest` or `oldest`");
+    }
+
+    #[test]
+    fn reports_missing_fields() {
+        let error = deserialize::<Search>(&[("page", &["1"])]).unwrap_err();
+        assert_eq!(error.message(), "missing field `q`");
+
+        let response = error.into_response();
+        assert_eq!(response.status().as_code(), 400);
+        assert_eq!(response.body(), "missing field `q`");
+    }
+
+    #[test]
+    fn deserializes_path_params() {
+        let mut request = request("");
+        request.path_params = vec![("name".to_owned(), "ada".to_owned()), ("age".to_owned(), "36".to_owned())];
+        assert_eq!(request.params::<User>().unwrap(), User { name: "ada".to_owned(), age: 36 });
+
+        request.path_params[1].1 = "old".to_owned();
+        assert_eq!(request.params::<User>().unwrap_err().message(), "age: invalid digit found in string (got \"old\")");
+    }
+
+    #[test]
+    fn deserializes_json_bodies() {
+        let body = r#"{"user": {"name": "ada", "age": 36}, "invites": [{"name": "bob", "age": 7}]}"#;
+        let signup: Signup = request(body).json().unwrap();
+
+        assert_eq!(signup.user, User { name: "ada".to_owned(), age: 36 });
+        assert_eq!(signup.invites.len(), 1);
+
+        // Whitespace after the value is fine
+        assert!(request(&format!("{}\r\n ", body)).json::<Signup>().is_ok());
+    }
+
+    #[test]
+    fn names_the_json_field_that_failed() {
+        let error = request(r#"{"user": {"name": "ada", "age": "36"}, "invites": []}"#).json::<Signup>().unwrap_err();
+        assert!(error.message().starts_with("user.age: invalid type: string \"36\", expected u32"), "{}", error);
+
+        let error = request(r#"{"user": {"name": "ada", "age": 1}, "invites": [{"name": "bob"}]}"#).json::<Signup>().unwrap_err();
+        assert!(error.message().starts_with("invites[0]: missing field `age`"), "{}", error);
+
+        let error = request(r#"{"user": {"name": "ada", "age": 1}}"#).json::<Signup>().unwrap_err();
+        assert!(error.message().starts_with("missing field `invites`"), "{}", error);
+    }
+
+    #[test]
+    fn rejects_data_after_the_json_value() {
+        let error = request(r#"{"name": "ada", "age": 36} {}"#).json::<User>().unwrap_err();
+        assert!(error.message().starts_with("trailing characters"), "{}", error);
+
+        let error = request(r#"{"name": "ada", "age": 36"#).json::<User>().unwrap_err();
+        assert!(error.message().contains("EOF"), "{}", error);
+    }
+}
@@ -0,0 +1,460 @@
+use std::{
+    collections::HashMap,
+    fmt::Display,
+    net::{IpAddr, SocketAddr},
+};
+
+// A range of IP addresses in CIDR notation, e.g. "10.0.0.0/8" or "fd00::/8".
+// A plain address is a range of one.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct IpCidr {
+    addr: IpAddr,
+    prefix: u8,
+}
+
+impl IpCidr {
+    pub fn from(cidr: &str) -> Option<IpCidr> {
+        let (addr, prefix) = match cidr.trim().split_once('/') {
+            Some((addr, prefix)) => (addr.parse::<IpAddr>().ok()?, Some(prefix)),
+            None => (cidr.trim().parse::<IpAddr>().ok()?, None),
+        };
+
+        let max_prefix = if addr.is_ipv4() { 32 } else { 128 };
+        let prefix = match prefix {
+            // Digits only, parse would also take a leading plus sign
+            Some(prefix) if prefix.bytes().all(|b| b.is_ascii_digit()) => prefix.parse::<u8>().ok()?,
+            Some(_) => return None,
+            None => max_prefix,
+        };
+
+        if prefix > max_prefix {
+            return None;
+        }
+
+        Some(IpCidr { addr, prefix })
+    }
+
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        // Clients reaching an IPv6 socket over IPv4 show up as IPv4-mapped addresses
+        let ip = match ip {
+            IpAddr::V6(v6) => v6.to_ipv4_mapped().map_or(ip, IpAddr::V4),
+            IpAddr::V4(_) => ip,
+        };
+
+        match (self.addr, ip) {
+            (IpAddr::V4(range), IpAddr::V4(ip)) => {
+                let mask = u32::MAX.checked_shl(32 - self.prefix as u32).unwrap_or(0);
+                u32::from(range) & mask == u32::from(ip) & mask
+            }
+            (IpAddr::V6(range), IpAddr::V6(ip)) => {
+                let mask = u128::MAX.checked_shl(128 - self.prefix as u32).unwrap_or(0);
+                u128::from(range) & mask == u128::from(ip) & mask
+            }
+            _ => false,
+        }
+    }
+}
+
+impl Display for IpCidr {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}/{}", self.addr, self.prefix)
+    }
+}
+
+// The forwarding headers the trusted proxies set. Only that family is read. A proxy that sets one
+// family usually passes the other one on as the client sent it, so reading both would let clients
+// pick their own address.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ForwardedHeaders {
+    // The Forwarded header of RFC 7239
+    Forwarded,
+    // X-Forwarded-For, X-Forwarded-Proto and X-Forwarded-Host
+    #[default]
+    XForwarded,
+}
+
+impl ForwardedHeaders {
+    pub fn from(headers: &str) -> Option<ForwardedHeaders> {
+        match headers {
+            "forwarded" => Some(ForwardedHeaders::Forwarded),
+            "x-forwarded" => Some(ForwardedHeaders::XForwarded),
+            _ => None,
+        }
+    }
+}
+
+// The proxies whose forwarding headers are believed. Empty by default, in which case the
+// headers are ignored and the peer address is the client.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct TrustedProxies {
+    cidrs: Vec<IpCidr>,
+    headers: ForwardedHeaders,
+}
+
+impl TrustedProxies {
+    pub fn new(cidrs: Vec<IpCidr>) -> TrustedProxies {
+        TrustedProxies {
+            cidrs,
+            headers: ForwardedHeaders::default(),
+        }
+    }
+
+    pub fn headers(&self) -> ForwardedHeaders {
+        self.headers
+    }
+
+    pub fn set_headers(&mut self, headers: ForwardedHeaders) {
+        self.headers = headers;
+    }
+
+    pub fn add(&mut self, cidr: IpCidr) {
+        if !self.cidrs.contains(&cidr) {
+            self.cidrs.push(cidr);
+        }
+    }
+
+    pub fn cidrs(&self) -> &[IpCidr] {
+        &self.cidrs
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.cidrs.is_empty()
+    }
+
+    pub fn is_trusted(&self, ip: IpAddr) -> bool {
+        self.cidrs.iter().any(|cidr| cidr.contains(ip))
+    }
+
+    // Finds what the proxy in front of the client reported about it. Starting from the peer, each
+    // trusted hop vouches for the one before it, until a hop that is not trusted is reached.
+    // Returns None when the peer itself is not trusted or sent none of the configured headers.
+    pub(crate) fn forwarded_hop(&self, peer: IpAddr, headers: &HashMap<String, String>) -> Option<ForwardedHop> {
+        if !self.is_trusted(peer) {
+            return None;
+        }
+
+        let hops = match self.headers {
+            ForwardedHeaders::Forwarded => headers.get("forwarded").map_or(Vec::new(), |value| parse_forwarded(value)),
+            ForwardedHeaders::XForwarded => parse_x_forwarded(headers),
+        };
+
+        let mut chosen = None;
+        let mut candidate = peer;
+
+        for hop in hops.into_iter().rev() {
+            if !self.is_trusted(candidate) {
+                break;
+            }
+
+            // Hidden or unknown addresses end the chain, the hop that reported them is the last one known
+            let Some(ip) = hop.for_ip else {
+                chosen = Some(hop);
+                break;
+            };
+
+            candidate = ip;
+            chosen = Some(hop);
+        }
+
+        chosen
+    }
+}
+
+// One entry of the forwarding headers: the address a proxy received the request from,
+// and the scheme and host that request was made with
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub(crate) struct ForwardedHop {
+    pub for_ip: Option<IpAddr>,
+    pub proto: Option<String>,
+    pub host: Option<String>,
+}
+
+// Parses the Forwarded header of RFC 7239, e.g. `for=192.0.2.60;proto=https, for="[2001:db8::1]:4711"`
+fn parse_forwarded(value: &str) -> Vec<ForwardedHop> {
+    split_unquoted(value, ',')
+        .into_iter()
+        .map(|element| {
+            let mut hop = ForwardedHop::default();
+
+            for pair in split_unquoted(element, ';') {
+                let Some((name, value)) = pair.split_once('=') else {
+                    continue;
+                };
+
+                let value = unquote(value.trim());
+
+                match name.trim().to_ascii_lowercase().as_str() {
+                    "for" => hop.for_ip = parse_node(&value),
+                    "proto" => hop.proto = Some(value.to_ascii_lowercase()),
+                    "host" => hop.host = Some(value),
+                    _ => {}
+                }
+            }
+
+            hop
+        })
+        .collect()
+}
+
+// Builds the same list from X-Forwarded-For, X-Forwarded-Proto and X-Forwarded-Host. The proto and
+// host lists are lined up with the end of the address list, and their first value is used for the
+// hops before them, since most proxies set those headers once instead of appending to them.
+fn parse_x_forwarded(headers: &HashMap<String, String>) -> Vec<ForwardedHop> {
+    let list = |name: &str| -> Vec<String> {
+        headers.get(name).map_or(Vec::new(), |value| {
+            value.split(',').map(|entry| entry.trim().to_owned()).filter(|entry| !entry.is_empty()).collect()
+        })
+    };
+
+    let addresses = list("x-forwarded-for");
+    let protos = list("x-forwarded-proto");
+    let hosts = list("x-forwarded-host");
+
+    let aligned = |values: &[String], idx: usize| -> Option<String> {
+        let from_end = addresses.len() - idx;
+        values.len().checked_sub(from_end).and_then(|idx| values.get(idx)).or(values.first()).cloned()
+    };
+
+    (0..addresses.len())
+        .map(|idx| ForwardedHop {
+            for_ip: parse_node(&addresses[idx]),
+            proto: aligned(&protos, idx).map(|proto| proto.to_ascii_lowercase()),
+            host: aligned(&hosts, idx),
+        })
+        .collect()
+}
+
+// Parses an address that may come with a port, e.g. "192.0.2.60", "192.0.2.60:4711",
+// "2001:db8::1" or "[2001:db8::1]:4711". Returns None for "unknown" and obfuscated identifiers.
+fn parse_node(node: &str) -> Option<IpAddr> {
+    let node = node.trim();
+
+    if let Ok(ip) = node.parse::<IpAddr>() {
+        return Some(ip);
+    }
+
+    if let Ok(addr) = node.parse::<SocketAddr>() {
+        return Some(addr.ip());
+    }
+
+    // A bracketed IPv6 address without a port
+    node.strip_prefix('[')?.strip_suffix(']')?.parse::<IpAddr>().ok()
+}
+
+// Splits on the separator, except inside quoted strings
+fn split_unquoted(value: &str, separator: char) -> Vec<&str> {
+    let mut parts = Vec::new();
+    let mut start = 0;
+    let mut quoted = false;
+    let mut escaped = false;
+
+    for (idx, c) in value.char_indices() {
+        match c {
+            _ if escaped => escaped = false,
+            '\\' if quoted => escaped = true,
+            '"' => quoted = !quoted,
+            _ if c == separator && !quoted => {
+                parts.push(value[start..idx].trim());
+                start = idx + 1;
+            }
+            _ => {}
+        }
+    }
+
+    parts.push(value[start..].trim());
+    parts.retain(|part| !part.is_empty());
+    parts
+}
+
+fn unquote(value: &str) -> String {
+    let Some(inner) = value.strip_prefix('"').and_then(|v| v.strip_suffix('"')) else {
+        return value.to_owned();
+    };
+
+    let mut unquoted = String::with_capacity(inner.len());
+    let mut chars = inner.chars();
+
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => unquoted.extend(chars.next()),
+            _ => unquoted.push(c),
+        }
+    }
+
+    unquoted
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ip(ip: &str) -> IpAddr {
+        ip.parse().unwrap()
+    }
+
+    fn proxies(cidrs: &[&str]) -> TrustedProxies {
+        TrustedProxies::new(cidrs.iter().map(|cidr| IpCidr::from(cidr).unwrap()).collect())
+    }
+
+    fn forwarded_proxies(cidrs: &[&str]) -> TrustedProxies {
+        let mut proxies = proxies(cidrs);
+        proxies.set_headers(ForwardedHeaders::Forwarded);
+        proxies
+    }
+
+    fn headers(pairs: &[(&str, &str)]) -> HashMap<String, String> {
+        pairs.iter().map(|(name, value)| (name.to_string(), value.to_string())).collect()
+    }
+
+    #[test]
+    fn parses_cidrs() {
+        let cidr = IpCidr::from("10.0.0.0/8").unwrap();
+        assert!(cidr.contains(ip("10.1.2.3")));
+        assert!(!cidr.contains(ip("11.0.0.1")));
+        assert!(cidr.contains(ip("::ffff:10.0.0.1")));
+
+        let cidr = IpCidr::from("fd00::/8").unwrap();
+        assert!(cidr.contains(ip("fd12::1")));
+        assert!(!cidr.contains(ip("fe80::1")));
+
+        // A plain address is a range of one
+        assert_eq!(IpCidr::from(" 192.0.2.1 ").unwrap().to_string(), "192.0.2.1/32");
+        assert!(IpCidr::from("0.0.0.0/0").unwrap().contains(ip("203.0.113.9")));
+    }
+
+    #[test]
+    fn rejects_malformed_cidrs() {
+        for cidr in ["", "10.0.0.0/", "10.0.0.0/33", "fd00::/129", "10.0.0.0/+8", "10.0.0.0/-1", "10.0.0/8", "host/8", "10.0.0.0/8/8"] {
+            assert_eq!(IpCidr::from(cidr), None, "{}", cidr);
+        }
+    }
+
+    #[test]
+    fn ignores_headers_from_untrusted_peers() {
+        let proxies = proxies(&["10.0.0.0/8"]);
+        let headers = headers(&[("forwarded", "for=203.0.113.9;proto=https"), ("x-forwarded-for", "203.0.113.9")]);
+
+        assert_eq!(proxies.forwarded_hop(ip("198.51.100.1"), &headers), None);
+        assert_eq!(TrustedProxies::default().forwarded_hop(ip("10.0.0.1"), &headers), None);
+    }
+
+    #[test]
+    fn walks_the_chain_of_trusted_hops() {
+        let proxies = proxies(&["10.0.0.0/8", "192.168.0.1"]);
+        let chain = headers(&[("x-forwarded-for", "198.51.100.7, 203.0.113.9, 192.168.0.1, 10.0.0.2")]);
+
+        // 10.0.0.2 and 192.168.0.1 are trusted proxies, 203.0.113.9 is the first one that isn't
+        let hop = proxies.forwarded_hop(ip("10.0.0.1"), &chain).unwrap();
+        assert_eq!(hop.for_ip, Some(ip("203.0.113.9")));
+
+        // Every hop is trusted, so the first address is the client
+        let chain = headers(&[("x-forwarded-for", "198.51.100.7, 10.0.0.3, 10.0.0.2")]);
+        let hop = proxies.forwarded_hop(ip("10.0.0.1"), &chain).unwrap();
+        assert_eq!(hop.for_ip, Some(ip("198.51.100.7")));
+    }
+
+    #[test]
+    fn stops_at_unknown_addresses() {
+        let proxies = forwarded_proxies(&["10.0.0.0/8"]);
+        let headers = headers(&[("forwarded", "for=198.51.100.7, for=unknown;proto=https, for=10.0.0.2")]);
+
+        let hop = proxies.forwarded_hop(ip("10.0.0.1"), &headers).unwrap();
+        assert_eq!(hop.for_ip, None);
+        assert_eq!(hop.proto.as_deref(), Some("https"));
+    }
+
+    #[test]
+    fn parses_bracketed_ipv6_nodes_with_ports() {
+        let hops = parse_forwarded(r#"for="[2001:db8:cafe::17]:4711";proto=HTTPS;host="example.com:8443", For=192.0.2.43:80"#);
+
+        assert_eq!(hops.len(), 2);
+        assert_eq!(hops[0].for_ip, Some(ip("2001:db8:cafe::17")));
+        assert_eq!(hops[0].proto.as_deref(), Some("https"));
+        assert_eq!(hops[0].host.as_deref(), Some("example.com:8443"));
+        assert_eq!(hops[1].for_ip, Some(ip("192.0.2.43")));
+
+        assert_eq!(parse_node("[2001:db8::1]"), Some(ip("2001:db8::1")));
+        assert_eq!(parse_node("2001:db8::1"), Some(ip("2001:db8::1")));
+        assert_eq!(parse_node("_hidden"), None);
+    }
+
+    #[test]
+    fn keeps_separators_inside_quotes() {
+        let hops = parse_forwarded(r#"for=192.0.2.1;host="a,b;c", for=192.0.2.2"#);
+
+        assert_eq!(hops.len(), 2);
+        assert_eq!(hops[0].host.as_deref(), Some("a,b;c"));
+    }
+
+    #[test]
+    fn lines_x_forwarded_values_up_with_the_end() {
+        let hops = parse_x_forwarded(&headers(&[
+            ("x-forwarded-for", "198.51.100.7, 10.0.0.2"),
+            ("x-forwarded-proto", "https"),
+            ("x-forwarded-host", "example.com"),
+        ]));
+
+        assert_eq!(hops.len(), 2);
+        assert!(hops.iter().all(|hop| hop.proto.as_deref() == Some("https")));
+        assert!(hops.iter().all(|hop| hop.host.as_deref() == Some("example.com")));
+
+        let hops = parse_x_forwarded(&headers(&[
+            ("x-forwarded-for", "198.51.100.7, 10.0.0.3, 10.0.0.2"),
+            ("x-forwarded-proto", "HTTP, https"),
+        ]));
+
+        let protos: Vec<_> = hops.iter().map(|hop| hop.proto.as_deref()).collect();
+        assert_eq!(protos, [Some("http"), Some("http"), Some("https")]);
+    }
+
+    #[test]
+    fn ignores_forwarded_injected_by_clients() {
+        // The proxy appended to X-Forwarded-For and passed on the Forwarded header the client sent
+        let proxies = proxies(&["10.0.0.0/8"]);
+        let both = headers(&[
+            ("forwarded", "for=1.2.3.4;proto=https;host=evil.example"),
+            ("x-forwarded-for", "203.0.113.9"),
+            ("x-forwarded-proto", "http"),
+            ("x-forwarded-host", "example.com"),
+        ]);
+
+        let hop = proxies.forwarded_hop(ip("10.0.0.1"), &both).unwrap();
+        assert_eq!(
+            hop,
+            ForwardedHop {
+                for_ip: Some(ip("203.0.113.9")),
+                proto: Some("http".to_owned()),
+                host: Some("example.com".to_owned()),
+            }
+        );
+
+        // Without X-Forwarded-For there is nothing to go by, even though Forwarded is there
+        let spoofed = headers(&[("forwarded", "for=1.2.3.4;proto=https;host=evil.example")]);
+        assert_eq!(proxies.forwarded_hop(ip("10.0.0.1"), &spoofed), None);
+    }
+
+    #[test]
+    fn ignores_x_forwarded_when_forwarded_is_configured() {
+        let proxies = forwarded_proxies(&["10.0.0.0/8"]);
+        let both = headers(&[
+            ("forwarded", "for=198.51.100.7;proto=https;host=example.com"),
+            ("x-forwarded-for", "1.2.3.4"),
+            ("x-forwarded-host", "evil.example"),
+        ]);
+
+        let hop = proxies.forwarded_hop(ip("10.0.0.1"), &both).unwrap();
+        assert_eq!(hop.for_ip, Some(ip("198.51.100.7")));
+        assert_eq!(hop.host.as_deref(), Some("example.com"));
+
+        let spoofed = headers(&[("x-forwarded-for", "1.2.3.4")]);
+        assert_eq!(proxies.forwarded_hop(ip("10.0.0.1"), &spoofed), None);
+    }
+
+    #[test]
+    fn parses_forwarded_headers_setting() {
+        assert_eq!(ForwardedHeaders::from("forwarded"), Some(ForwardedHeaders::Forwarded));
+        assert_eq!(ForwardedHeaders::from("x-forwarded"), Some(ForwardedHeaders::XForwarded));
+        assert_eq!(ForwardedHeaders::from("both"), None);
+        assert_eq!(TrustedProxies::default().headers(), ForwardedHeaders::XForwarded);
+    }
+}
@@ -1,15 +1,47 @@
+mod chunked;
+mod client_certificate;
+mod cookie;
+mod cookie_keys;
+#[cfg(feature = "serde")]
+mod deserializer;
+mod forwarded;
+mod http_date;
 mod message;
 mod method;
+mod multipart;
+mod parser;
+mod percent_encoding;
 mod request;
 mod response;
 mod search_params;
+mod session;
+mod sse;
 mod status_codes;
 mod uri_parser;
+mod version;
+#[cfg(feature = "websocket")]
+mod websocket;
 
+pub use chunked::*;
+pub use client_certificate::*;
+pub use cookie::*;
+pub use cookie_keys::*;
+#[cfg(feature = "serde")]
+pub use deserializer::*;
+pub use forwarded::*;
+pub use http_date::*;
 pub use message::*;
 pub use method::*;
+pub use multipart::*;
+pub use parser::*;
+pub use percent_encoding::*;
 pub use request::*;
 pub use response::*;
 pub use search_params::*;
+pub use session::*;
+pub use sse::*;
 pub use status_codes::*;
 pub use uri_parser::*;
+pub use version::*;
+#[cfg(feature = "websocket")]
+pub use websocket::*;
@@ -2,18 +2,94 @@ use std::collections::HashMap;
 
 use crate::status_codes::StatusCodes;
 
+// Parses header lines separated by CRLF, rejecting the forms that RFC 9112 forbids
+// (whitespace before the colon, obs-fold continuation lines and control characters).
 pub fn parse_headers(headers_string: &str) -> Result<HashMap<String, String>, StatusCodes> {
     let header_lines = headers_string.split("\r\n");
     let mut headers: HashMap<String, String> = HashMap::new();
 
     for line in header_lines {
+        if line.starts_with([' ', '\t']) {
+            println!("Error(parse_headers): obsolete line folding.\n{}\n", line);
+            return Err(StatusCodes::BadRequest);
+        }
+
         let Some((k, v)) = line.split_once(':') else {
             println!("Error(parse_headers): incorrect string format.\n{}\n", line);
             return Err(StatusCodes::BadRequest);
         };
 
-        headers.insert(k.trim().to_lowercase().to_owned(), v.trim().to_owned());
+        let v = v.trim_matches([' ', '\t']);
+
+        if !is_token(k.as_bytes()) || !is_field_value(v.as_bytes()) {
+            println!("Error(parse_headers): invalid header.\n{}\n", line);
+            return Err(StatusCodes::BadRequest);
+        }
+
+        headers.insert(k.to_lowercase(), v.to_owned());
     }
 
     Ok(headers)
 }
+
+// Whether the bytes form a token as defined in RFC 9110, section 5.6.2, e.g. a method or a header name
+pub fn is_token(bytes: &[u8]) -> bool {
+    !bytes.is_empty() && bytes.iter().all(|&b| b.is_ascii_alphanumeric() || b"!#$%&'*+-.^_`|~".contains(&b))
+}
+
+// Whether the bytes are a valid header value, RFC 9110, section 5.5: visible characters,
+// spaces, tabs and obs-text, which leaves out every control character.
+pub fn is_field_value(bytes: &[u8]) -> bool {
+    bytes.iter().all(|&b| b.is_ascii_graphic() || b == b' ' || b == b'\t' || b >= 0x80)
+}
+
+// Gets a parameter from a header value such as `form-data; name="field"; filename="a.txt"`.
+// Parameter names are case-insensitive and quoted values are unescaped.
+pub fn get_header_param(header_value: &str, param: &str) -> Option<String> {
+    let mut rest = header_value.split_once(';')?.1;
+
+    loop {
+        let (name, after_name) = rest.split_once('=')?;
+        let after_name = after_name.trim_start();
+        let mut value = String::new();
+
+        if let Some(quoted) = after_name.strip_prefix('"') {
+            let mut chars = quoted.char_indices();
+            let mut end = None;
+
+            while let Some((idx, c)) = chars.next() {
+                match c {
+                    '\\' => {
+                        if let Some((_, escaped)) = chars.next() {
+                            value.push(escaped);
+                        }
+                    }
+                    '"' => {
+                        end = Some(idx + 1);
+                        break;
+                    }
+                    _ => value.push(c),
+                }
+            }
+
+            // Unterminated quoted string
+            let end = end?;
+            rest = match quoted[end..].split_once(';') {
+                Some((_, next)) => next,
+                None => "",
+            };
+        } else {
+            let (token, next) = after_name.split_once(';').unwrap_or((after_name, ""));
+            value.push_str(token.trim());
+            rest = next;
+        }
+
+        if name.trim().eq_ignore_ascii_case(param) {
+            return Some(value);
+        }
+
+        if rest.is_empty() {
+            return None;
+        }
+    }
+}
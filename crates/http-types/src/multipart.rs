@@ -0,0 +1,562 @@
+use std::{
+    collections::HashMap,
+    io::{Read, Write},
+    path::PathBuf,
+};
+
+use tempfile::NamedTempFile;
+
+use crate::{
+    message::{get_header_param, parse_headers},
+    status_codes::StatusCodes,
+};
+
+// Headers of a single part can not be larger than this
+const MAX_PART_HEADERS_SIZE: usize = 8 * 1024;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MultipartLimits {
+    // Maximum size of the data of a single part
+    pub max_part_size: usize,
+    // Maximum size of the whole body, including boundaries and headers
+    pub max_total_size: usize,
+    // File parts bigger than this are written to a temporary file instead of being kept in memory
+    pub memory_threshold: usize,
+    // Directory where the temporary files are created
+    pub temp_dir: PathBuf,
+}
+
+impl Default for MultipartLimits {
+    fn default() -> Self {
+        MultipartLimits {
+            max_part_size: 10 * 1024 * 1024,
+            max_total_size: 50 * 1024 * 1024,
+            memory_threshold: 256 * 1024,
+            temp_dir: std::env::temp_dir(),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum PartData {
+    Memory(Vec<u8>),
+    // The file is deleted when dropped, unless it is persisted with NamedTempFile::persist
+    File(NamedTempFile),
+}
+
+#[derive(Debug)]
+pub struct Part {
+    pub headers: HashMap<String, String>,
+    pub name: String,
+    pub filename: Option<String>,
+    pub content_type: Option<String>,
+    pub data: PartData,
+    pub size: usize,
+}
+
+impl Part {
+    pub fn is_file(&self) -> bool {
+        self.filename.is_some()
+    }
+
+    // Reads the whole part into memory, regardless of where it is stored
+    pub fn bytes(&self) -> std::io::Result<Vec<u8>> {
+        match &self.data {
+            PartData::Memory(data) => Ok(data.clone()),
+            PartData::File(file) => {
+                let mut data = Vec::with_capacity(self.size);
+
+                // A handle of its own starts at the beginning of the file
+                file.reopen()?.read_to_end(&mut data)?;
+
+                Ok(data)
+            }
+        }
+    }
+
+    pub fn text(&self) -> Result<String, StatusCodes> {
+        let Ok(bytes) = self.bytes() else {
+            println!("Error(Part::text): could not read part {}", self.name);
+            return Err(StatusCodes::InternalServerError);
+        };
+
+        String::from_utf8(bytes).map_err(|_| StatusCodes::BadRequest)
+    }
+}
+
+#[derive(Debug, PartialEq, Eq)]
+enum State {
+    Preamble,
+    AfterBoundary,
+    Headers,
+    Body,
+    Done,
+}
+
+// Incremental multipart/form-data parser (RFC 7578). The body can be fed in chunks of any size
+// and every part is returned as soon as its closing boundary has been read.
+pub struct MultipartParser {
+    // "--" followed by the boundary, which opens the first part
+    dash_boundary: Vec<u8>,
+    // "\r\n--" followed by the boundary, which ends the data of every part
+    delimiter: Vec<u8>,
+    limits: MultipartLimits,
+    state: State,
+    buffer: Vec<u8>,
+    total_size: usize,
+    current: Option<Part>,
+}
+
+impl MultipartParser {
+    pub fn new(boundary: &str, limits: MultipartLimits) -> MultipartParser {
+        MultipartParser {
+            dash_boundary: format!("--{}", boundary).into_bytes(),
+            delimiter: format!("\r\n--{}", boundary).into_bytes(),
+            limits,
+            state: State::Preamble,
+            buffer: Vec::new(),
+            total_size: 0,
+            current: None,
+        }
+    }
+
+    pub fn from_content_type(content_type: &str, limits: MultipartLimits) -> Result<MultipartParser, StatusCodes> {
+        let media_type = content_type.split(';').next().unwrap_or("").trim();
+
+        if !media_type.eq_ignore_ascii_case("multipart/form-data") {
+            return Err(StatusCodes::UnsupportedMediaType);
+        }
+
+        // RFC 2046 limits boundaries to 70 characters
+        match get_header_param(content_type, "boundary") {
+            Some(boundary) if !boundary.is_empty() && boundary.len() <= 70 => {
+                Ok(MultipartParser::new(&boundary, limits))
+            }
+            _ => {
+                println!("Error(multipart): missing or invalid boundary.\n{}\n", content_type);
+                Err(StatusCodes::BadRequest)
+            }
+        }
+    }
+
+    // Feeds the next chunk of the body, returning the parts that were completed by it
+    pub fn feed(&mut self, chunk: &[u8]) -> Result<Vec<Part>, StatusCodes> {
+        self.total_size += chunk.len();
+
+        if self.total_size > self.limits.max_total_size {
+            println!("Error(multipart): body exceeds the limit of {} bytes", self.limits.max_total_size);
+            return Err(StatusCodes::RequestEntityTooLarge);
+        }
+
+        if self.state == State::Done {
+            // Anything after the closing boundary is an epilogue and is ignored
+            return Ok(Vec::new());
+        }
+
+        self.buffer.extend_from_slice(chunk);
+
+        let mut parts = Vec::new();
+
+        while self.step(&mut parts)? {}
+
+        Ok(parts)
+    }
+
+    // Checks that the body ended with the closing boundary
+    pub fn finish(self) -> Result<(), StatusCodes> {
+        if self.state != State::Done {
+            println!("Error(multipart): body ended before the closing boundary");
+            return Err(StatusCodes::BadRequest);
+        }
+
+        Ok(())
+    }
+
+    // Advances the state machine, returning false when more data is needed
+    fn step(&mut self, parts: &mut Vec<Part>) -> Result<bool, StatusCodes> {
+        match self.state {
+            State::Preamble => {
+                let Some(idx) = find(&self.buffer, &self.dash_boundary) else {
+                    // Keep enough bytes to detect a boundary split across chunks
+                    let keep = self.dash_boundary.len() - 1;
+                    let discard = self.buffer.len().saturating_sub(keep);
+                    self.buffer.drain(..discard);
+
+                    return Ok(false);
+                };
+
+                self.buffer.drain(..idx + self.dash_boundary.len());
+                self.state = State::AfterBoundary;
+
+                Ok(true)
+            }
+            State::AfterBoundary => {
+                if self.buffer.starts_with(b"--") {
+                    self.buffer.clear();
+                    self.state = State::Done;
+
+                    return Ok(false);
+                }
+
+                // Skip the optional transport padding before the line break
+                let padding = self.buffer.iter().take_while(|b| **b == b' ' || **b == b'\t').count();
+                let rest = &self.buffer[padding..];
+
+                if rest.len() < 2 {
+                    return Ok(false);
+                }
+
+                if &rest[..2] != b"\r\n" {
+                    println!("Error(multipart): unexpected data after boundary");
+                    return Err(StatusCodes::BadRequest);
+                }
+
+                self.buffer.drain(..padding + 2);
+                self.state = State::Headers;
+
+                Ok(true)
+            }
+            State::Headers => {
+                // A part without headers starts right away with an empty line
+                let (headers_string, consumed) = if self.buffer.starts_with(b"\r\n") {
+                    (String::new(), 2)
+                } else {
+                    let Some(idx) = find(&self.buffer, b"\r\n\r\n") else {
+                        if self.buffer.len() > MAX_PART_HEADERS_SIZE {
+                            println!("Error(multipart): part headers are too large");
+                            return Err(StatusCodes::RequestEntityTooLarge);
+                        }
+
+                        return Ok(false);
+                    };
+
+                    let Ok(headers_string) = String::from_utf8(self.buffer[..idx].to_vec()) else {
+                        println!("Error(multipart): part headers are not valid UTF-8");
+                        return Err(StatusCodes::BadRequest);
+                    };
+
+                    (headers_string, idx + 4)
+                };
+
+                self.buffer.drain(..consumed);
+                self.current = Some(MultipartParser::create_part(&headers_string)?);
+                self.state = State::Body;
+
+                Ok(true)
+            }
+            State::Body => {
+                let (data_len, consumed, found) = match find(&self.buffer, &self.delimiter) {
+                    Some(idx) => (idx, idx + self.delimiter.len(), true),
+                    None => {
+                        // Keep enough bytes to detect a delimiter split across chunks
+                        let data_len = self.buffer.len().saturating_sub(self.delimiter.len() - 1);
+                        (data_len, data_len, false)
+                    }
+                };
+
+                let data: Vec<u8> = self.buffer.drain(..consumed).take(data_len).collect();
+                self.write_to_part(&data)?;
+
+                if !found {
+                    return Ok(false);
+                }
+
+                if let Some(part) = self.current.take() {
+                    parts.push(part);
+                }
+                self.state = State::AfterBoundary;
+
+                Ok(true)
+            }
+            State::Done => Ok(false),
+        }
+    }
+
+    fn create_part(headers_string: &str) -> Result<Part, StatusCodes> {
+        let headers = if headers_string.is_empty() {
+            HashMap::new()
+        } else {
+            parse_headers(headers_string)?
+        };
+
+        let Some(disposition) = headers.get("content-disposition") else {
+            println!("Error(multipart): part without content-disposition header");
+            return Err(StatusCodes::BadRequest);
+        };
+
+        let disposition_type = disposition.split(';').next().unwrap_or("").trim();
+
+        if !disposition_type.eq_ignore_ascii_case("form-data") {
+            println!("Error(multipart): unexpected content-disposition.\n{}\n", disposition);
+            return Err(StatusCodes::BadRequest);
+        }
+
+        let Some(name) = get_header_param(disposition, "name") else {
+            println!("Error(multipart): part without a name.\n{}\n", disposition);
+            return Err(StatusCodes::BadRequest);
+        };
+
+        let filename = get_header_param(disposition, "filename");
+        let content_type = headers.get("content-type").cloned();
+
+        Ok(Part {
+            headers,
+            name,
+            filename,
+            content_type,
+            data: PartData::Memory(Vec::new()),
+            size: 0,
+        })
+    }
+
+    fn write_to_part(&mut self, data: &[u8]) -> Result<(), StatusCodes> {
+        let Some(part) = self.current.as_mut() else {
+            return Ok(());
+        };
+
+        part.size += data.len();
+
+        if part.size > self.limits.max_part_size {
+            println!("Error(multipart): part {} exceeds the limit of {} bytes", part.name, self.limits.max_part_size);
+            return Err(StatusCodes::RequestEntityTooLarge);
+        }
+
+        // Spill file parts to disk once they grow past the memory threshold
+        if part.is_file() && part.size > self.limits.memory_threshold {
+            if let PartData::Memory(buffered) = &part.data {
+                let file = tempfile::Builder::new()
+                    .prefix("upload-")
+                    .tempfile_in(&self.limits.temp_dir)
+                    .and_then(|mut file| file.write_all(buffered).map(|_| file));
+
+                match file {
+                    Ok(file) => part.data = PartData::File(file),
+                    Err(e) => {
+                        println!("Error(multipart): could not create temporary file: {}", e);
+                        return Err(StatusCodes::InternalServerError);
+                    }
+                }
+            }
+        }
+
+        let result = match &mut part.data {
+            PartData::Memory(buffered) => {
+                buffered.extend_from_slice(data);
+                Ok(())
+            }
+            PartData::File(file) => file.write_all(data),
+        };
+
+        if let Err(e) = result {
+            println!("Error(multipart): could not write temporary file: {}", e);
+            return Err(StatusCodes::InternalServerError);
+        }
+
+        Ok(())
+    }
+}
+
+fn find(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack.windows(needle.len()).position(|window| window == needle)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CONTENT_TYPE: &str = "multipart/form-data; boundary=XyZ";
+
+    // Two fields and a file, between a preamble and an epilogue
+    const BODY: &[u8] = b"preamble --Xy\r\n\
+        --XyZ\r\n\
+        Content-Disposition: form-data; name=\"title\"\r\n\
+        \r\n\
+        hello --XyZ\r\n\
+        --XyZ\r\n\
+        Content-Disposition: form-data; name=\"upload\"; filename=\"a.txt\"\r\n\
+        Content-Type: text/plain\r\n\
+        \r\n\
+        line\r\n-- not a delimiter\r\n\
+        --XyZ\r\n\
+        Content-Disposition: form-data; name=\"empty\"\r\n\
+        \r\n\
+        \r\n\
+        --XyZ--\r\n\
+        epilogue --XyZ\r\n";
+
+    fn parser(limits: MultipartLimits) -> MultipartParser {
+        MultipartParser::from_content_type(CONTENT_TYPE, limits).unwrap()
+    }
+
+    // Feeds the chunks and checks that the body was complete
+    fn parse(limits: MultipartLimits, chunks: &[&[u8]]) -> Result<Vec<Part>, StatusCodes> {
+        let mut parser = parser(limits);
+        let mut parts = Vec::new();
+
+        for chunk in chunks {
+            parts.extend(parser.feed(chunk)?);
+        }
+
+        parser.finish()?;
+
+        Ok(parts)
+    }
+
+    fn status(result: Result<Vec<Part>, StatusCodes>) -> i16 {
+        result.err().unwrap().as_code()
+    }
+
+    fn assert_parts(parts: &[Part]) {
+        assert_eq!(parts.len(), 3);
+
+        assert_eq!(parts[0].name, "title");
+        assert!(!parts[0].is_file());
+        assert_eq!(parts[0].text().unwrap(), "hello --XyZ");
+
+        assert_eq!(parts[1].name, "upload");
+        assert_eq!(parts[1].filename.as_deref(), Some("a.txt"));
+        assert_eq!(parts[1].content_type.as_deref(), Some("text/plain"));
+        assert_eq!(parts[1].bytes().unwrap(), b"line\r\n-- not a delimiter");
+        assert_eq!(parts[1].size, 24);
+
+        assert_eq!(parts[2].name, "empty");
+        assert_eq!(parts[2].bytes().unwrap(), b"");
+    }
+
+    #[test]
+    fn parses_parts_between_preamble_and_epilogue() {
+        assert_parts(&parse(MultipartLimits::default(), &[BODY]).unwrap());
+    }
+
+    #[test]
+    fn parses_across_every_split_point() {
+        for split in 0..BODY.len() {
+            let parts = parse(MultipartLimits::default(), &[&BODY[..split], &BODY[split..]]).unwrap();
+            assert_parts(&parts);
+        }
+
+        let bytes: Vec<&[u8]> = BODY.chunks(1).collect();
+        assert_parts(&parse(MultipartLimits::default(), &bytes).unwrap());
+    }
+
+    #[test]
+    fn returns_parts_once_their_delimiter_arrived() {
+        let mut parser = parser(MultipartLimits::default());
+        let end = find(BODY, b"--XyZ\r\nContent-Disposition: form-data; name=\"upload\"").unwrap();
+
+        // The delimiter before the second part is cut after "\r\n--X"
+        assert!(parser.feed(&BODY[..end - 4]).unwrap().is_empty());
+
+        let parts = parser.feed(&BODY[end - 4..end + 6]).unwrap();
+        assert_eq!(parts.len(), 1);
+        assert_eq!(parts[0].name, "title");
+    }
+
+    #[test]
+    fn skips_transport_padding() {
+        let body = b"--XyZ \t\r\nContent-Disposition: form-data; name=\"a\"\r\n\r\n1\r\n--XyZ\t\r\n\
+            Content-Disposition: form-data; name=\"b\"\r\n\r\n2\r\n--XyZ--";
+
+        let parts = parse(MultipartLimits::default(), &[body]).unwrap();
+        assert_eq!(parts.len(), 2);
+        assert_eq!(parts[1].text().unwrap(), "2");
+
+        let body = b"--XyZ x\r\nContent-Disposition: form-data; name=\"a\"\r\n\r\n1\r\n--XyZ--";
+        assert_eq!(status(parse(MultipartLimits::default(), &[body])), 400);
+    }
+
+    #[test]
+    fn rejects_invalid_parts() {
+        let bodies: [&[u8]; 3] = [
+            b"--XyZ\r\nContent-Type: text/plain\r\n\r\n1\r\n--XyZ--",
+            b"--XyZ\r\nContent-Disposition: attachment; name=\"a\"\r\n\r\n1\r\n--XyZ--",
+            b"--XyZ\r\nContent-Disposition: form-data\r\n\r\n1\r\n--XyZ--",
+        ];
+
+        for body in bodies {
+            assert_eq!(status(parse(MultipartLimits::default(), &[body])), 400);
+        }
+    }
+
+    #[test]
+    fn limits_the_size_of_parts_and_bodies() {
+        let part_limit = MultipartLimits {
+            max_part_size: 23,
+            ..MultipartLimits::default()
+        };
+        assert_eq!(status(parse(part_limit, &[BODY])), 413);
+
+        let part_limit = MultipartLimits {
+            max_part_size: 24,
+            ..MultipartLimits::default()
+        };
+        assert_parts(&parse(part_limit, &[BODY]).unwrap());
+
+        // The total counts everything that was fed, across chunks
+        let total_limit = MultipartLimits {
+            max_total_size: BODY.len() - 1,
+            ..MultipartLimits::default()
+        };
+        assert_eq!(status(parse(total_limit, &[&BODY[..10], &BODY[10..]])), 413);
+
+        let total_limit = MultipartLimits {
+            max_total_size: BODY.len(),
+            ..MultipartLimits::default()
+        };
+        assert_parts(&parse(total_limit, &[BODY]).unwrap());
+    }
+
+    #[test]
+    fn spills_large_files_to_the_temp_dir() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let limits = MultipartLimits {
+            memory_threshold: 8,
+            temp_dir: dir.path().to_owned(),
+            ..MultipartLimits::default()
+        };
+
+        let bytes: Vec<&[u8]> = BODY.chunks(5).collect();
+        let parts = parse(limits, &bytes).unwrap();
+        assert_parts(&parts);
+
+        // Only file parts are spilled, fields stay in memory whatever their size
+        assert!(matches!(parts[0].data, PartData::Memory(_)));
+        let PartData::File(file) = &parts[1].data else {
+            panic!("the file part was kept in memory");
+        };
+        assert!(file.path().starts_with(dir.path()));
+
+        // Reading it twice starts at the beginning both times
+        assert_eq!(parts[1].bytes().unwrap(), parts[1].bytes().unwrap());
+
+        drop(parts);
+        assert_eq!(std::fs::read_dir(dir.path()).unwrap().count(), 0);
+    }
+
+    #[test]
+    fn requires_the_closing_boundary() {
+        let end = find(BODY, b"--XyZ--").unwrap();
+
+        for truncated in [&BODY[..0], &BODY[..20], &BODY[..end], &BODY[..end + 6]] {
+            let mut parser = parser(MultipartLimits::default());
+            parser.feed(truncated).unwrap();
+            assert_eq!(parser.finish().err().unwrap().as_code(), 400);
+        }
+
+        let mut parser = parser(MultipartLimits::default());
+        parser.feed(&BODY[..end + 7]).unwrap();
+        assert!(parser.finish().is_ok());
+    }
+
+    #[test]
+    fn reads_the_boundary_from_the_content_type() {
+        let parser = MultipartParser::from_content_type("Multipart/Form-Data; boundary=\"a b\"", MultipartLimits::default());
+        assert_eq!(parser.unwrap().delimiter, b"\r\n--a b");
+
+        let status = |content_type| MultipartParser::from_content_type(content_type, MultipartLimits::default()).err().unwrap().as_code();
+        assert_eq!(status("application/json"), 415);
+        assert_eq!(status("multipart/form-data"), 400);
+        assert_eq!(status("multipart/form-data; boundary="), 400);
+        assert_eq!(status(&format!("multipart/form-data; boundary={}", "a".repeat(71))), 400);
+    }
+}
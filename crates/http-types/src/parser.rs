@@ -0,0 +1,707 @@
+use std::{fmt::Display, ops::Range};
+
+use crate::{
+    message::{is_field_value, is_token},
+    status_codes::StatusCodes,
+};
+
+// Limits enforced while parsing, so that a client can not make the buffer grow without bound
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ParserLimits {
+    // Length of the request line, without the line break
+    pub max_request_line_size: usize,
+    // Combined length of the header lines, including their line breaks
+    pub max_header_size: usize,
+    pub max_header_count: usize,
+}
+
+impl Default for ParserLimits {
+    fn default() -> Self {
+        ParserLimits {
+            max_request_line_size: 8 * 1024,
+            max_header_size: 16 * 1024,
+            max_header_count: 100,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ParseStatus {
+    // The head is complete and takes up this many bytes at the start of the buffer.
+    // Anything after it belongs to the body.
+    Complete(usize),
+    // More data is needed to finish the head
+    Partial,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ParseError {
+    RequestLineTooLong,
+    HeadersTooLarge,
+    TooManyHeaders,
+    InvalidRequestLine,
+    InvalidMethod,
+    InvalidTarget,
+    InvalidVersion,
+    // A well-formed version other than HTTP/1.x
+    UnsupportedVersion,
+    InvalidHeader,
+    ObsoleteLineFolding,
+    // A repeated Content-Length or Host header, which proxies may disagree on
+    DuplicateHeader,
+    // Both framing headers at once, the classic request smuggling setup, RFC 9112, section 6.3
+    ContentLengthWithTransferEncoding,
+    // Transfer-Encoding that does not end in chunked, or is sent with HTTP/1.0
+    InvalidTransferEncoding,
+    // Transfer codings other than chunked, such as gzip
+    UnsupportedTransferEncoding,
+    // HTTP/1.1 requests must name the host, RFC 9112, section 3.2
+    MissingHost,
+}
+
+impl ParseError {
+    // The status code to answer the request with
+    pub fn status(&self) -> StatusCodes {
+        match self {
+            ParseError::RequestLineTooLong => StatusCodes::RequestURITooLong,
+            ParseError::HeadersTooLarge | ParseError::TooManyHeaders => StatusCodes::RequestHeaderFieldsTooLarge,
+            ParseError::UnsupportedVersion => StatusCodes::HTTPVersionNotSupported,
+            ParseError::UnsupportedTransferEncoding => StatusCodes::NotImplemented,
+            _ => StatusCodes::BadRequest,
+        }
+    }
+}
+
+impl Display for ParseError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let message = match self {
+            ParseError::RequestLineTooLong => "request line too long",
+            ParseError::HeadersTooLarge => "headers too large",
+            ParseError::TooManyHeaders => "too many headers",
+            ParseError::InvalidRequestLine => "malformed request line",
+            ParseError::InvalidMethod => "invalid method",
+            ParseError::InvalidTarget => "invalid request target",
+            ParseError::InvalidVersion => "invalid HTTP version",
+            ParseError::UnsupportedVersion => "unsupported HTTP version",
+            ParseError::InvalidHeader => "malformed header line",
+            ParseError::ObsoleteLineFolding => "obsolete line folding",
+            ParseError::DuplicateHeader => "duplicate content-length or host header",
+            ParseError::ContentLengthWithTransferEncoding => "both content-length and transfer-encoding",
+            ParseError::InvalidTransferEncoding => "invalid transfer-encoding",
+            ParseError::UnsupportedTransferEncoding => "unsupported transfer coding",
+            ParseError::MissingHost => "missing host header",
+        };
+
+        write!(f, "{}", message)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum State {
+    // Skipping the empty lines that may precede the request line
+    Start,
+    Headers,
+    Done,
+}
+
+// Incremental parser for the head of an HTTP/1.x request. Feed it the same buffer every time more
+// data arrives, it resumes where it stopped instead of scanning the buffer again. The parsed
+// request line and headers are kept as offsets and read back as slices of the buffer, so nothing
+// is copied.
+#[derive(Debug, Clone)]
+pub struct RequestParser {
+    limits: ParserLimits,
+    state: State,
+    // Everything before this offset has been scanned
+    position: usize,
+    // Start of the line being scanned
+    line_start: usize,
+    // Start of the first header line
+    headers_start: usize,
+    method: Range<usize>,
+    target: Range<usize>,
+    version: Range<usize>,
+    // Offsets of the name and the value of every header
+    headers: Vec<(Range<usize>, Range<usize>)>,
+    // Headers that decide how the request is framed, checked once the head is complete
+    has_host: bool,
+    has_content_length: bool,
+    has_transfer_encoding: bool,
+    // Whether the body is sent in chunks, known once the head is complete
+    chunked: bool,
+}
+
+impl RequestParser {
+    pub fn new(limits: ParserLimits) -> RequestParser {
+        RequestParser {
+            limits,
+            state: State::Start,
+            position: 0,
+            line_start: 0,
+            headers_start: 0,
+            method: 0..0,
+            target: 0..0,
+            version: 0..0,
+            headers: Vec::new(),
+            has_host: false,
+            has_content_length: false,
+            has_transfer_encoding: false,
+            chunked: false,
+        }
+    }
+
+    // Prepares the parser for the next request, keeping its allocations
+    pub fn reset(&mut self) {
+        *self = RequestParser {
+            headers: std::mem::take(&mut self.headers),
+            ..RequestParser::new(self.limits)
+        };
+        self.headers.clear();
+    }
+
+    // Parses as much of the head as the buffer holds. The buffer must start with the bytes
+    // that were passed in previous calls since the last reset.
+    pub fn parse(&mut self, buf: &[u8]) -> Result<ParseStatus, ParseError> {
+        loop {
+            if self.state == State::Done {
+                return Ok(ParseStatus::Complete(self.position));
+            }
+
+            let Some(newline) = buf[self.position..].iter().position(|&b| b == b'\n') else {
+                self.position = buf.len();
+                self.check_partial_line(buf.len())?;
+
+                return Ok(ParseStatus::Partial);
+            };
+
+            let line_end = self.position + newline;
+            self.position = line_end + 1;
+
+            // Both CRLF and a bare LF end a line, RFC 9112, section 2.2. A CR anywhere else is
+            // rejected along with the other control characters.
+            let mut line = self.line_start..line_end;
+            if line_end > line.start && buf[line_end - 1] == b'\r' {
+                line.end -= 1;
+            }
+
+            match self.state {
+                State::Start => self.parse_request_line(buf, line)?,
+                State::Headers => self.parse_header_line(buf, line)?,
+                State::Done => {}
+            }
+
+            self.line_start = self.position;
+        }
+    }
+
+    // The parsed head, once parse returned Complete for this buffer
+    pub fn head<'a>(&'a self, buf: &'a [u8]) -> Option<RequestHead<'a>> {
+        if self.state != State::Done || buf.len() < self.position {
+            return None;
+        }
+
+        Some(RequestHead { parser: self, buf })
+    }
+
+    // Fails as soon as the unfinished line can no longer fit in the limits
+    fn check_partial_line(&self, buf_len: usize) -> Result<(), ParseError> {
+        match self.state {
+            // One extra byte for a carriage return that may be followed by the line feed
+            State::Start if buf_len - self.line_start > self.limits.max_request_line_size + 1 => {
+                Err(ParseError::RequestLineTooLong)
+            }
+            State::Headers if buf_len - self.headers_start > self.limits.max_header_size + 1 => {
+                Err(ParseError::HeadersTooLarge)
+            }
+            _ => Ok(()),
+        }
+    }
+
+    fn parse_request_line(&mut self, buf: &[u8], line: Range<usize>) -> Result<(), ParseError> {
+        if line.len() > self.limits.max_request_line_size {
+            return Err(ParseError::RequestLineTooLong);
+        }
+
+        if line.is_empty() {
+            // Empty lines before the request line are ignored, but only so many of them
+            if self.position > self.limits.max_request_line_size {
+                return Err(ParseError::RequestLineTooLong);
+            }
+
+            return Ok(());
+        }
+
+        // Exactly one space between the parts, RFC 9112, section 3
+        let bytes = &buf[line.clone()];
+        let mut parts = split_offsets(bytes, b' ');
+
+        let (Some(method), Some(target), Some(version), None) = (parts.next(), parts.next(), parts.next(), parts.next())
+        else {
+            return Err(ParseError::InvalidRequestLine);
+        };
+
+        if !is_token(&bytes[method.clone()]) {
+            return Err(ParseError::InvalidMethod);
+        }
+
+        if !is_valid_target(&bytes[method.clone()], &bytes[target.clone()]) {
+            return Err(ParseError::InvalidTarget);
+        }
+
+        match &bytes[version.clone()] {
+            [b'H', b'T', b'T', b'P', b'/', major, b'.', minor] if major.is_ascii_digit() && minor.is_ascii_digit() => {
+                if *major != b'1' {
+                    return Err(ParseError::UnsupportedVersion);
+                }
+            }
+            _ => return Err(ParseError::InvalidVersion),
+        }
+
+        let offset = |part: Range<usize>| line.start + part.start..line.start + part.end;
+
+        self.method = offset(method);
+        self.target = offset(target);
+        self.version = offset(version);
+        self.headers_start = self.position;
+        self.state = State::Headers;
+
+        Ok(())
+    }
+
+    fn parse_header_line(&mut self, buf: &[u8], line: Range<usize>) -> Result<(), ParseError> {
+        // The empty line that ends the head
+        if line.is_empty() {
+            self.finish_head(buf)?;
+            self.state = State::Done;
+            return Ok(());
+        }
+
+        if self.position - self.headers_start > self.limits.max_header_size {
+            return Err(ParseError::HeadersTooLarge);
+        }
+
+        if self.headers.len() == self.limits.max_header_count {
+            return Err(ParseError::TooManyHeaders);
+        }
+
+        let bytes = &buf[line.clone()];
+
+        if matches!(bytes[0], b' ' | b'\t') {
+            return Err(ParseError::ObsoleteLineFolding);
+        }
+
+        let Some(colon) = bytes.iter().position(|&b| b == b':') else {
+            return Err(ParseError::InvalidHeader);
+        };
+
+        // No whitespace is allowed between the name and the colon, RFC 9112, section 5.1
+        let name = 0..colon;
+        let value = trim_whitespace(bytes, colon + 1..bytes.len());
+
+        if !is_token(&bytes[name.clone()]) || !is_field_value(&bytes[value.clone()]) {
+            return Err(ParseError::InvalidHeader);
+        }
+
+        let name_bytes = &bytes[name.clone()];
+        if name_bytes.eq_ignore_ascii_case(b"content-length") || name_bytes.eq_ignore_ascii_case(b"host") {
+            let duplicate = self.headers.iter().any(|(other, _)| buf[other.clone()].eq_ignore_ascii_case(name_bytes));
+
+            if duplicate {
+                return Err(ParseError::DuplicateHeader);
+            }
+        }
+
+        if name_bytes.eq_ignore_ascii_case(b"host") {
+            self.has_host = true;
+        } else if name_bytes.eq_ignore_ascii_case(b"content-length") {
+            self.has_content_length = true;
+        } else if name_bytes.eq_ignore_ascii_case(b"transfer-encoding") {
+            self.has_transfer_encoding = true;
+        }
+
+        if self.has_content_length && self.has_transfer_encoding {
+            return Err(ParseError::ContentLengthWithTransferEncoding);
+        }
+
+        self.headers.push((
+            line.start + name.start..line.start + name.end,
+            line.start + value.start..line.start + value.end,
+        ));
+
+        Ok(())
+    }
+
+    // Checks the headers that only make sense together, once all of them are known
+    fn finish_head(&mut self, buf: &[u8]) -> Result<(), ParseError> {
+        // The version is HTTP/1.x, anything past HTTP/1.0 is handled as HTTP/1.1
+        let http_1_0 = &buf[self.version.clone()] == b"HTTP/1.0";
+
+        if !http_1_0 && !self.has_host {
+            return Err(ParseError::MissingHost);
+        }
+
+        if !self.has_transfer_encoding {
+            return Ok(());
+        }
+
+        // HTTP/1.0 has no transfer codings, so the framing can not be trusted, RFC 9112, section 6.1
+        if http_1_0 {
+            return Err(ParseError::InvalidTransferEncoding);
+        }
+
+        // Every Transfer-Encoding line adds to the list of codings, in order. Empty list
+        // elements are allowed and ignored, RFC 9110, section 5.6.1.
+        let codings: Vec<Range<usize>> = self
+            .headers
+            .iter()
+            .filter(|(name, _)| buf[name.clone()].eq_ignore_ascii_case(b"transfer-encoding"))
+            .flat_map(|(_, value)| {
+                split_offsets(&buf[value.clone()], b',').map(|coding| value.start + coding.start..value.start + coding.end)
+            })
+            .map(|coding| trim_whitespace(buf, coding))
+            .filter(|coding| !coding.is_empty())
+            .collect();
+
+        let is_chunked = |coding: &Range<usize>| buf[coding.clone()].eq_ignore_ascii_case(b"chunked");
+
+        // Only a body that ends with the chunked coding has a known length, RFC 9112, section 6.3
+        match codings.split_last() {
+            Some((last, rest)) if is_chunked(last) && !rest.iter().any(is_chunked) => {
+                if !rest.is_empty() {
+                    return Err(ParseError::UnsupportedTransferEncoding);
+                }
+            }
+            _ => return Err(ParseError::InvalidTransferEncoding),
+        }
+
+        self.chunked = true;
+
+        Ok(())
+    }
+}
+
+// The request line and headers of a parsed head, borrowed from the buffer
+#[derive(Debug, Clone, Copy)]
+pub struct RequestHead<'a> {
+    parser: &'a RequestParser,
+    buf: &'a [u8],
+}
+
+impl<'a> RequestHead<'a> {
+    pub fn method(&self) -> &'a str {
+        self.ascii(self.parser.method.clone())
+    }
+
+    pub fn target(&self) -> &'a str {
+        self.ascii(self.parser.target.clone())
+    }
+
+    pub fn version(&self) -> &'a str {
+        self.ascii(self.parser.version.clone())
+    }
+
+    // Names are returned as sent, values are raw bytes since they may hold any octet
+    pub fn headers(&self) -> impl Iterator<Item = (&'a str, &'a [u8])> + 'a {
+        let buf = self.buf;
+
+        self.parser.headers.iter().map(move |(name, value)| {
+            (
+                std::str::from_utf8(&buf[name.clone()]).unwrap_or_default(),
+                &buf[value.clone()],
+            )
+        })
+    }
+
+    pub fn header_count(&self) -> usize {
+        self.parser.headers.len()
+    }
+
+    // Whether the body is sent with the chunked transfer coding instead of a Content-Length
+    pub fn is_chunked(&self) -> bool {
+        self.parser.chunked
+    }
+
+    // Length of the head, including the empty line that ends it
+    pub fn size(&self) -> usize {
+        self.parser.position
+    }
+
+    // The parser only accepts ASCII in these ranges
+    fn ascii(&self, range: Range<usize>) -> &'a str {
+        std::str::from_utf8(&self.buf[range]).unwrap_or_default()
+    }
+}
+
+// Checks the request-target against the form the method calls for, RFC 9112, section 3.2
+fn is_valid_target(method: &[u8], target: &[u8]) -> bool {
+    if !target.iter().all(|&b| b.is_ascii_alphanumeric() || b"-._~:/?[]@!$&'()*+,;=%".contains(&b)) {
+        return false;
+    }
+
+    match (method, target) {
+        // authority-form
+        (b"CONNECT", _) => !target.contains(&b'/') && !target.contains(&b'?') && target.contains(&b':'),
+        // asterisk-form
+        (b"OPTIONS", b"*") => true,
+        // origin-form
+        (_, [b'/', ..]) => true,
+        // absolute-form
+        (_, [first, ..]) if first.is_ascii_alphabetic() => {
+            let scheme_end = target.iter().position(|&b| b == b':');
+
+            scheme_end.is_some_and(|end| {
+                target[..end]
+                    .iter()
+                    .all(|&b| b.is_ascii_alphanumeric() || b"+-.".contains(&b))
+            })
+        }
+        _ => false,
+    }
+}
+
+fn split_offsets(bytes: &[u8], separator: u8) -> impl Iterator<Item = Range<usize>> + '_ {
+    let mut start = 0;
+    let mut done = false;
+
+    std::iter::from_fn(move || {
+        if done {
+            return None;
+        }
+
+        match bytes[start..].iter().position(|&b| b == separator) {
+            Some(idx) => {
+                let part = start..start + idx;
+                start += idx + 1;
+                Some(part)
+            }
+            None => {
+                done = true;
+                Some(start..bytes.len())
+            }
+        }
+    })
+}
+
+// Narrows the range to exclude the spaces and tabs around it
+fn trim_whitespace(bytes: &[u8], mut range: Range<usize>) -> Range<usize> {
+    while range.start < range.end && matches!(bytes[range.start], b' ' | b'\t') {
+        range.start += 1;
+    }
+
+    while range.end > range.start && matches!(bytes[range.end - 1], b' ' | b'\t') {
+        range.end -= 1;
+    }
+
+    range
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(input: &[u8]) -> Result<ParseStatus, ParseError> {
+        RequestParser::new(ParserLimits::default()).parse(input)
+    }
+
+    fn limits(max_request_line_size: usize, max_header_size: usize, max_header_count: usize) -> ParserLimits {
+        ParserLimits {
+            max_request_line_size,
+            max_header_size,
+            max_header_count,
+        }
+    }
+
+    #[test]
+    fn completes_across_every_split_point() {
+        let input = b"\r\nPOST /items?id=1 HTTP/1.1\r\nHost: example.com\r\nContent-Length: 4\r\nX-Empty:\r\n\r\n";
+
+        for split in 0..input.len() {
+            let mut parser = RequestParser::new(ParserLimits::default());
+
+            assert_eq!(parser.parse(&input[..split]), Ok(ParseStatus::Partial), "{}", split);
+            assert_eq!(parser.parse(input), Ok(ParseStatus::Complete(input.len())), "{}", split);
+
+            let head = parser.head(input).unwrap();
+            assert_eq!((head.method(), head.target(), head.version()), ("POST", "/items?id=1", "HTTP/1.1"));
+
+            let headers: Vec<_> = head.headers().collect();
+            assert_eq!(
+                headers,
+                [("Host", b"example.com".as_slice()), ("Content-Length", b"4"), ("X-Empty", b"")]
+            );
+        }
+    }
+
+    #[test]
+    fn completes_byte_by_byte() {
+        let input = b"GET / HTTP/1.1\r\nHost: a\r\n\r\n";
+        let mut parser = RequestParser::new(ParserLimits::default());
+
+        for end in 1..input.len() {
+            assert_eq!(parser.parse(&input[..end]), Ok(ParseStatus::Partial));
+        }
+
+        assert_eq!(parser.parse(input), Ok(ParseStatus::Complete(input.len())));
+    }
+
+    #[test]
+    fn leaves_pipelined_bytes_after_the_head() {
+        let first = b"GET /a HTTP/1.1\r\nHost: a\r\n\r\n";
+        let input = [first.as_slice(), b"GET /b HTTP/1.1\r\nHost: a\r\n\r\n"].concat();
+        let mut parser = RequestParser::new(ParserLimits::default());
+
+        assert_eq!(parser.parse(&input), Ok(ParseStatus::Complete(first.len())));
+        assert_eq!(parser.head(&input).unwrap().target(), "/a");
+
+        // Parsing again does not move past the first head
+        assert_eq!(parser.parse(&input), Ok(ParseStatus::Complete(first.len())));
+
+        parser.reset();
+        let rest = &input[first.len()..];
+        assert_eq!(parser.parse(rest), Ok(ParseStatus::Complete(rest.len())));
+        assert_eq!(parser.head(rest).unwrap().target(), "/b");
+    }
+
+    #[test]
+    fn accepts_bare_lf_line_endings() {
+        // Recipients may take a bare LF as the end of a line, RFC 9112, section 2.2
+        let input = b"GET / HTTP/1.1\nHost: a\r\nAccept: */*\n\n";
+        let mut parser = RequestParser::new(ParserLimits::default());
+
+        assert_eq!(parser.parse(input), Ok(ParseStatus::Complete(input.len())));
+        assert_eq!(parser.head(input).unwrap().header_count(), 2);
+    }
+
+    #[test]
+    fn rejects_bare_cr() {
+        assert_eq!(parse(b"GET / HTTP/1.1\r\nHost: a\rb\r\n\r\n"), Err(ParseError::InvalidHeader));
+        assert_eq!(parse(b"GET /\r HTTP/1.1\r\n"), Err(ParseError::InvalidTarget));
+    }
+
+    #[test]
+    fn limits_the_request_line() {
+        let line = format!("GET /{} HTTP/1.1", "a".repeat(100));
+        let fits = limits(line.len(), 1024, 10);
+        let too_small = limits(line.len() - 1, 1024, 10);
+
+        let input = format!("{}\r\nHost: a\r\n\r\n", line);
+        assert!(matches!(RequestParser::new(fits).parse(input.as_bytes()), Ok(ParseStatus::Complete(_))));
+        assert_eq!(RequestParser::new(too_small).parse(input.as_bytes()), Err(ParseError::RequestLineTooLong));
+
+        // Reported before the line ends
+        let partial = &input.as_bytes()[..line.len() + 1];
+        assert_eq!(RequestParser::new(too_small).parse(partial), Err(ParseError::RequestLineTooLong));
+        assert_eq!(ParseError::RequestLineTooLong.status().as_code(), 414);
+
+        // Empty lines before the request line count towards it
+        let blank = "\r\n".repeat(100);
+        assert_eq!(
+            RequestParser::new(limits(64, 1024, 10)).parse(blank.as_bytes()),
+            Err(ParseError::RequestLineTooLong)
+        );
+    }
+
+    #[test]
+    fn limits_the_headers() {
+        let input = b"GET / HTTP/1.1\r\nHost: a\r\nX-Long: aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaa\r\n\r\n";
+
+        assert_eq!(RequestParser::new(limits(64, 32, 10)).parse(input), Err(ParseError::HeadersTooLarge));
+        assert_eq!(RequestParser::new(limits(64, 32, 10)).parse(&input[..60]), Err(ParseError::HeadersTooLarge));
+        assert_eq!(RequestParser::new(limits(64, 1024, 1)).parse(input), Err(ParseError::TooManyHeaders));
+        assert!(matches!(RequestParser::new(limits(64, 1024, 2)).parse(input), Ok(ParseStatus::Complete(_))));
+
+        assert_eq!(ParseError::HeadersTooLarge.status().as_code(), 431);
+        assert_eq!(ParseError::TooManyHeaders.status().as_code(), 431);
+    }
+
+    #[test]
+    fn rejects_other_major_versions() {
+        for version in ["HTTP/2.0", "HTTP/0.9", "HTTP/3.0"] {
+            let input = format!("GET / {}\r\n\r\n", version);
+            assert_eq!(parse(input.as_bytes()), Err(ParseError::UnsupportedVersion), "{}", version);
+        }
+
+        assert_eq!(ParseError::UnsupportedVersion.status().as_code(), 505);
+
+        for version in ["HTTP/1", "HTTP/1.10", "http/1.1", "HTTP/a.1"] {
+            let input = format!("GET / {}\r\n\r\n", version);
+            assert_eq!(parse(input.as_bytes()), Err(ParseError::InvalidVersion), "{}", version);
+        }
+    }
+
+    #[test]
+    fn rejects_malformed_request_lines() {
+        assert_eq!(parse(b"GET  / HTTP/1.1\r\n"), Err(ParseError::InvalidRequestLine));
+        assert_eq!(parse(b"GET / HTTP/1.1 \r\n"), Err(ParseError::InvalidRequestLine));
+        assert_eq!(parse(b"G(T / HTTP/1.1\r\n"), Err(ParseError::InvalidMethod));
+        assert_eq!(parse(b"GET foo HTTP/1.1\r\n"), Err(ParseError::InvalidTarget));
+        assert_eq!(parse(b"GET / HTTP/1.1\r\nHost : a\r\n"), Err(ParseError::InvalidHeader));
+        assert_eq!(parse(b"GET / HTTP/1.1\r\nHost: a\r\n folded\r\n"), Err(ParseError::ObsoleteLineFolding));
+    }
+
+    #[test]
+    fn rejects_transfer_encoding_with_content_length() {
+        let input = b"POST / HTTP/1.1\r\nHost: a\r\nContent-Length: 5\r\nTransfer-Encoding: chunked\r\n\r\n";
+        assert_eq!(parse(input), Err(ParseError::ContentLengthWithTransferEncoding));
+
+        let input = b"POST / HTTP/1.1\r\nHost: a\r\nTransfer-Encoding: chunked\r\nContent-Length: 5\r\n\r\n";
+        assert_eq!(parse(input), Err(ParseError::ContentLengthWithTransferEncoding));
+        assert_eq!(ParseError::ContentLengthWithTransferEncoding.status().as_code(), 400);
+    }
+
+    #[test]
+    fn accepts_chunked_transfer_encoding() {
+        let input = b"POST / HTTP/1.1\r\nHost: a\r\nTransfer-Encoding: Chunked\r\n\r\n";
+        let mut parser = RequestParser::new(ParserLimits::default());
+
+        assert_eq!(parser.parse(input), Ok(ParseStatus::Complete(input.len())));
+        assert!(parser.head(input).unwrap().is_chunked());
+    }
+
+    #[test]
+    fn rejects_other_transfer_codings() {
+        let input = b"POST / HTTP/1.1\r\nHost: a\r\nTransfer-Encoding: gzip, chunked\r\n\r\n";
+        assert_eq!(parse(input), Err(ParseError::UnsupportedTransferEncoding));
+        assert_eq!(ParseError::UnsupportedTransferEncoding.status().as_code(), 501);
+
+        let input = b"POST / HTTP/1.1\r\nHost: a\r\nTransfer-Encoding: gzip\r\nTransfer-Encoding: chunked\r\n\r\n";
+        assert_eq!(parse(input), Err(ParseError::UnsupportedTransferEncoding));
+    }
+
+    #[test]
+    fn rejects_transfer_encoding_not_ending_in_chunked() {
+        for coding in ["gzip", "chunked, gzip", "chunked, chunked", ""] {
+            let input = format!("POST / HTTP/1.1\r\nHost: a\r\nTransfer-Encoding: {}\r\n\r\n", coding);
+            assert_eq!(parse(input.as_bytes()), Err(ParseError::InvalidTransferEncoding), "{}", coding);
+        }
+    }
+
+    #[test]
+    fn rejects_transfer_encoding_in_http_1_0() {
+        let input = b"POST / HTTP/1.0\r\nTransfer-Encoding: chunked\r\n\r\n";
+        assert_eq!(parse(input), Err(ParseError::InvalidTransferEncoding));
+    }
+
+    #[test]
+    fn requires_host_in_http_1_1() {
+        let input = b"GET / HTTP/1.1\r\nAccept: */*\r\n\r\n";
+        assert_eq!(parse(input), Err(ParseError::MissingHost));
+        assert_eq!(ParseError::MissingHost.status().as_code(), 400);
+
+        // Later minor versions are handled as HTTP/1.1
+        assert_eq!(parse(b"GET / HTTP/1.2\r\n\r\n"), Err(ParseError::MissingHost));
+
+        let input = b"GET / HTTP/1.0\r\n\r\n";
+        assert_eq!(parse(input), Ok(ParseStatus::Complete(input.len())));
+    }
+
+    #[test]
+    fn rejects_duplicate_content_length_and_host() {
+        let input = b"POST / HTTP/1.1\r\nHost: a\r\nContent-Length: 1\r\ncontent-length: 1\r\n\r\n";
+        assert_eq!(parse(input), Err(ParseError::DuplicateHeader));
+
+        let input = b"GET / HTTP/1.1\r\nHost: a\r\nHOST: b\r\n\r\n";
+        assert_eq!(parse(input), Err(ParseError::DuplicateHeader));
+        assert_eq!(ParseError::DuplicateHeader.status().as_code(), 400);
+    }
+}
@@ -0,0 +1,106 @@
+// Decodes a percent-encoded string as described in RFC 3986, section 2.1.
+// Returns None if an escape sequence is malformed or the result is not valid UTF-8.
+pub fn percent_decode(input: &str) -> Option<String> {
+    let bytes = input.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+
+    let mut idx = 0;
+    while idx < bytes.len() {
+        if bytes[idx] != b'%' {
+            decoded.push(bytes[idx]);
+            idx += 1;
+            continue;
+        }
+
+        if idx + 2 >= bytes.len() {
+            return None;
+        }
+
+        let high = hex_value(bytes[idx + 1])?;
+        let low = hex_value(bytes[idx + 2])?;
+
+        decoded.push(high << 4 | low);
+        idx += 3;
+    }
+
+    String::from_utf8(decoded).ok()
+}
+
+// Decodes a value following the application/x-www-form-urlencoded rules,
+// which are the same as percent_decode except that '+' stands for a space.
+pub fn form_urlencoded_decode(input: &str) -> Option<String> {
+    percent_decode(&input.replace('+', " "))
+}
+
+// Percent-encodes every byte except the unreserved characters of RFC 3986, section 2.3
+pub fn percent_encode(input: &str) -> String {
+    let mut encoded = String::with_capacity(input.len());
+
+    for byte in input.bytes() {
+        if byte.is_ascii_alphanumeric() || b"-._~".contains(&byte) {
+            encoded.push(byte as char);
+        } else {
+            encoded.push_str(&format!("%{:02X}", byte));
+        }
+    }
+
+    encoded
+}
+
+fn hex_value(byte: u8) -> Option<u8> {
+    match byte {
+        b'0'..=b'9' => Some(byte - b'0'),
+        b'a'..=b'f' => Some(byte - b'a' + 10),
+        b'A'..=b'F' => Some(byte - b'A' + 10),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn decodes_escapes() {
+        assert_eq!(percent_decode("a%20b%2Fc%2fd").as_deref(), Some("a b/c/d"));
+        assert_eq!(percent_decode("%C3%BC%F0%9F%A6%80").as_deref(), Some("ü🦀"));
+        assert_eq!(percent_decode("%2541").as_deref(), Some("%41"));
+        assert_eq!(percent_decode("").as_deref(), Some(""));
+    }
+
+    #[test]
+    fn rejects_malformed_escapes() {
+        for input in ["%", "a%", "%4", "a%4", "%G1", "%4G", "%-1", "% 41", "%%41"] {
+            assert_eq!(percent_decode(input), None, "{}", input);
+        }
+    }
+
+    #[test]
+    fn rejects_invalid_utf8() {
+        assert_eq!(percent_decode("%FF"), None);
+        assert_eq!(percent_decode("%C3"), None);
+        assert_eq!(percent_decode("%C3%28"), None);
+    }
+
+    #[test]
+    fn decodes_plus_only_in_forms() {
+        assert_eq!(percent_decode("a+b").as_deref(), Some("a+b"));
+        assert_eq!(form_urlencoded_decode("a+b").as_deref(), Some("a b"));
+        assert_eq!(form_urlencoded_decode("a%2Bb+%2B").as_deref(), Some("a+b +"));
+        assert_eq!(form_urlencoded_decode("a+%"), None);
+    }
+
+    #[test]
+    fn encodes_everything_but_unreserved_characters() {
+        assert_eq!(percent_encode("AZaz09-._~"), "AZaz09-._~");
+        assert_eq!(percent_encode("a b+c/d%e=f&\n"), "a%20b%2Bc%2Fd%25e%3Df%26%0A");
+        assert_eq!(percent_encode("ü"), "%C3%BC");
+    }
+
+    #[test]
+    fn round_trips() {
+        for input in ["", "plain", "a b+c", "100%", "%41", "key=value&x=y\r\n", "ü🦀", "\0\u{7f}"] {
+            assert_eq!(percent_decode(&percent_encode(input)).as_deref(), Some(input));
+        }
+    }
+}
@@ -1,16 +1,53 @@
-use std::collections::HashMap;
+use std::{
+    collections::HashMap,
+    net::{IpAddr, SocketAddr},
+    sync::Arc,
+};
 
-use crate::search_params::SearchParams;
+use crate::{
+    client_certificate::ClientCertificate,
+    cookie::CookieJar,
+    cookie_keys::CookieKeys,
+    forwarded::{ForwardedHop, TrustedProxies},
+    multipart::Part,
+    search_params::SearchParams,
+    session::Session,
+};
 
-use super::{method::HTTPMethod, status_codes::StatusCodes};
+#[cfg(feature = "serde")]
+use crate::deserializer::{deserialize_params, DeserializeError};
+#[cfg(feature = "serde")]
+use serde::de::Error;
+
+use super::{method::HTTPMethod, status_codes::StatusCodes, version::HTTPVersion};
+
+// Maximum body size accepted by Request::form
+pub const DEFAULT_FORM_SIZE_LIMIT: usize = 1024 * 1024;
 
 #[derive(Debug)]
 pub struct Request {
     pub method: HTTPMethod,
     pub uri: String,
     pub headers: HashMap<String, String>,
-    pub body: String,
+    pub body: Vec<u8>,
     pub search_params: SearchParams,
+    // Filled in with the dynamic params of the route that is handling the request
+    pub path_params: Vec<(String, String)>,
+    // Keys used by signed_cookies and private_cookies, configured on the server
+    pub cookie_keys: Arc<CookieKeys>,
+    // Only set when sessions are enabled on the server
+    pub session: Option<Session>,
+    // Proxies whose forwarding headers client_ip, scheme and host believe, configured on the server
+    pub trusted_proxies: Arc<TrustedProxies>,
+    version: HTTPVersion,
+    // Addresses of the connection the request came in on, set by the server
+    remote_addr: Option<SocketAddr>,
+    local_addr: Option<SocketAddr>,
+    // Whether the connection is encrypted with TLS
+    secure: bool,
+    client_certificate: Option<Arc<ClientCertificate>>,
+    // The parts of a multipart/form-data body, which the server parses as the body arrives
+    multipart: Option<Vec<Part>>,
 }
 
 impl Request {
@@ -18,7 +55,7 @@ impl Request {
         method: HTTPMethod,
         uri: String,
         headers: HashMap<String, String>,
-        body: String,
+        body: Vec<u8>,
         search_params: SearchParams,
     ) -> Request {
         Request {
@@ -27,46 +64,194 @@ impl Request {
             headers,
             body,
             search_params,
+            path_params: Vec::new(),
+            cookie_keys: CookieKeys::ephemeral(),
+            session: None,
+            trusted_proxies: Arc::new(TrustedProxies::default()),
+            version: HTTPVersion::default(),
+            remote_addr: None,
+            local_addr: None,
+            secure: false,
+            client_certificate: None,
+            multipart: None,
+        }
+    }
+
+    pub fn version(&self) -> HTTPVersion {
+        self.version
+    }
+
+    pub fn set_version(&mut self, version: HTTPVersion) {
+        self.version = version;
+    }
+
+    // The address of the client, or of the last proxy in front of it
+    pub fn remote_addr(&self) -> Option<SocketAddr> {
+        self.remote_addr
+    }
+
+    pub fn local_addr(&self) -> Option<SocketAddr> {
+        self.local_addr
+    }
+
+    // The address of the client. Behind trusted proxies, this is the address they reported
+    // in the Forwarded or X-Forwarded-For header.
+    pub fn client_ip(&self) -> Option<IpAddr> {
+        let peer = self.remote_addr?.ip();
+
+        match self.forwarded_hop() {
+            Some(ForwardedHop { for_ip: Some(ip), .. }) => Some(ip),
+            _ => Some(peer),
+        }
+    }
+
+    // "http" or "https", depending on the connection or on what trusted proxies reported
+    pub fn scheme(&self) -> String {
+        match self.forwarded_hop() {
+            Some(ForwardedHop { proto: Some(proto), .. }) => proto,
+            _ if self.secure => "https".to_owned(),
+            _ => "http".to_owned(),
+        }
+    }
+
+    pub fn is_secure(&self) -> bool {
+        self.secure
+    }
+
+    pub fn set_secure(&mut self, secure: bool) {
+        self.secure = secure;
+    }
+
+    // The verified certificate the client presented, when the server asks for one
+    pub fn client_certificate(&self) -> Option<&ClientCertificate> {
+        self.client_certificate.as_deref()
+    }
+
+    pub fn set_client_certificate(&mut self, certificate: Option<Arc<ClientCertificate>>) {
+        self.client_certificate = certificate;
+    }
+
+    // Sent by clients reconnecting to an event stream, the id of the last event they received
+    pub fn last_event_id(&self) -> Option<&str> {
+        self.headers.get("last-event-id").map(String::as_str)
+    }
+
+    // The host the client sent the request to, including the port if there was one
+    pub fn host(&self) -> Option<String> {
+        match self.forwarded_hop() {
+            Some(ForwardedHop { host: Some(host), .. }) => Some(host),
+            _ => self.headers.get("host").cloned(),
+        }
+    }
+
+    fn forwarded_hop(&self) -> Option<ForwardedHop> {
+        self.trusted_proxies.forwarded_hop(self.remote_addr?.ip(), &self.headers)
+    }
+
+    pub fn set_addrs(&mut self, remote_addr: Option<SocketAddr>, local_addr: Option<SocketAddr>) {
+        self.remote_addr = remote_addr;
+        self.local_addr = local_addr;
+    }
+
+    // The body as text, failing with 400 if it is not valid UTF-8
+    pub fn text(&self) -> Result<&str, StatusCodes> {
+        match std::str::from_utf8(&self.body) {
+            Ok(text) => Ok(text),
+            Err(_) => {
+                println!("Error(text): could not parse body to a string");
+                Err(StatusCodes::BadRequest)
+            }
+        }
+    }
+
+    // The content-type header without its parameters (e.g. "text/html" for "text/html; charset=utf-8")
+    pub fn media_type(&self) -> Option<&str> {
+        let content_type = self.headers.get("content-type")?;
+
+        match content_type.split_once(';') {
+            Some((media_type, _)) => Some(media_type.trim()),
+            None => Some(content_type.trim()),
+        }
+    }
+
+    pub fn form(&self) -> Result<SearchParams, StatusCodes> {
+        self.form_with_limit(DEFAULT_FORM_SIZE_LIMIT)
+    }
+
+    pub fn form_with_limit(&self, limit: usize) -> Result<SearchParams, StatusCodes> {
+        match self.media_type() {
+            Some(media_type) if media_type.eq_ignore_ascii_case("application/x-www-form-urlencoded") => {}
+            _ => return Err(StatusCodes::UnsupportedMediaType),
         }
+
+        if self.body.len() > limit {
+            println!("Error(form): body of {} bytes exceeds the limit of {} bytes", self.body.len(), limit);
+            return Err(StatusCodes::RequestEntityTooLarge);
+        }
+
+        SearchParams::from(self.text()?)
+    }
+
+    pub fn cookies(&self) -> CookieJar {
+        match self.headers.get("cookie") {
+            Some(cookie_header) => CookieJar::from(cookie_header),
+            None => CookieJar::new(),
+        }
+    }
+
+    // The cookies whose signature is valid, with the signature removed. Tampered cookies are left out.
+    pub fn signed_cookies(&self) -> CookieJar {
+        let mut jar = CookieJar::new();
+
+        for cookie in self.cookies().iter() {
+            if let Some(cookie) = self.cookie_keys.verify(cookie) {
+                jar.add(cookie);
+            }
+        }
+
+        jar
+    }
+
+    // The cookies that could be decrypted, with their decrypted values. Tampered cookies are left out.
+    pub fn private_cookies(&self) -> CookieJar {
+        let mut jar = CookieJar::new();
+
+        for cookie in self.cookies().iter() {
+            if let Some(cookie) = self.cookie_keys.decrypt(cookie) {
+                jar.add(cookie);
+            }
+        }
+
+        jar
+    }
+
+    // The fields and files of a multipart/form-data body, failing with 415 for other requests.
+    // The server parses these bodies while reading them, so body is left empty for them.
+    pub fn multipart(&self) -> Result<&[Part], StatusCodes> {
+        self.multipart.as_deref().ok_or(StatusCodes::UnsupportedMediaType)
+    }
+
+    pub fn set_multipart(&mut self, parts: Vec<Part>) {
+        self.multipart = Some(parts);
+    }
+
+    #[cfg(feature = "serde")]
+    pub fn json<T: serde::de::DeserializeOwned>(&self) -> Result<T, DeserializeError> {
+        let mut deserializer = serde_json::Deserializer::from_slice(&self.body);
+        let value = serde_path_to_error::deserialize(&mut deserializer)?;
+
+        // Make sure there is nothing but whitespace after the JSON value
+        deserializer.end().map_err(DeserializeError::custom)?;
+
+        Ok(value)
     }
 
-    pub fn parse_request_line(request_line: &str) -> Result<(HTTPMethod, String), StatusCodes> {
-        let Some((method, rest)) = request_line.split_once(' ') else {
-            println!("Error(parse_request_line[0]): incorrect string format.\n{}\n", request_line);
-            return Err(StatusCodes::BadRequest);
-        };
-
-        // TODO: Might wanna regex test the uri to check its validity
-        let Some((uri, _)) = rest.split_once(' ') else {
-            println!("Error(parse_request_line[1]): incorrect string format.\n{}\n", request_line);
-            return Err(StatusCodes::BadRequest);
-        };
-
-        let parsed_method = match method {
-            "OPTIONS" => HTTPMethod::OPTIONS,
-            "GET" => HTTPMethod::GET,
-            "HEAD" => HTTPMethod::HEAD,
-            "POST" => HTTPMethod::POST,
-            "PUT" => HTTPMethod::PUT,
-            "DELETE" => HTTPMethod::DELETE,
-            "TRACE" => HTTPMethod::TRACE,
-            "CONNECT" => HTTPMethod::CONNECT,
-            _ => return Err(StatusCodes::MethodNotAllowed),
-        };
-
-        Ok((parsed_method, uri.to_owned()))
-    }
-
-    pub fn get_sections(request_string: &str) -> Result<(&str, &str, &str), StatusCodes> {
-        let Some((request_line, rest)) = request_string.split_once("\r\n") else {
-            println!("Error(get_sections): could not get request_line.\n{}\n", request_string);
-            return Err(StatusCodes::BadRequest);
-        };
-        let Some((headers, body)) = rest.split_once("\r\n\r\n") else {
-            println!("Error(get_sections): could not split headers and body.\n{}\n", request_string);
-            return Err(StatusCodes::BadRequest);
-        };
-
-        Ok((request_line, headers, body))
+    #[cfg(feature = "serde")]
+    pub fn params<T: serde::de::DeserializeOwned>(&self) -> Result<T, DeserializeError> {
+        deserialize_params(
+            self.path_params
+                .iter()
+                .map(|(key, value)| (key.as_str(), std::slice::from_ref(value))),
+        )
     }
 }
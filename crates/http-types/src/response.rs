@@ -1,43 +1,195 @@
 use std::collections::HashMap;
 
-use async_std::{io::WriteExt, net::TcpStream};
+use async_std::{
+    io::{Write, WriteExt},
+    stream::Stream,
+};
 
-use super::status_codes::StatusCodes;
+use super::{
+    cookie::Cookie,
+    cookie_keys::CookieKeys,
+    sse::{Event, EventStream},
+    status_codes::StatusCodes,
+    version::HTTPVersion,
+};
+
+#[cfg(feature = "websocket")]
+use crate::{
+    request::Request,
+    websocket::{self, WebSocketHandler},
+};
 
 pub struct Response {
+    // Sent in the status line, the server sets it to the version of the request
+    version: HTTPVersion,
     status: StatusCodes,
     headers: HashMap<String, String>,
+    cookies: Vec<Cookie>,
     body: String,
+    // Sent after the head in place of the body, for responses made with Response::sse
+    events: Option<EventStream>,
+    // Takes over the connection once this response is sent
+    #[cfg(feature = "websocket")]
+    websocket: Option<WebSocketHandler>,
 }
 
 impl Response {
     pub fn new(status: StatusCodes, mut headers: HashMap<String, String>, body: &str) -> Response {
-        let content_length = body.as_bytes().len().to_string();
-        headers.entry("content-length".to_owned()).and_modify(|e| *e = content_length.to_owned()).or_insert(content_length);
-        
-        if !headers.contains_key("content-type") {
-            headers.insert("content-type".to_owned(), "text/plain".to_owned());
+        // Informational responses have no content, RFC 9110, section 15.2
+        if status.as_code() >= 200 {
+            let content_length = body.as_bytes().len().to_string();
+            headers.entry("content-length".to_owned()).and_modify(|e| *e = content_length.to_owned()).or_insert(content_length);
+
+            if !headers.contains_key("content-type") {
+                headers.insert("content-type".to_owned(), "text/plain".to_owned());
+            }
         }
 
         Response {
+            version: HTTPVersion::default(),
             status,
             headers,
+            cookies: Vec::new(),
             body: body.to_owned(),
+            events: None,
+            #[cfg(feature = "websocket")]
+            websocket: None,
+        }
+    }
+
+    // A text/event-stream response sending the events of the stream as they come, with
+    // comments in between to keep the connection open. It ends with the stream.
+    pub fn sse(events: impl Stream<Item = Event> + Send + 'static) -> Response {
+        let mut headers = HashMap::new();
+        headers.insert("content-type".to_owned(), "text/event-stream".to_owned());
+        headers.insert("cache-control".to_owned(), "no-cache".to_owned());
+
+        let mut response = Response::new(StatusCodes::OK, headers, "");
+
+        // The events go on until the connection closes
+        response.headers.remove("content-length");
+        response.events = Some(EventStream::new(events));
+        response
+    }
+
+    // Accepts the WebSocket handshake of the request with a 101 response, after which the
+    // handler is given the connection. Requests that aren't a valid handshake get a 400, or
+    // a 426 when they ask for a version other than 13.
+    #[cfg(feature = "websocket")]
+    pub fn websocket(request: &Request, handler: WebSocketHandler) -> Response {
+        let mut headers = HashMap::new();
+
+        match websocket::accept_key(request) {
+            Ok(accept) => {
+                headers.insert("upgrade".to_owned(), "websocket".to_owned());
+                headers.insert("connection".to_owned(), "Upgrade".to_owned());
+                headers.insert("sec-websocket-accept".to_owned(), accept);
+
+                let mut response = Response::new(StatusCodes::SwitchingProtocols, headers, "");
+                response.websocket = Some(handler);
+                response
+            }
+            Err(status) => {
+                if let StatusCodes::UpgradeRequired = status {
+                    headers.insert("sec-websocket-version".to_owned(), "13".to_owned());
+                }
+
+                Response::new(status, headers, "")
+            }
         }
     }
 
+    #[cfg(feature = "serde")]
+    pub fn json<T: serde::Serialize>(status: StatusCodes, mut headers: HashMap<String, String>, value: &T) -> Response {
+        let body = match serde_json::to_string(value) {
+            Ok(body) => body,
+            Err(e) => {
+                println!("Error(Response::json): {}", e);
+                return Response::new(StatusCodes::InternalServerError, HashMap::new(), "");
+            }
+        };
+
+        if !headers.contains_key("content-type") {
+            headers.insert("content-type".to_owned(), "application/json".to_owned());
+        }
+
+        Response::new(status, headers, &body)
+    }
+
+    pub fn version(&self) -> HTTPVersion {
+        self.version
+    }
+
+    pub fn set_version(&mut self, version: HTTPVersion) {
+        self.version = version;
+    }
+
+    pub fn status(&self) -> &StatusCodes {
+        &self.status
+    }
+
+    pub fn headers(&self) -> &HashMap<String, String> {
+        &self.headers
+    }
+
+    pub fn cookies(&self) -> &[Cookie] {
+        &self.cookies
+    }
+
+    pub fn body(&self) -> &str {
+        &self.body
+    }
+
+    // Taken by the server to send the events after the head
+    pub fn take_events(&mut self) -> Option<EventStream> {
+        self.events.take()
+    }
+
+    #[cfg(feature = "websocket")]
+    pub fn websocket_handler(&self) -> Option<WebSocketHandler> {
+        self.websocket
+    }
+
+    // Replaces the header with the same name, if there is one
+    pub fn set_header(&mut self, name: &str, value: &str) {
+        self.headers.insert(name.to_ascii_lowercase(), value.to_owned());
+    }
+
+    // Every cookie is sent in its own set-cookie header
+    pub fn add_cookie(&mut self, cookie: Cookie) {
+        if !cookie.is_valid() {
+            println!("Error(add_cookie): invalid cookie {:?}", cookie.name);
+            return;
+        }
+
+        self.cookies.push(cookie);
+    }
+
+    pub fn add_signed_cookie(&mut self, cookie: Cookie, keys: &CookieKeys) {
+        self.add_cookie(keys.sign(cookie));
+    }
+
+    pub fn add_private_cookie(&mut self, cookie: Cookie, keys: &CookieKeys) {
+        self.add_cookie(keys.encrypt(cookie));
+    }
+
     fn parse_headers(&self) -> String {
         let mut headers_string = String::new();
         for (key, value) in &self.headers {
             headers_string.push_str(&format!("{}: {}\r\n", *key, *value));
         }
 
+        for cookie in &self.cookies {
+            headers_string.push_str(&format!("set-cookie: {}\r\n", cookie.to_header_value()));
+        }
+
         headers_string
     }
 
     pub fn to_string(&self) -> String {
         format!(
-            "HTTP/1.1 {} {}\r\n{}\r\n{}",
+            "{} {} {}\r\n{}\r\n{}",
+            self.version.as_str(),
             self.status.as_code(),
             self.status.as_reason_phrase(),
             self.parse_headers(),
@@ -45,8 +197,12 @@ impl Response {
         )
     }
 
-    pub async fn send(&self, stream: &mut TcpStream) {
-        let result = stream.write_all(self.to_string().as_bytes()).await;
+    pub async fn send<W: Write + Unpin>(&self, stream: &mut W) {
+        // Flushed, since TLS streams buffer what is written to them
+        let result = match stream.write_all(self.to_string().as_bytes()).await {
+            Ok(_) => stream.flush().await,
+            Err(error) => Err(error),
+        };
 
         match result {
             Ok(_) => {}
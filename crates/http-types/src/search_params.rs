@@ -1,5 +1,10 @@
 use std::collections::HashMap;
 
+use crate::{percent_encoding::form_urlencoded_decode, status_codes::StatusCodes};
+
+#[cfg(feature = "serde")]
+use crate::deserializer::{deserialize_params, DeserializeError};
+
 #[derive(Debug, Clone)]
 pub struct SearchParams {
     values: HashMap<String, Vec<String>>,
@@ -43,7 +48,16 @@ impl SearchParams {
         self.values.insert(key.to_owned(), value.clone());
     }
 
-    pub fn from(search_string: &str) -> SearchParams {
+    #[cfg(feature = "serde")]
+    pub fn deserialize<T: serde::de::DeserializeOwned>(&self) -> Result<T, DeserializeError> {
+        deserialize_params(
+            self.values
+                .iter()
+                .map(|(key, values)| (key.as_str(), values.as_slice())),
+        )
+    }
+
+    pub fn from(search_string: &str) -> Result<SearchParams, StatusCodes> {
         let search_split = search_string.split("&");
         let mut search = HashMap::<String, Vec<String>>::new();
 
@@ -57,12 +71,17 @@ impl SearchParams {
                 None => (param, ""),
             };
 
+            let (Some(key), Some(value)) = (form_urlencoded_decode(key), form_urlencoded_decode(value)) else {
+                println!("Error(SearchParams::from): invalid percent-encoding.\n{}\n", param);
+                return Err(StatusCodes::BadRequest);
+            };
+
             search
-                .entry(key.to_owned())
+                .entry(key)
                 .and_modify(|e| e.push(value.to_owned()))
-                .or_insert([value.to_owned()].to_vec());
+                .or_insert([value].to_vec());
         }
 
-        SearchParams { values: search }
+        Ok(SearchParams { values: search })
     }
 }
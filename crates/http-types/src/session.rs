@@ -0,0 +1,694 @@
+use std::{
+    collections::HashMap,
+    fs,
+    io::{Error, ErrorKind, Write},
+    path::PathBuf,
+    str::FromStr,
+    sync::{Arc, Mutex},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use aes_gcm::aead::{rand_core::RngCore, OsRng};
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use tempfile::NamedTempFile;
+
+use crate::{
+    cookie::{Cookie, SameSite},
+    cookie_keys::CookieKeys,
+    percent_encoding::{percent_decode, percent_encode},
+    request::Request,
+    response::Response,
+};
+
+pub type SessionData = HashMap<String, String>;
+
+// Storage for session data. Implementations must drop sessions once they expire.
+pub trait SessionStore: Send + Sync {
+    fn load(&self, id: &str) -> Result<Option<SessionData>, Error>;
+
+    fn save(&self, id: &str, data: &SessionData, ttl: Duration) -> Result<(), Error>;
+
+    fn destroy(&self, id: &str) -> Result<(), Error>;
+
+    // Expires the session ttl from now, keeping its data. Stores that can update the expiry on
+    // its own should do so, the default writes back what was loaded and can undo a concurrent save.
+    fn touch(&self, id: &str, ttl: Duration) -> Result<(), Error> {
+        match self.load(id)? {
+            Some(data) => self.save(id, &data, ttl),
+            None => Ok(()),
+        }
+    }
+
+    // Removes every expired session. Called periodically by the server.
+    fn sweep(&self) -> Result<(), Error> {
+        Ok(())
+    }
+}
+
+#[derive(Debug, Default)]
+struct SessionState {
+    // None until the session is saved for the first time
+    id: Option<String>,
+    data: SessionData,
+    changed: bool,
+    destroyed: bool,
+    regenerate: bool,
+}
+
+// The session of the client that sent the request. Values are stored as strings
+// and converted to and from the requested type.
+#[derive(Debug, Default)]
+pub struct Session {
+    state: Mutex<SessionState>,
+}
+
+impl Session {
+    pub fn new() -> Session {
+        Session::default()
+    }
+
+    pub fn existing(id: &str, data: SessionData) -> Session {
+        Session {
+            state: Mutex::new(SessionState {
+                id: Some(id.to_owned()),
+                data,
+                ..SessionState::default()
+            }),
+        }
+    }
+
+    pub fn id(&self) -> Option<String> {
+        self.lock().id.clone()
+    }
+
+    pub fn get<T: FromStr>(&self, key: &str) -> Option<T> {
+        self.lock().data.get(key)?.parse().ok()
+    }
+
+    pub fn insert<T: ToString>(&self, key: &str, value: T) {
+        let mut state = self.lock();
+
+        state.data.insert(key.to_owned(), value.to_string());
+        state.changed = true;
+    }
+
+    pub fn remove(&self, key: &str) -> Option<String> {
+        let mut state = self.lock();
+        let value = state.data.remove(key);
+
+        state.changed |= value.is_some();
+        value
+    }
+
+    pub fn contains_key(&self, key: &str) -> bool {
+        self.lock().data.contains_key(key)
+    }
+
+    pub fn clear(&self) {
+        let mut state = self.lock();
+
+        state.data.clear();
+        state.changed = true;
+    }
+
+    // Removes the session from the store and tells the client to forget the session cookie
+    pub fn destroy(&self) {
+        let mut state = self.lock();
+
+        state.data.clear();
+        state.destroyed = true;
+    }
+
+    // Moves the data to a new id when saving, e.g. after logging in, to prevent session fixation
+    pub fn regenerate(&self) {
+        let mut state = self.lock();
+
+        state.regenerate = true;
+        state.changed = true;
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, SessionState> {
+        // A handler that panicked can not leave the map in an inconsistent state, so ignore poisoning
+        self.state.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct SessionConfig {
+    pub cookie_name: String,
+    pub cookie_path: String,
+    pub secure: bool,
+    pub same_site: SameSite,
+    // How long a session lives after the last request that used it
+    pub ttl: Duration,
+    // How often the store is asked to remove expired sessions
+    pub sweep_interval: Duration,
+}
+
+impl Default for SessionConfig {
+    fn default() -> Self {
+        SessionConfig {
+            cookie_name: "session_id".to_owned(),
+            cookie_path: "/".to_owned(),
+            secure: false,
+            same_site: SameSite::Lax,
+            ttl: Duration::from_secs(24 * 60 * 60),
+            sweep_interval: Duration::from_secs(60),
+        }
+    }
+}
+
+// Loads the session of every request and saves it after the handler ran.
+// The session id travels in a signed cookie, so clients can not forge ids.
+#[derive(Clone)]
+pub struct SessionManager {
+    pub store: Arc<dyn SessionStore>,
+    pub config: SessionConfig,
+}
+
+impl SessionManager {
+    pub fn new(store: Arc<dyn SessionStore>, config: SessionConfig) -> SessionManager {
+        SessionManager { store, config }
+    }
+
+    pub fn load(&self, request: &Request) -> Session {
+        match self.session_id(request) {
+            Some(id) => self.load_id(&id),
+            None => Session::new(),
+        }
+    }
+
+    // The id in the session cookie of the request, if its signature is valid
+    pub fn session_id(&self, request: &Request) -> Option<String> {
+        let cookie = request.signed_cookies().get(&self.config.cookie_name).cloned()?;
+        Some(cookie.value)
+    }
+
+    // Loads the session with the id from the store, or starts a new one if it is not there.
+    // Blocks for as long as the store does.
+    pub fn load_id(&self, id: &str) -> Session {
+        match self.store.load(id) {
+            Ok(Some(data)) => Session::existing(id, data),
+            Ok(None) => Session::new(),
+            Err(e) => {
+                println!("Error(SessionManager::load): {}", e);
+                Session::new()
+            }
+        }
+    }
+
+    // Blocks for as long as the store does
+    pub fn save(&self, session: &Session, response: &mut Response, keys: &CookieKeys) {
+        let mut state = session.lock();
+
+        if state.destroyed {
+            if let Some(id) = state.id.take() {
+                if let Err(e) = self.store.destroy(&id) {
+                    println!("Error(SessionManager::save): {}", e);
+                }
+
+                response.add_cookie(Cookie {
+                    path: Some(self.config.cookie_path.clone()),
+                    ..Cookie::removal(&self.config.cookie_name)
+                });
+            }
+
+            return;
+        }
+
+        // New sessions that were never written to are not stored, nor is a cookie sent for them
+        if state.id.is_none() && (!state.changed || state.data.is_empty()) {
+            return;
+        }
+
+        let id = match state.id.clone() {
+            // Using a session keeps it alive. Unchanged sessions only get a new expiry, so that
+            // requests that just read them don't overwrite what another request saved meanwhile.
+            Some(id) if !state.changed => {
+                if let Err(e) = self.store.touch(&id, self.config.ttl) {
+                    println!("Error(SessionManager::save): {}", e);
+                    return;
+                }
+
+                id
+            }
+            _ => {
+                if state.regenerate {
+                    if let Some(old_id) = state.id.take() {
+                        if let Err(e) = self.store.destroy(&old_id) {
+                            println!("Error(SessionManager::save): {}", e);
+                        }
+                    }
+                }
+
+                let id = state.id.get_or_insert_with(generate_session_id).clone();
+
+                if let Err(e) = self.store.save(&id, &state.data, self.config.ttl) {
+                    println!("Error(SessionManager::save): {}", e);
+                    return;
+                }
+
+                state.changed = false;
+                state.regenerate = false;
+
+                id
+            }
+        };
+
+        let cookie = Cookie {
+            path: Some(self.config.cookie_path.clone()),
+            max_age: Some(self.config.ttl.as_secs() as i64),
+            secure: self.config.secure,
+            http_only: true,
+            same_site: Some(self.config.same_site),
+            ..Cookie::new(&self.config.cookie_name, &id)
+        };
+
+        response.add_signed_cookie(cookie, keys);
+    }
+}
+
+fn generate_session_id() -> String {
+    let mut bytes = [0u8; 32];
+    OsRng.fill_bytes(&mut bytes);
+
+    URL_SAFE_NO_PAD.encode(bytes)
+}
+
+fn unix_seconds(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH).map_or(0, |duration| duration.as_secs())
+}
+
+// Keeps the sessions in memory. They are lost when the process exits.
+#[derive(Default)]
+pub struct MemoryStore {
+    sessions: Mutex<HashMap<String, (SessionData, SystemTime)>>,
+}
+
+impl MemoryStore {
+    pub fn new() -> MemoryStore {
+        MemoryStore::default()
+    }
+}
+
+impl SessionStore for MemoryStore {
+    fn load(&self, id: &str) -> Result<Option<SessionData>, Error> {
+        let sessions = self.sessions.lock().unwrap_or_else(|e| e.into_inner());
+
+        match sessions.get(id) {
+            Some((data, expires)) if *expires > SystemTime::now() => Ok(Some(data.clone())),
+            _ => Ok(None),
+        }
+    }
+
+    fn save(&self, id: &str, data: &SessionData, ttl: Duration) -> Result<(), Error> {
+        let mut sessions = self.sessions.lock().unwrap_or_else(|e| e.into_inner());
+        sessions.insert(id.to_owned(), (data.clone(), SystemTime::now() + ttl));
+
+        Ok(())
+    }
+
+    fn destroy(&self, id: &str) -> Result<(), Error> {
+        let mut sessions = self.sessions.lock().unwrap_or_else(|e| e.into_inner());
+        sessions.remove(id);
+
+        Ok(())
+    }
+
+    fn touch(&self, id: &str, ttl: Duration) -> Result<(), Error> {
+        let now = SystemTime::now();
+        let mut sessions = self.sessions.lock().unwrap_or_else(|e| e.into_inner());
+
+        if let Some((_, expires)) = sessions.get_mut(id).filter(|(_, expires)| *expires > now) {
+            *expires = now + ttl;
+        }
+
+        Ok(())
+    }
+
+    fn sweep(&self) -> Result<(), Error> {
+        let now = SystemTime::now();
+        let mut sessions = self.sessions.lock().unwrap_or_else(|e| e.into_inner());
+        sessions.retain(|_, (_, expires)| *expires > now);
+
+        Ok(())
+    }
+}
+
+// Keeps every session in its own file inside a directory. The first line of a file is the
+// expiry time in seconds since the Unix epoch, followed by one percent-encoded key=value per line.
+pub struct FileStore {
+    dir: PathBuf,
+}
+
+impl FileStore {
+    pub fn new(dir: impl Into<PathBuf>) -> Result<FileStore, Error> {
+        let dir = dir.into();
+        fs::create_dir_all(&dir)?;
+
+        Ok(FileStore { dir })
+    }
+
+    fn path(&self, id: &str) -> Result<PathBuf, Error> {
+        // Ids are generated by the server, but never let one escape the directory
+        if id.is_empty() || !id.bytes().all(|b| b.is_ascii_alphanumeric() || b == b'-' || b == b'_') {
+            return Err(Error::new(ErrorKind::InvalidInput, "invalid session id"));
+        }
+
+        Ok(self.dir.join(format!("{}.session", id)))
+    }
+
+    fn read(path: &PathBuf) -> Result<Option<(SessionData, u64)>, Error> {
+        let contents = match fs::read_to_string(path) {
+            Ok(contents) => contents,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e),
+        };
+
+        let mut lines = contents.lines();
+        let Some(Ok(expires)) = lines.next().map(|line| line.parse::<u64>()) else {
+            return Err(Error::new(ErrorKind::InvalidData, "missing session expiry"));
+        };
+
+        let mut data = SessionData::new();
+
+        for line in lines {
+            let decoded = line
+                .split_once('=')
+                .and_then(|(key, value)| Some((percent_decode(key)?, percent_decode(value)?)));
+
+            let Some((key, value)) = decoded else {
+                return Err(Error::new(ErrorKind::InvalidData, "malformed session entry"));
+            };
+
+            data.insert(key, value);
+        }
+
+        Ok(Some((data, expires)))
+    }
+}
+
+impl SessionStore for FileStore {
+    fn load(&self, id: &str) -> Result<Option<SessionData>, Error> {
+        match FileStore::read(&self.path(id)?)? {
+            Some((data, expires)) if expires > unix_seconds(SystemTime::now()) => Ok(Some(data)),
+            _ => Ok(None),
+        }
+    }
+
+    fn save(&self, id: &str, data: &SessionData, ttl: Duration) -> Result<(), Error> {
+        let mut contents = format!("{}\n", unix_seconds(SystemTime::now() + ttl));
+
+        for (key, value) in data {
+            contents.push_str(&format!("{}={}\n", percent_encode(key), percent_encode(value)));
+        }
+
+        // Write to a temporary file first so that readers never see a half-written session. Each save
+        // gets a file of its own, so that concurrent saves of a session don't write to the same one.
+        let path = self.path(id)?;
+        let mut temp_file = NamedTempFile::new_in(&self.dir)?;
+        temp_file.write_all(contents.as_bytes())?;
+        temp_file.persist(path)?;
+
+        Ok(())
+    }
+
+    fn destroy(&self, id: &str) -> Result<(), Error> {
+        match fs::remove_file(self.path(id)?) {
+            Err(e) if e.kind() != ErrorKind::NotFound => Err(e),
+            _ => Ok(()),
+        }
+    }
+
+    fn sweep(&self) -> Result<(), Error> {
+        let now = unix_seconds(SystemTime::now());
+
+        for entry in fs::read_dir(&self.dir)? {
+            let path = entry?.path();
+
+            if path.extension().is_none_or(|extension| extension != "session") {
+                continue;
+            }
+
+            // Unreadable files are removed as well, they can never be loaded
+            match FileStore::read(&path) {
+                Ok(Some((_, expires))) if expires > now => {}
+                _ => {
+                    let _ = fs::remove_file(&path);
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{cookie_keys::CookieKey, method::HTTPMethod, search_params::SearchParams, status_codes::StatusCodes};
+
+    const HOUR: Duration = Duration::from_secs(60 * 60);
+
+    fn data(entries: &[(&str, &str)]) -> SessionData {
+        entries.iter().map(|(key, value)| (key.to_string(), value.to_string())).collect()
+    }
+
+    fn keys() -> CookieKeys {
+        CookieKeys::new(CookieKey::from(&[7; 32]).unwrap())
+    }
+
+    fn manager(store: Arc<dyn SessionStore>) -> SessionManager {
+        let config = SessionConfig {
+            ttl: HOUR,
+            ..SessionConfig::default()
+        };
+
+        SessionManager::new(store, config)
+    }
+
+    fn request(cookie: Option<&str>) -> Request {
+        let mut headers = HashMap::new();
+        if let Some(cookie) = cookie {
+            headers.insert("cookie".to_owned(), cookie.to_owned());
+        }
+
+        let mut request = Request::new(HTTPMethod::GET, "/".to_owned(), headers, Vec::new(), SearchParams::from("").unwrap());
+        request.cookie_keys = Arc::new(keys());
+        request
+    }
+
+    // Saves the session and returns the cookies of the response
+    fn save(manager: &SessionManager, session: &Session) -> Vec<Cookie> {
+        let mut response = Response::new(StatusCodes::OK, HashMap::new(), "");
+        manager.save(session, &mut response, &keys());
+        response.cookies().to_vec()
+    }
+
+    // The session id in a cookie sent by save
+    fn cookie_id(cookie: &Cookie) -> String {
+        keys().verify(cookie).unwrap().value
+    }
+
+    fn memory_expiry(store: &MemoryStore, id: &str) -> SystemTime {
+        store.sessions.lock().unwrap()[id].1
+    }
+
+    #[test]
+    fn memory_store_saves_and_expires_sessions() {
+        let store = MemoryStore::new();
+
+        store.save("a", &data(&[("user", "42")]), HOUR).unwrap();
+        store.save("expired", &data(&[("user", "7")]), Duration::ZERO).unwrap();
+
+        assert_eq!(store.load("a").unwrap(), Some(data(&[("user", "42")])));
+        assert_eq!(store.load("expired").unwrap(), None);
+        assert_eq!(store.load("missing").unwrap(), None);
+
+        // Expired sessions can not be brought back by using them
+        store.touch("expired", HOUR).unwrap();
+        assert_eq!(store.load("expired").unwrap(), None);
+
+        store.sweep().unwrap();
+        assert_eq!(store.sessions.lock().unwrap().len(), 1);
+
+        store.destroy("a").unwrap();
+        assert_eq!(store.load("a").unwrap(), None);
+    }
+
+    #[test]
+    fn memory_store_touch_only_moves_the_expiry() {
+        let store = MemoryStore::new();
+        store.save("a", &data(&[("user", "42")]), Duration::from_secs(1)).unwrap();
+
+        store.touch("a", HOUR).unwrap();
+
+        assert!(memory_expiry(&store, "a") > SystemTime::now() + HOUR / 2);
+        assert_eq!(store.load("a").unwrap(), Some(data(&[("user", "42")])));
+    }
+
+    #[test]
+    fn file_store_saves_and_expires_sessions() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let store = FileStore::new(dir.path().join("sessions")).unwrap();
+        let stored = data(&[("name", "a=b\nc%d"), ("key with spaces", ""), ("emoji", "ü🦀")]);
+
+        store.save("a-1_B", &stored, HOUR).unwrap();
+        assert_eq!(store.load("a-1_B").unwrap(), Some(stored.clone()));
+
+        // Values are percent-encoded, so every entry stays on a line of its own
+        let contents = fs::read_to_string(dir.path().join("sessions/a-1_B.session")).unwrap();
+        assert_eq!(contents.lines().count(), 4);
+
+        store.save("a-1_B", &data(&[("user", "42")]), Duration::ZERO).unwrap();
+        assert_eq!(store.load("a-1_B").unwrap(), None);
+
+        store.destroy("a-1_B").unwrap();
+        store.destroy("a-1_B").unwrap();
+        assert_eq!(store.load("a-1_B").unwrap(), None);
+    }
+
+    #[test]
+    fn file_store_touch_keeps_the_data() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let store = FileStore::new(dir.path()).unwrap();
+        store.save("a", &data(&[("user", "42")]), Duration::from_secs(1)).unwrap();
+
+        store.touch("a", HOUR).unwrap();
+
+        let (data, expires) = FileStore::read(&dir.path().join("a.session")).unwrap().unwrap();
+        assert_eq!(data, self::data(&[("user", "42")]));
+        assert!(expires > unix_seconds(SystemTime::now() + HOUR / 2));
+    }
+
+    #[test]
+    fn file_store_rejects_ids_outside_its_directory() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let store = FileStore::new(dir.path().join("sessions")).unwrap();
+
+        for id in ["../x", "a/b", "..", "a.session", ""] {
+            assert_eq!(store.load(id).unwrap_err().kind(), ErrorKind::InvalidInput, "{}", id);
+            assert_eq!(store.save(id, &SessionData::new(), HOUR).unwrap_err().kind(), ErrorKind::InvalidInput);
+            assert_eq!(store.destroy(id).unwrap_err().kind(), ErrorKind::InvalidInput);
+        }
+
+        assert!(!dir.path().join("x.session").exists());
+    }
+
+    #[test]
+    fn file_store_sweeps_expired_and_corrupt_sessions() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let store = FileStore::new(dir.path()).unwrap();
+
+        store.save("valid", &data(&[("user", "42")]), HOUR).unwrap();
+        store.save("expired", &data(&[("user", "7")]), Duration::ZERO).unwrap();
+        fs::write(dir.path().join("no_expiry.session"), "user=42\n").unwrap();
+        fs::write(dir.path().join("bad_entry.session"), "99999999999\nuser\n").unwrap();
+        fs::write(dir.path().join("notes.txt"), "not a session").unwrap();
+
+        assert_eq!(store.load("no_expiry").unwrap_err().kind(), ErrorKind::InvalidData);
+
+        store.sweep().unwrap();
+
+        let mut left: Vec<_> = fs::read_dir(dir.path())
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+            .collect();
+        left.sort();
+
+        assert_eq!(left, ["notes.txt", "valid.session"]);
+    }
+
+    #[test]
+    fn loads_the_session_of_the_signed_cookie() {
+        let store = Arc::new(MemoryStore::new());
+        let manager = manager(store.clone());
+        store.save("abc", &data(&[("user", "42")]), HOUR).unwrap();
+
+        let signed = keys().sign(Cookie::new("session_id", "abc")).value;
+        let session = manager.load(&request(Some(&format!("session_id={}", signed))));
+        assert_eq!(session.id().as_deref(), Some("abc"));
+        assert_eq!(session.get::<u32>("user"), Some(42));
+
+        // Unsigned ids are not trusted
+        assert_eq!(manager.load(&request(Some("session_id=abc"))).id(), None);
+        assert_eq!(manager.load(&request(None)).id(), None);
+    }
+
+    #[test]
+    fn saves_new_sessions_once_written_to() {
+        let store = Arc::new(MemoryStore::new());
+        let manager = manager(store.clone());
+
+        assert!(save(&manager, &Session::new()).is_empty());
+
+        let session = Session::new();
+        session.insert("user", 42);
+        let cookies = save(&manager, &session);
+
+        assert_eq!(cookies.len(), 1);
+        assert_eq!(cookies[0].max_age, Some(HOUR.as_secs() as i64));
+        assert!(cookies[0].http_only);
+
+        let id = cookie_id(&cookies[0]);
+        assert_eq!(session.id(), Some(id.clone()));
+        assert_eq!(store.load(&id).unwrap(), Some(data(&[("user", "42")])));
+    }
+
+    #[test]
+    fn refreshes_sessions_that_are_only_read() {
+        let store = Arc::new(MemoryStore::new());
+        let manager = manager(store.clone());
+        store.save("abc", &data(&[("user", "42")]), Duration::from_secs(1)).unwrap();
+
+        let session = manager.load_id("abc");
+        assert_eq!(session.get::<u32>("user"), Some(42));
+
+        // Another request changed the session in the meantime, which touching must not undo
+        store.save("abc", &data(&[("user", "43")]), Duration::from_secs(1)).unwrap();
+
+        let cookies = save(&manager, &session);
+        assert_eq!(cookie_id(&cookies[0]), "abc");
+        assert_eq!(cookies[0].max_age, Some(HOUR.as_secs() as i64));
+
+        assert!(memory_expiry(&store, "abc") > SystemTime::now() + HOUR / 2);
+        assert_eq!(store.load("abc").unwrap(), Some(data(&[("user", "43")])));
+    }
+
+    #[test]
+    fn destroys_sessions() {
+        let store = Arc::new(MemoryStore::new());
+        let manager = manager(store.clone());
+        store.save("abc", &data(&[("user", "42")]), HOUR).unwrap();
+
+        let session = manager.load_id("abc");
+        session.destroy();
+        let cookies = save(&manager, &session);
+
+        assert_eq!(cookies.len(), 1);
+        assert_eq!(cookies[0].name, "session_id");
+        assert_eq!(cookies[0].max_age, Some(0));
+        assert_eq!(store.load("abc").unwrap(), None);
+
+        // A session the client never had needs no removal cookie
+        let session = Session::new();
+        session.destroy();
+        assert!(save(&manager, &session).is_empty());
+    }
+
+    #[test]
+    fn regenerates_session_ids() {
+        let store = Arc::new(MemoryStore::new());
+        let manager = manager(store.clone());
+        store.save("abc", &data(&[("user", "42")]), HOUR).unwrap();
+
+        let session = manager.load_id("abc");
+        session.regenerate();
+        let cookies = save(&manager, &session);
+
+        let id = cookie_id(&cookies[0]);
+        assert_ne!(id, "abc");
+        assert_eq!(store.load("abc").unwrap(), None);
+        assert_eq!(store.load(&id).unwrap(), Some(data(&[("user", "42")])));
+    }
+}
@@ -0,0 +1,165 @@
+use std::{pin::Pin, sync::Mutex, time::Duration};
+
+use async_std::{
+    future::timeout,
+    stream::{Stream, StreamExt},
+};
+
+// Sent when no event came for a while, so that clients and proxies keep the connection open
+const KEEP_ALIVE: &str = ": keep-alive\n\n";
+
+// An event of a Server-Sent Events stream, sent in the text/event-stream format
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Event {
+    event: Option<String>,
+    data: String,
+    id: Option<String>,
+    retry: Option<Duration>,
+}
+
+impl Event {
+    pub fn new(data: &str) -> Event {
+        Event {
+            data: data.to_owned(),
+            ..Event::default()
+        }
+    }
+
+    // The type clients listen for with addEventListener, "message" when not set
+    pub fn event(mut self, event: &str) -> Event {
+        self.event = Some(event.to_owned());
+        self
+    }
+
+    // Sent back by clients in the Last-Event-ID header when they reconnect
+    pub fn id(mut self, id: &str) -> Event {
+        self.id = Some(id.to_owned());
+        self
+    }
+
+    // How long clients wait before reconnecting once the connection is lost
+    pub fn retry(mut self, retry: Duration) -> Event {
+        self.retry = Some(retry);
+        self
+    }
+
+    // Every line of the data goes in its own data field. Line breaks would end the other
+    // fields early, so they are left out.
+    fn encode(&self) -> String {
+        let field = |value: &str| value.replace(['\r', '\n', '\0'], "");
+        let mut encoded = String::new();
+
+        if let Some(event) = &self.event {
+            encoded.push_str(&format!("event: {}\n", field(event)));
+        }
+
+        if let Some(id) = &self.id {
+            encoded.push_str(&format!("id: {}\n", field(id)));
+        }
+
+        if let Some(retry) = self.retry {
+            encoded.push_str(&format!("retry: {}\n", retry.as_millis()));
+        }
+
+        for line in self.data.replace("\r\n", "\n").split(['\n', '\r']) {
+            encoded.push_str(&format!("data: {}\n", line));
+        }
+
+        encoded.push('\n');
+        encoded
+    }
+}
+
+// The body of a response made with Response::sse, sent by the server as the events come
+pub struct EventStream {
+    // Only used through &mut self, the mutex lets responses be shared between threads
+    // without the stream having to be Sync
+    events: Mutex<Pin<Box<dyn Stream<Item = Event> + Send>>>,
+}
+
+impl EventStream {
+    pub fn new(events: impl Stream<Item = Event> + Send + 'static) -> EventStream {
+        EventStream {
+            events: Mutex::new(Box::pin(events)),
+        }
+    }
+
+    // Waits for the next event and returns it encoded, or returns a comment when none came
+    // within the keep-alive interval. None once the stream ended.
+    pub async fn next_chunk(&mut self, keep_alive: Duration) -> Option<String> {
+        let events = self.events.get_mut().unwrap_or_else(|poisoned| poisoned.into_inner());
+
+        match timeout(keep_alive, events.next()).await {
+            Ok(event) => event.map(|event| event.encode()),
+            Err(_) => Some(KEEP_ALIVE.to_owned()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::task::{Context, Poll};
+
+    use async_std::{stream, task};
+
+    use super::*;
+
+    // A stream that never has an event
+    struct Silent;
+
+    impl Stream for Silent {
+        type Item = Event;
+
+        fn poll_next(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<Option<Event>> {
+            Poll::Pending
+        }
+    }
+
+    #[test]
+    fn encodes_data() {
+        assert_eq!(Event::new("hello").encode(), "data: hello\n\n");
+        assert_eq!(Event::new("").encode(), "data: \n\n");
+    }
+
+    #[test]
+    fn splits_data_into_lines() {
+        assert_eq!(Event::new("a\nb\r\nc\rd").encode(), "data: a\ndata: b\ndata: c\ndata: d\n\n");
+
+        // Empty lines are kept, so that clients put the same data back together
+        assert_eq!(Event::new("a\n\nb\n").encode(), "data: a\ndata: \ndata: b\ndata: \n\n");
+    }
+
+    #[test]
+    fn encodes_every_field() {
+        let event = Event::new("{\"id\": 1}")
+            .event("update")
+            .id("42")
+            .retry(Duration::from_secs(3));
+
+        assert_eq!(event.encode(), "event: update\nid: 42\nretry: 3000\ndata: {\"id\": 1}\n\n");
+        assert_eq!(Event::new("x").retry(Duration::from_micros(1500)).encode(), "retry: 1\ndata: x\n\n");
+    }
+
+    #[test]
+    fn strips_line_breaks_from_fields() {
+        let event = Event::new("x").event("up\r\ndata: injected\n").id("4\r2\0");
+
+        assert_eq!(event.encode(), "event: updata: injected\nid: 42\ndata: x\n\n");
+    }
+
+    #[test]
+    fn sends_events_and_keep_alives() {
+        task::block_on(async {
+            let mut events = EventStream::new(stream::from_iter([Event::new("a"), Event::new("b").id("2")]));
+
+            assert_eq!(events.next_chunk(Duration::from_secs(5)).await.as_deref(), Some("data: a\n\n"));
+            assert_eq!(events.next_chunk(Duration::from_secs(5)).await.as_deref(), Some("id: 2\ndata: b\n\n"));
+            assert_eq!(events.next_chunk(Duration::from_secs(5)).await, None);
+
+            // A comment line, which clients ignore
+            let mut events = EventStream::new(Silent);
+            assert_eq!(events.next_chunk(Duration::from_millis(10)).await.as_deref(), Some(": keep-alive\n\n"));
+            assert_eq!(events.next_chunk(Duration::from_millis(10)).await.as_deref(), Some(KEEP_ALIVE));
+        });
+    }
+}
@@ -39,6 +39,8 @@ pub enum StatusCodes {
     UnsupportedMediaType,
     RequestRangeNotSatisfiable,
     ExpectationFailed,
+    UpgradeRequired,
+    RequestHeaderFieldsTooLarge,
     // Server Error 5xx
     InternalServerError,
     NotImplemented,
@@ -86,6 +88,8 @@ impl StatusCodes {
             StatusCodes::UnsupportedMediaType => 415,
             StatusCodes::RequestRangeNotSatisfiable => 416,
             StatusCodes::ExpectationFailed => 417,
+            StatusCodes::UpgradeRequired => 426,
+            StatusCodes::RequestHeaderFieldsTooLarge => 431,
             StatusCodes::InternalServerError => 500,
             StatusCodes::NotImplemented => 501,
             StatusCodes::BadGateway => 502,
@@ -132,6 +136,8 @@ impl StatusCodes {
             StatusCodes::UnsupportedMediaType => "Unsupported Media Type",
             StatusCodes::RequestRangeNotSatisfiable => "Requested Range Not Satisfiable",
             StatusCodes::ExpectationFailed => "Expectation Failed",
+            StatusCodes::UpgradeRequired => "Upgrade Required",
+            StatusCodes::RequestHeaderFieldsTooLarge => "Request Header Fields Too Large",
             StatusCodes::InternalServerError => "Internal Server Error",
             StatusCodes::NotImplemented => "Not Implemented",
             StatusCodes::BadGateway => "Bad Gateway",
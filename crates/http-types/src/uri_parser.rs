@@ -1,5 +1,7 @@
 use std::{collections::HashMap, str::Split};
 
+use crate::percent_encoding::percent_decode;
+
 #[derive(Debug, Clone)]
 pub struct UriParser {
     pub path: String,
@@ -15,7 +17,13 @@ impl UriParser {
         let mut idx = 0;
         for value in path.split('/') {
             match self.dynamic_params.get(&idx) {
-                Some(key) => vec.push((key.to_owned(), value.to_owned())),
+                Some(key) => {
+                    let Some(decoded_value) = percent_decode(value) else {
+                        return Err(format!("Invalid percent-encoding in path segment: {}", value));
+                    };
+
+                    vec.push((key.to_owned(), decoded_value))
+                }
                 None => {}
             }
 
@@ -63,7 +71,7 @@ impl UriParser {
             // If it doesn't, check if the param from the uri matches the one from the UriParser
             let parser_value = parser_split_path_vec[idx];
 
-            if parser_value != value {
+            if percent_decode(value).as_deref() != Some(parser_value) {
                 // If it doesn't, return false
                 return false;
             }
@@ -0,0 +1,26 @@
+#[derive(Debug, Hash, Eq, PartialEq, Clone, Copy, Default)]
+pub enum HTTPVersion {
+    HTTP1_0,
+    #[default]
+    HTTP1_1,
+    // Never part of a request line, requests made over HTTP/2 connections are given it by the server
+    HTTP2,
+}
+
+impl HTTPVersion {
+    pub fn from(version_string: &str) -> Option<HTTPVersion> {
+        match version_string {
+            "HTTP/1.0" => Some(HTTPVersion::HTTP1_0),
+            "HTTP/1.1" => Some(HTTPVersion::HTTP1_1),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> &str {
+        match self {
+            HTTPVersion::HTTP1_0 => "HTTP/1.0",
+            HTTPVersion::HTTP1_1 => "HTTP/1.1",
+            HTTPVersion::HTTP2 => "HTTP/2",
+        }
+    }
+}
@@ -0,0 +1,1086 @@
+use std::{
+    borrow::Cow,
+    future::{self, Future},
+    io::{Error, ErrorKind},
+    pin::Pin,
+    time::Duration,
+};
+
+use async_std::io::{self as async_io, Read, ReadExt, Write, WriteExt};
+use base64::{engine::general_purpose::STANDARD, Engine};
+use flate2::{Compress, Compression, Decompress, FlushCompress, FlushDecompress, Status};
+use sha1::{Digest, Sha1};
+
+use crate::{method::HTTPMethod, request::Request, status_codes::StatusCodes, version::HTTPVersion};
+
+// Appended to the client's key before hashing it, RFC 6455, section 1.3
+const HANDSHAKE_GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+
+const OPCODE_CONTINUATION: u8 = 0x0;
+const OPCODE_TEXT: u8 = 0x1;
+const OPCODE_BINARY: u8 = 0x2;
+const OPCODE_CLOSE: u8 = 0x8;
+const OPCODE_PING: u8 = 0x9;
+const OPCODE_PONG: u8 = 0xA;
+
+// Messages sent by the server are split into frames of at most this size
+const FRAME_SIZE: usize = 16 * 1024;
+
+// How much is read from the connection at once
+const READ_CHUNK_SIZE: usize = 8 * 1024;
+
+// Control frames carry at most 125 bytes, 2 of which are the status code of a close frame
+const MAX_CONTROL_PAYLOAD: usize = 125;
+const MAX_CLOSE_REASON: usize = MAX_CONTROL_PAYLOAD - 2;
+
+// The empty block a sync flush ends with, left out of compressed messages, RFC 7692, section 7.2.1
+const DEFLATE_TRAILER: [u8; 4] = [0x00, 0x00, 0xff, 0xff];
+
+// Runs a WebSocket once the handshake completed, generated by the #[websocket] macro
+pub type WebSocketHandler = for<'a> fn(Request, &'a mut WebSocket) -> Pin<Box<dyn Future<Output = ()> + Send + 'a>>;
+
+// The connections a WebSocket can run on
+pub trait WebSocketStream: Read + Write + Unpin + Send {}
+
+impl<T: Read + Write + Unpin + Send> WebSocketStream for T {}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Message {
+    Text(String),
+    Binary(Vec<u8>),
+    Ping(Vec<u8>),
+    Pong(Vec<u8>),
+    // None when the close frame has no status code
+    Close(Option<CloseFrame>),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CloseFrame {
+    pub code: u16,
+    pub reason: String,
+}
+
+impl CloseFrame {
+    // Status codes, RFC 6455, section 7.4.1
+    pub const NORMAL: u16 = 1000;
+    pub const GOING_AWAY: u16 = 1001;
+    pub const PROTOCOL_ERROR: u16 = 1002;
+    pub const UNSUPPORTED_DATA: u16 = 1003;
+    pub const INVALID_DATA: u16 = 1007;
+    pub const POLICY_VIOLATION: u16 = 1008;
+    pub const MESSAGE_TOO_BIG: u16 = 1009;
+    pub const INTERNAL_ERROR: u16 = 1011;
+
+    // Codes that may be sent in a close frame. The others are reserved, or only used
+    // locally to report a connection that closed without one.
+    fn is_valid_code(code: u16) -> bool {
+        matches!(code, 1000..=1003 | 1007..=1014 | 3000..=4999)
+    }
+}
+
+// The parameters of the permessage-deflate extension agreed on during the handshake, RFC 7692
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct PerMessageDeflate {
+    // Whether the server, or the client, compresses every message on its own instead of
+    // referring back to the previous ones
+    pub server_no_context_takeover: bool,
+    pub client_no_context_takeover: bool,
+}
+
+impl PerMessageDeflate {
+    // Accepts the first offer of a sec-websocket-extensions header that the server supports
+    pub fn negotiate(offers: &str) -> Option<PerMessageDeflate> {
+        offers.split(',').find_map(PerMessageDeflate::accept)
+    }
+
+    fn accept(offer: &str) -> Option<PerMessageDeflate> {
+        let mut params = offer.split(';').map(str::trim);
+
+        if !params.next()?.eq_ignore_ascii_case("permessage-deflate") {
+            return None;
+        }
+
+        let mut deflate = PerMessageDeflate::default();
+        let mut names = Vec::new();
+
+        for param in params {
+            let (name, value) = match param.split_once('=') {
+                Some((name, value)) => (name.trim(), Some(value.trim().trim_matches('"'))),
+                None => (param, None),
+            };
+
+            let name = name.to_ascii_lowercase();
+
+            // Offers with a parameter given twice are declined, RFC 7692, section 7
+            if names.contains(&name) {
+                return None;
+            }
+
+            match (name.as_str(), value) {
+                ("server_no_context_takeover", None) => deflate.server_no_context_takeover = true,
+                ("client_no_context_takeover", None) => deflate.client_no_context_takeover = true,
+                // The server always compresses with a 32KB window, so it can't agree to a smaller one
+                ("server_max_window_bits", Some("15")) => {}
+                // Windows the client uses are fine, as decompressing handles them all
+                ("client_max_window_bits", None) => {}
+                ("client_max_window_bits", Some(bits)) if matches!(bits.parse::<u8>(), Ok(8..=15)) => {}
+                _ => return None,
+            }
+
+            names.push(name);
+        }
+
+        Some(deflate)
+    }
+
+    // The sec-websocket-extensions header of the response
+    pub fn to_header_value(&self) -> String {
+        let mut value = "permessage-deflate".to_owned();
+
+        if self.server_no_context_takeover {
+            value.push_str("; server_no_context_takeover");
+        }
+
+        if self.client_no_context_takeover {
+            value.push_str("; client_no_context_takeover");
+        }
+
+        value
+    }
+}
+
+// Compression state of a connection, kept from one message to the next unless a side
+// agreed not to
+struct Deflate {
+    params: PerMessageDeflate,
+    compress: Compress,
+    decompress: Decompress,
+}
+
+impl Deflate {
+    fn new(params: PerMessageDeflate) -> Deflate {
+        Deflate {
+            params,
+            compress: Compress::new(Compression::default(), false),
+            decompress: Decompress::new(false),
+        }
+    }
+
+    fn compress(&mut self, data: &[u8]) -> Result<Vec<u8>, Error> {
+        let start = self.compress.total_in();
+        let mut output = Vec::with_capacity(data.len() / 2 + 64);
+
+        // Done once all of the data went in and the flush left room in the output
+        loop {
+            if output.len() == output.capacity() {
+                output.reserve(output.capacity());
+            }
+
+            let consumed = (self.compress.total_in() - start) as usize;
+            self.compress.compress_vec(&data[consumed..], &mut output, FlushCompress::Sync).map_err(Error::other)?;
+
+            if (self.compress.total_in() - start) as usize == data.len() && output.len() < output.capacity() {
+                break;
+            }
+        }
+
+        if output.ends_with(&DEFLATE_TRAILER) {
+            output.truncate(output.len() - DEFLATE_TRAILER.len());
+        }
+
+        if self.params.server_no_context_takeover {
+            self.compress.reset();
+        }
+
+        Ok(output)
+    }
+
+    // Fails with the status code to close the connection with
+    fn decompress(&mut self, data: &[u8], limit: usize) -> Result<Vec<u8>, u16> {
+        let mut input = Vec::with_capacity(data.len() + DEFLATE_TRAILER.len());
+        input.extend_from_slice(data);
+        input.extend_from_slice(&DEFLATE_TRAILER);
+
+        let start = self.decompress.total_in();
+        let mut output = Vec::with_capacity((data.len() * 2).min(limit) + 64);
+
+        loop {
+            if output.len() == output.capacity() {
+                output.reserve(output.capacity());
+            }
+
+            let consumed = (self.decompress.total_in() - start) as usize;
+            let produced = output.len();
+
+            let status = self
+                .decompress
+                .decompress_vec(&input[consumed..], &mut output, FlushDecompress::Sync)
+                .map_err(|_| CloseFrame::INVALID_DATA)?;
+
+            // Checked as the message grows, so that small messages can't inflate without bound
+            if output.len() > limit {
+                return Err(CloseFrame::MESSAGE_TOO_BIG);
+            }
+
+            // A message ending the deflate stream leaves nothing for the next ones to refer to
+            if status == Status::StreamEnd {
+                self.decompress.reset(false);
+                return Ok(output);
+            }
+
+            let done = (self.decompress.total_in() - start) as usize == input.len();
+            let stalled = (self.decompress.total_in() - start) as usize == consumed && output.len() == produced;
+
+            if (done || stalled) && output.len() < output.capacity() {
+                break;
+            }
+        }
+
+        if self.params.client_no_context_takeover {
+            self.decompress.reset(false);
+        }
+
+        Ok(output)
+    }
+}
+
+struct Frame {
+    fin: bool,
+    // Set on the first frame of compressed messages
+    rsv1: bool,
+    opcode: u8,
+    payload: Vec<u8>,
+}
+
+enum Failure {
+    // The client broke the protocol, the connection is closed with this status code
+    Protocol(u16),
+    // The connection failed or was closed without a close frame
+    Io,
+}
+
+impl From<Error> for Failure {
+    fn from(_: Error) -> Failure {
+        Failure::Io
+    }
+}
+
+// A WebSocket connection, RFC 6455, handed to #[websocket] handlers after the handshake.
+// Pings are answered on their own, and the close handshake is completed by the server when
+// the handler returns without closing.
+pub struct WebSocket {
+    stream: Box<dyn WebSocketStream>,
+    deflate: Option<Deflate>,
+    max_message_size: usize,
+    write_timeout: Duration,
+    // Received bytes that do not make up a whole frame yet
+    read_buffer: Vec<u8>,
+    // Frames waiting to be written. Kept here so that a cancelled send or recv never leaves
+    // half a frame on the connection.
+    write_buffer: Vec<u8>,
+    // The opcode and compression of the fragmented message being received, and its data so far
+    fragmented: Option<(u8, bool)>,
+    fragments: Vec<u8>,
+    close_sent: bool,
+    close_received: bool,
+    closed: bool,
+}
+
+impl WebSocket {
+    // Takes over a connection once the 101 response was sent
+    pub fn new(
+        stream: Box<dyn WebSocketStream>,
+        deflate: Option<PerMessageDeflate>,
+        max_message_size: usize,
+        write_timeout: Duration,
+    ) -> WebSocket {
+        WebSocket {
+            stream,
+            deflate: deflate.map(Deflate::new),
+            max_message_size,
+            write_timeout,
+            read_buffer: Vec::new(),
+            write_buffer: Vec::new(),
+            fragmented: None,
+            fragments: Vec::new(),
+            close_sent: false,
+            close_received: false,
+            closed: false,
+        }
+    }
+
+    // Waits for the next message, with fragmented messages put back together. Once the client
+    // closes the connection its close frame is returned, and None from then on.
+    // Cancel safe, so it can be raced against other futures, e.g. a channel of messages to send.
+    pub async fn recv(&mut self) -> Option<Message> {
+        if self.close_received {
+            return None;
+        }
+
+        match self.read_message().await {
+            Ok(message) => Some(message),
+            Err(Failure::Protocol(code)) => {
+                self.close_received = true;
+
+                if !self.close_sent {
+                    self.close_sent = true;
+                    self.queue_close(code, "");
+                    let _ = self.flush().await;
+                }
+
+                None
+            }
+            Err(Failure::Io) => {
+                self.close_sent = true;
+                self.close_received = true;
+                None
+            }
+        }
+    }
+
+    // Text and binary messages are compressed when permessage-deflate was negotiated, and split
+    // into frames of at most 16KB. Sending a close frame closes the WebSocket like close does.
+    pub async fn send(&mut self, message: Message) -> Result<(), Error> {
+        if self.close_sent {
+            return Err(Error::new(ErrorKind::NotConnected, "the WebSocket is closed"));
+        }
+
+        let result = match message {
+            Message::Text(text) => self.send_data(OPCODE_TEXT, text.as_bytes()).await,
+            Message::Binary(data) => self.send_data(OPCODE_BINARY, &data).await,
+            Message::Ping(payload) => self.send_control(OPCODE_PING, &payload).await,
+            Message::Pong(payload) => self.send_control(OPCODE_PONG, &payload).await,
+            Message::Close(frame) => {
+                let frame = frame.unwrap_or(CloseFrame { code: CloseFrame::NORMAL, reason: String::new() });
+                self.close(frame.code, &frame.reason).await;
+                return Ok(());
+            }
+        };
+
+        // Nothing can be sent after a failed write, as part of a frame may be missing
+        if let Err(error) = &result {
+            if error.kind() != ErrorKind::InvalidInput {
+                self.close_sent = true;
+                self.close_received = true;
+            }
+        }
+
+        result
+    }
+
+    // Sends a close frame, unless the client already closed, then waits for the client's
+    // close frame and shuts the connection down. Reasons longer than 123 bytes are cut.
+    pub async fn close(&mut self, code: u16, reason: &str) {
+        if self.closed {
+            return;
+        }
+
+        if !self.close_sent {
+            self.close_sent = true;
+            self.queue_close(code, reason);
+        }
+
+        let write_timeout = self.write_timeout;
+
+        // Messages still on their way are dropped
+        let handshake = async {
+            self.flush().await?;
+
+            while !self.close_received {
+                if self.read_message().await.is_err() {
+                    break;
+                }
+            }
+
+            Ok(())
+        };
+
+        let _ = async_io::timeout(write_timeout, handshake).await;
+
+        self.close_received = true;
+        self.closed = true;
+
+        let stream = &mut self.stream;
+        let _ = async_io::timeout(write_timeout, future::poll_fn(|cx| Pin::new(&mut *stream).poll_close(cx))).await;
+    }
+
+    async fn read_message(&mut self) -> Result<Message, Failure> {
+        loop {
+            let Some(frame) = self.parse_frame()? else {
+                self.fill().await?;
+                continue;
+            };
+
+            match frame.opcode {
+                OPCODE_PING => {
+                    if !self.close_sent {
+                        self.queue_frame(OPCODE_PONG, true, false, &frame.payload);
+                        self.flush().await?;
+                    }
+
+                    return Ok(Message::Ping(frame.payload));
+                }
+                OPCODE_PONG => return Ok(Message::Pong(frame.payload)),
+                OPCODE_CLOSE => {
+                    let close = WebSocket::parse_close(&frame.payload)?;
+                    self.close_received = true;
+
+                    // Answered with the same status code, RFC 6455, section 5.5.1
+                    if !self.close_sent {
+                        self.close_sent = true;
+
+                        match &close {
+                            Some(close) => self.queue_close(close.code, ""),
+                            None => self.queue_frame(OPCODE_CLOSE, true, false, &[]),
+                        }
+
+                        self.flush().await?;
+                    }
+
+                    return Ok(Message::Close(close));
+                }
+                _ => {
+                    if let Some(message) = self.assemble(frame)? {
+                        return Ok(message);
+                    }
+                }
+            }
+        }
+    }
+
+    // Adds a data frame to the message being received, and returns the message once complete
+    fn assemble(&mut self, frame: Frame) -> Result<Option<Message>, Failure> {
+        let (opcode, compressed) = match (frame.opcode, self.fragmented) {
+            (OPCODE_CONTINUATION, Some(fragmented)) if !frame.rsv1 => fragmented,
+            (OPCODE_TEXT | OPCODE_BINARY, None) if !frame.rsv1 || self.deflate.is_some() => (frame.opcode, frame.rsv1),
+            _ => return Err(Failure::Protocol(CloseFrame::PROTOCOL_ERROR)),
+        };
+
+        self.fragments.extend_from_slice(&frame.payload);
+
+        if !frame.fin {
+            self.fragmented = Some((opcode, compressed));
+            return Ok(None);
+        }
+
+        self.fragmented = None;
+        let mut data = std::mem::take(&mut self.fragments);
+
+        if let (true, Some(deflate)) = (compressed, &mut self.deflate) {
+            data = deflate.decompress(&data, self.max_message_size).map_err(Failure::Protocol)?;
+        }
+
+        match opcode {
+            OPCODE_TEXT => match String::from_utf8(data) {
+                Ok(text) => Ok(Some(Message::Text(text))),
+                Err(_) => Err(Failure::Protocol(CloseFrame::INVALID_DATA)),
+            },
+            _ => Ok(Some(Message::Binary(data))),
+        }
+    }
+
+    // Takes the next frame out of the read buffer, None until all of it was received
+    fn parse_frame(&mut self) -> Result<Option<Frame>, Failure> {
+        let buffer = &self.read_buffer;
+
+        if buffer.len() < 2 {
+            return Ok(None);
+        }
+
+        let fin = buffer[0] & 0x80 != 0;
+        let rsv1 = buffer[0] & 0x40 != 0;
+        let opcode = buffer[0] & 0x0f;
+        let control = opcode & 0x08 != 0;
+
+        // RSV2 and RSV3 belong to extensions that are never negotiated, and clients must mask every frame
+        if buffer[0] & 0x30 != 0 || buffer[1] & 0x80 == 0 {
+            return Err(Failure::Protocol(CloseFrame::PROTOCOL_ERROR));
+        }
+
+        if !matches!(opcode, OPCODE_CONTINUATION | OPCODE_TEXT | OPCODE_BINARY | OPCODE_CLOSE | OPCODE_PING | OPCODE_PONG) {
+            return Err(Failure::Protocol(CloseFrame::PROTOCOL_ERROR));
+        }
+
+        let (length, mask_offset) = match buffer[1] & 0x7f {
+            126 if buffer.len() < 4 => return Ok(None),
+            126 => (u16::from_be_bytes([buffer[2], buffer[3]]) as u64, 4),
+            127 if buffer.len() < 10 => return Ok(None),
+            127 => (u64::from_be_bytes(buffer[2..10].try_into().unwrap()), 10),
+            length => (length as u64, 2),
+        };
+
+        // Control frames are small and never fragmented, RFC 6455, section 5.5
+        if control && (!fin || rsv1 || length > MAX_CONTROL_PAYLOAD as u64) {
+            return Err(Failure::Protocol(CloseFrame::PROTOCOL_ERROR));
+        }
+
+        // Checked before the payload arrives, so that oversized messages are never buffered
+        if !control && self.fragments.len() as u64 + length > self.max_message_size as u64 {
+            return Err(Failure::Protocol(CloseFrame::MESSAGE_TOO_BIG));
+        }
+
+        let length = length as usize;
+        let payload_offset = mask_offset + 4;
+
+        if buffer.len() < payload_offset + length {
+            return Ok(None);
+        }
+
+        let mask = [
+            buffer[mask_offset],
+            buffer[mask_offset + 1],
+            buffer[mask_offset + 2],
+            buffer[mask_offset + 3],
+        ];
+
+        let mut payload = buffer[payload_offset..payload_offset + length].to_vec();
+
+        for (index, byte) in payload.iter_mut().enumerate() {
+            *byte ^= mask[index % 4];
+        }
+
+        self.read_buffer.drain(..payload_offset + length);
+
+        Ok(Some(Frame {
+            fin,
+            rsv1,
+            opcode,
+            payload,
+        }))
+    }
+
+    fn parse_close(payload: &[u8]) -> Result<Option<CloseFrame>, Failure> {
+        if payload.is_empty() {
+            return Ok(None);
+        }
+
+        if payload.len() < 2 {
+            return Err(Failure::Protocol(CloseFrame::PROTOCOL_ERROR));
+        }
+
+        let code = u16::from_be_bytes([payload[0], payload[1]]);
+
+        if !CloseFrame::is_valid_code(code) {
+            return Err(Failure::Protocol(CloseFrame::PROTOCOL_ERROR));
+        }
+
+        match String::from_utf8(payload[2..].to_vec()) {
+            Ok(reason) => Ok(Some(CloseFrame { code, reason })),
+            Err(_) => Err(Failure::Protocol(CloseFrame::INVALID_DATA)),
+        }
+    }
+
+    async fn fill(&mut self) -> Result<(), Failure> {
+        let mut chunk = [0; READ_CHUNK_SIZE];
+        let read = self.stream.read(&mut chunk).await?;
+
+        if read == 0 {
+            return Err(Failure::Io);
+        }
+
+        self.read_buffer.extend_from_slice(&chunk[..read]);
+        Ok(())
+    }
+
+    async fn send_data(&mut self, opcode: u8, data: &[u8]) -> Result<(), Error> {
+        let (payload, compressed) = match &mut self.deflate {
+            Some(deflate) => (Cow::Owned(deflate.compress(data)?), true),
+            None => (Cow::Borrowed(data), false),
+        };
+
+        // Only the first frame is marked as compressed, RFC 7692, section 6
+        let mut remaining: &[u8] = &payload;
+        let mut opcode = opcode;
+        let mut rsv1 = compressed;
+
+        loop {
+            let (frame, rest) = remaining.split_at(remaining.len().min(FRAME_SIZE));
+            self.queue_frame(opcode, rest.is_empty(), rsv1, frame);
+
+            if rest.is_empty() {
+                break;
+            }
+
+            remaining = rest;
+            opcode = OPCODE_CONTINUATION;
+            rsv1 = false;
+        }
+
+        self.flush().await
+    }
+
+    async fn send_control(&mut self, opcode: u8, payload: &[u8]) -> Result<(), Error> {
+        if payload.len() > MAX_CONTROL_PAYLOAD {
+            return Err(Error::new(ErrorKind::InvalidInput, "control frames carry at most 125 bytes"));
+        }
+
+        self.queue_frame(opcode, true, false, payload);
+        self.flush().await
+    }
+
+    fn queue_close(&mut self, code: u16, reason: &str) {
+        let mut end = reason.len().min(MAX_CLOSE_REASON);
+
+        while !reason.is_char_boundary(end) {
+            end -= 1;
+        }
+
+        let mut payload = code.to_be_bytes().to_vec();
+        payload.extend_from_slice(&reason.as_bytes()[..end]);
+
+        self.queue_frame(OPCODE_CLOSE, true, false, &payload);
+    }
+
+    // Frames sent by the server are not masked
+    fn queue_frame(&mut self, opcode: u8, fin: bool, rsv1: bool, payload: &[u8]) {
+        let mut first = opcode;
+
+        if fin {
+            first |= 0x80;
+        }
+
+        if rsv1 {
+            first |= 0x40;
+        }
+
+        self.write_buffer.push(first);
+
+        match payload.len() {
+            length @ 0..=125 => self.write_buffer.push(length as u8),
+            length @ 126..=0xffff => {
+                self.write_buffer.push(126);
+                self.write_buffer.extend_from_slice(&(length as u16).to_be_bytes());
+            }
+            length => {
+                self.write_buffer.push(127);
+                self.write_buffer.extend_from_slice(&(length as u64).to_be_bytes());
+            }
+        }
+
+        self.write_buffer.extend_from_slice(payload);
+    }
+
+    // Writes the queued frames, giving up on clients that do not read them in time
+    async fn flush(&mut self) -> Result<(), Error> {
+        let stream = &mut self.stream;
+        let buffer = &mut self.write_buffer;
+
+        async_io::timeout(self.write_timeout, async {
+            while !buffer.is_empty() {
+                let written = stream.write(buffer).await?;
+
+                if written == 0 {
+                    return Err(Error::from(ErrorKind::WriteZero));
+                }
+
+                buffer.drain(..written);
+            }
+
+            stream.flush().await
+        })
+        .await
+    }
+}
+
+// Checks the opening handshake of a request, RFC 6455, section 4.2.1, and returns the
+// sec-websocket-accept header for it
+pub(crate) fn accept_key(request: &Request) -> Result<String, StatusCodes> {
+    let header = |name: &str| request.headers.get(name).map(String::as_str).unwrap_or("");
+    let has_token = |name: &str, token: &str| header(name).split(',').any(|value| value.trim().eq_ignore_ascii_case(token));
+
+    if request.method != HTTPMethod::GET
+        || request.version() != HTTPVersion::HTTP1_1
+        || !has_token("upgrade", "websocket")
+        || !has_token("connection", "upgrade")
+    {
+        return Err(StatusCodes::BadRequest);
+    }
+
+    if header("sec-websocket-version").trim() != "13" {
+        return Err(StatusCodes::UpgradeRequired);
+    }
+
+    let key = header("sec-websocket-key").trim();
+
+    if !STANDARD.decode(key).is_ok_and(|nonce| nonce.len() == 16) {
+        return Err(StatusCodes::BadRequest);
+    }
+
+    let mut hash = Sha1::new();
+    hash.update(key.as_bytes());
+    hash.update(HANDSHAKE_GUID.as_bytes());
+
+    Ok(STANDARD.encode(hash.finalize()))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        collections::HashMap,
+        io,
+        sync::{Arc, Mutex},
+        task::{Context, Poll},
+    };
+
+    use async_std::task;
+
+    use super::*;
+    use crate::search_params::SearchParams;
+
+    // A connection whose client already sent everything in input, and which keeps what the
+    // server writes. Reading past the input is the client closing the connection.
+    struct MemoryStream {
+        input: Vec<u8>,
+        position: usize,
+        output: Arc<Mutex<Vec<u8>>>,
+    }
+
+    impl Read for MemoryStream {
+        fn poll_read(mut self: Pin<&mut Self>, _: &mut Context<'_>, buf: &mut [u8]) -> Poll<io::Result<usize>> {
+            let read = buf.len().min(self.input.len() - self.position);
+            buf[..read].copy_from_slice(&self.input[self.position..self.position + read]);
+            self.position += read;
+
+            Poll::Ready(Ok(read))
+        }
+    }
+
+    impl Write for MemoryStream {
+        fn poll_write(self: Pin<&mut Self>, _: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+            self.output.lock().unwrap().extend_from_slice(buf);
+            Poll::Ready(Ok(buf.len()))
+        }
+
+        fn poll_flush(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<io::Result<()>> {
+            Poll::Ready(Ok(()))
+        }
+
+        fn poll_close(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<io::Result<()>> {
+            Poll::Ready(Ok(()))
+        }
+    }
+
+    fn socket(input: Vec<u8>, deflate: Option<PerMessageDeflate>) -> (WebSocket, Arc<Mutex<Vec<u8>>>) {
+        let output = Arc::new(Mutex::new(Vec::new()));
+        let stream = MemoryStream {
+            input,
+            position: 0,
+            output: output.clone(),
+        };
+
+        let socket = WebSocket::new(Box::new(stream), deflate, 1024 * 1024, Duration::from_secs(1));
+        (socket, output)
+    }
+
+    // Receives every message until the connection closes
+    fn recv_all(input: Vec<u8>, deflate: Option<PerMessageDeflate>) -> (Vec<Message>, Vec<u8>) {
+        let (mut socket, output) = socket(input, deflate);
+        let mut messages = Vec::new();
+
+        task::block_on(async {
+            while let Some(message) = socket.recv().await {
+                messages.push(message);
+            }
+        });
+
+        let output = output.lock().unwrap().clone();
+        (messages, output)
+    }
+
+    // A frame as clients send it, masked with a fixed key
+    fn client_frame(first: u8, payload: &[u8]) -> Vec<u8> {
+        let mask = [0x37, 0xfa, 0x21, 0x3d];
+        let mut frame = vec![first];
+
+        match payload.len() {
+            length @ 0..=125 => frame.push(0x80 | length as u8),
+            length @ 126..=0xffff => {
+                frame.push(0x80 | 126);
+                frame.extend_from_slice(&(length as u16).to_be_bytes());
+            }
+            length => {
+                frame.push(0x80 | 127);
+                frame.extend_from_slice(&(length as u64).to_be_bytes());
+            }
+        }
+
+        frame.extend_from_slice(&mask);
+        frame.extend(payload.iter().enumerate().map(|(index, byte)| byte ^ mask[index % 4]));
+        frame
+    }
+
+    fn close_frame(code: u16) -> Vec<u8> {
+        let mut frame = vec![0x88, 0x02];
+        frame.extend_from_slice(&code.to_be_bytes());
+        frame
+    }
+
+    fn handshake_request(headers: &[(&str, &str)]) -> Request {
+        let mut all = HashMap::from([
+            ("upgrade".to_owned(), "websocket".to_owned()),
+            ("connection".to_owned(), "keep-alive, Upgrade".to_owned()),
+            ("sec-websocket-version".to_owned(), "13".to_owned()),
+            ("sec-websocket-key".to_owned(), "dGhlIHNhbXBsZSBub25jZQ==".to_owned()),
+        ]);
+
+        for (name, value) in headers {
+            all.insert(name.to_string(), value.to_string());
+        }
+
+        Request::new(HTTPMethod::GET, "/chat".to_owned(), all, Vec::new(), SearchParams::from("").unwrap())
+    }
+
+    #[test]
+    fn computes_the_accept_key() {
+        // The sample key and answer of RFC 6455, section 1.3
+        assert_eq!(accept_key(&handshake_request(&[])).unwrap(), "s3pPLMBiTxaQ9kYGzzhZRbK+xOo=");
+    }
+
+    #[test]
+    fn rejects_invalid_handshakes() {
+        let status = |request: Request| accept_key(&request).unwrap_err().as_code();
+
+        assert_eq!(status(handshake_request(&[("sec-websocket-version", "8")])), 426);
+        assert_eq!(status(handshake_request(&[("sec-websocket-key", "c2hvcnQ=")])), 400);
+        assert_eq!(status(handshake_request(&[("upgrade", "h2c")])), 400);
+        assert_eq!(status(handshake_request(&[("connection", "close")])), 400);
+
+        let mut request = handshake_request(&[]);
+        request.method = HTTPMethod::POST;
+        assert_eq!(status(request), 400);
+
+        let mut request = handshake_request(&[]);
+        request.set_version(HTTPVersion::HTTP1_0);
+        assert_eq!(status(request), 400);
+    }
+
+    #[test]
+    fn receives_masked_frames() {
+        // The masked "Hello" of RFC 6455, section 5.7
+        let input = vec![0x81, 0x85, 0x37, 0xfa, 0x21, 0x3d, 0x7f, 0x9f, 0x4d, 0x51, 0x58];
+        let (messages, _) = recv_all(input, None);
+
+        assert_eq!(messages, [Message::Text("Hello".to_owned())]);
+
+        let input = client_frame(0x82, &[0; 300]);
+        let (messages, _) = recv_all(input, None);
+
+        assert_eq!(messages, [Message::Binary(vec![0; 300])]);
+    }
+
+    #[test]
+    fn rejects_unmasked_frames() {
+        // The unmasked "Hello" of RFC 6455, section 5.7, which only servers may send
+        let (messages, output) = recv_all(vec![0x81, 0x05, b'H', b'e', b'l', b'l', b'o'], None);
+
+        assert!(messages.is_empty());
+        assert_eq!(output, close_frame(CloseFrame::PROTOCOL_ERROR));
+    }
+
+    #[test]
+    fn sends_unmasked_frames() {
+        let (mut socket, output) = socket(Vec::new(), None);
+
+        task::block_on(async {
+            socket.send(Message::Text("Hello".to_owned())).await.unwrap();
+            socket.send(Message::Ping(b"Hello".to_vec())).await.unwrap();
+        });
+
+        // Both as in RFC 6455, section 5.7
+        let expected = [[0x81, 0x05].as_slice(), b"Hello", &[0x89, 0x05], b"Hello"].concat();
+        assert_eq!(*output.lock().unwrap(), expected);
+    }
+
+    #[test]
+    fn splits_large_messages_into_frames() {
+        let (mut socket, output) = socket(Vec::new(), None);
+        task::block_on(socket.send(Message::Binary(vec![7; FRAME_SIZE + 10]))).unwrap();
+
+        let output = output.lock().unwrap();
+        let second = 4 + FRAME_SIZE;
+
+        // A binary frame without fin and a 16 bit length, then a final continuation frame
+        assert_eq!(output[..4], [0x02, 126, 0x40, 0x00]);
+        assert_eq!(output[second..second + 2], [0x80, 10]);
+        assert_eq!(output.len(), second + 2 + 10);
+    }
+
+    #[test]
+    fn reassembles_fragmented_messages() {
+        // "Hel" and "lo", with a ping between the fragments
+        let input = [client_frame(0x01, b"Hel"), client_frame(0x89, b"ping"), client_frame(0x80, b"lo")].concat();
+        let (messages, output) = recv_all(input, None);
+
+        assert_eq!(messages, [Message::Ping(b"ping".to_vec()), Message::Text("Hello".to_owned())]);
+        assert_eq!(output, [[0x8a, 0x04].as_slice(), b"ping"].concat());
+    }
+
+    #[test]
+    fn rejects_unexpected_continuation_frames() {
+        // A continuation without a message to continue, and a new message in the middle of another one
+        for input in [
+            client_frame(0x80, b"lo"),
+            [client_frame(0x01, b"Hel"), client_frame(0x81, b"lo")].concat(),
+        ] {
+            let (messages, output) = recv_all(input, None);
+
+            assert!(messages.is_empty());
+            assert_eq!(output, close_frame(CloseFrame::PROTOCOL_ERROR));
+        }
+    }
+
+    #[test]
+    fn enforces_control_frame_rules() {
+        // Over 125 bytes, fragmented, and compressed
+        for input in [
+            client_frame(0x89, &[0; 126]),
+            client_frame(0x09, b"ping"),
+            client_frame(0xc9, b"ping"),
+        ] {
+            let (messages, output) = recv_all(input, None);
+
+            assert!(messages.is_empty());
+            assert_eq!(output, close_frame(CloseFrame::PROTOCOL_ERROR));
+        }
+
+        let (mut socket, _) = socket(Vec::new(), None);
+        let error = task::block_on(socket.send(Message::Ping(vec![0; 126]))).unwrap_err();
+        assert_eq!(error.kind(), ErrorKind::InvalidInput);
+    }
+
+    #[test]
+    fn rejects_invalid_data() {
+        let (_, output) = recv_all(client_frame(0x81, &[0xff, 0xfe]), None);
+        assert_eq!(output, close_frame(CloseFrame::INVALID_DATA));
+
+        // Reserved bits and opcodes that were not negotiated
+        let (_, output) = recv_all(client_frame(0xc1, b"Hello"), None);
+        assert_eq!(output, close_frame(CloseFrame::PROTOCOL_ERROR));
+
+        let (_, output) = recv_all(client_frame(0x83, b"Hello"), None);
+        assert_eq!(output, close_frame(CloseFrame::PROTOCOL_ERROR));
+    }
+
+    #[test]
+    fn enforces_the_message_size() {
+        let (mut socket, output) = socket(client_frame(0x82, &[0; 2000]), None);
+        socket.max_message_size = 1000;
+
+        assert_eq!(task::block_on(socket.recv()), None);
+        assert_eq!(*output.lock().unwrap(), close_frame(CloseFrame::MESSAGE_TOO_BIG));
+    }
+
+    #[test]
+    fn completes_the_close_handshake() {
+        let payload = [&CloseFrame::GOING_AWAY.to_be_bytes()[..], b"bye"].concat();
+        let input = [client_frame(0x88, &payload), client_frame(0x81, b"ignored")].concat();
+        let (messages, output) = recv_all(input, None);
+
+        // Echoed with the same code, and nothing is received after it
+        let close = CloseFrame { code: CloseFrame::GOING_AWAY, reason: "bye".to_owned() };
+        assert_eq!(messages, [Message::Close(Some(close))]);
+        assert_eq!(output, close_frame(CloseFrame::GOING_AWAY));
+
+        let (messages, output) = recv_all(client_frame(0x88, &[]), None);
+        assert_eq!(messages, [Message::Close(None)]);
+        assert_eq!(output, [0x88, 0x00]);
+
+        // 1005 is only used locally, and a lone byte is not a status code
+        for payload in [&1005u16.to_be_bytes()[..], &[0x03]] {
+            let (messages, output) = recv_all(client_frame(0x88, payload), None);
+
+            assert!(messages.is_empty());
+            assert_eq!(output, close_frame(CloseFrame::PROTOCOL_ERROR));
+        }
+    }
+
+    #[test]
+    fn closes_with_a_close_frame() {
+        let (mut socket, output) = socket(client_frame(0x88, &CloseFrame::NORMAL.to_be_bytes()), None);
+        let reason = "é".repeat(100);
+
+        task::block_on(async {
+            socket.close(CloseFrame::NORMAL, &reason).await;
+            assert!(socket.send(Message::Text("late".to_owned())).await.is_err());
+            assert_eq!(socket.recv().await, None);
+        });
+
+        // The reason is cut to 123 bytes without splitting a character
+        let output = output.lock().unwrap();
+        assert_eq!(output[..4], [0x88, 124, 0x03, 0xe8]);
+        assert_eq!(output[4..], reason.as_bytes()[..122]);
+    }
+
+    #[test]
+    fn negotiates_permessage_deflate() {
+        let deflate = PerMessageDeflate::negotiate("permessage-deflate; client_max_window_bits").unwrap();
+        assert_eq!(deflate, PerMessageDeflate::default());
+        assert_eq!(deflate.to_header_value(), "permessage-deflate");
+
+        // The first offer asks for a smaller window than the server uses, so the second one is taken
+        let offers = "permessage-deflate; server_max_window_bits=10, permessage-deflate; server_no_context_takeover; client_no_context_takeover";
+        let deflate = PerMessageDeflate::negotiate(offers).unwrap();
+        assert!(deflate.server_no_context_takeover && deflate.client_no_context_takeover);
+        assert_eq!(
+            deflate.to_header_value(),
+            "permessage-deflate; server_no_context_takeover; client_no_context_takeover"
+        );
+
+        for offers in [
+            "x-webkit-deflate-frame",
+            "permessage-deflate; server_no_context_takeover; server_no_context_takeover",
+            "permessage-deflate; client_max_window_bits=16",
+            "permessage-deflate; unknown",
+        ] {
+            assert_eq!(PerMessageDeflate::negotiate(offers), None, "{}", offers);
+        }
+    }
+
+    #[test]
+    fn round_trips_compressed_messages() {
+        for params in [PerMessageDeflate::default(), PerMessageDeflate { server_no_context_takeover: true, client_no_context_takeover: true }] {
+            let mut server = Deflate::new(params);
+            let mut client = Deflate::new(params);
+
+            // The second message refers back to the first one, unless no context takeover was agreed on
+            for message in [b"Hello, Hello, Hello".as_slice(), b"Hello, Hello, Hello", &[0; 100_000]] {
+                let compressed = server.compress(message).unwrap();
+
+                assert!(!compressed.ends_with(&DEFLATE_TRAILER));
+                assert_eq!(client.decompress(&compressed, 1024 * 1024).unwrap(), message);
+            }
+        }
+    }
+
+    #[test]
+    fn receives_and_sends_compressed_messages() {
+        // The compressed "Hello" of RFC 7692, section 7.2.3.1
+        let compressed = [0xf2, 0x48, 0xcd, 0xc9, 0xc9, 0x07, 0x00];
+        let input = [client_frame(0x41, &compressed[..3]), client_frame(0x80, &compressed[3..])].concat();
+        let (messages, _) = recv_all(input, Some(PerMessageDeflate::default()));
+
+        assert_eq!(messages, [Message::Text("Hello".to_owned())]);
+
+        let (messages, _) = recv_all(client_frame(0xc1, &compressed), Some(PerMessageDeflate::default()));
+        assert_eq!(messages, [Message::Text("Hello".to_owned())]);
+
+        let (mut socket, output) = socket(Vec::new(), Some(PerMessageDeflate::default()));
+        task::block_on(socket.send(Message::Text("Hello".to_owned()))).unwrap();
+
+        let output = output.lock().unwrap();
+        assert_eq!(output[..2], [0xc1, output.len() as u8 - 2]);
+
+        let mut client = Deflate::new(PerMessageDeflate::default());
+        assert_eq!(client.decompress(&output[2..], 1024).unwrap(), b"Hello");
+    }
+
+    #[test]
+    fn limits_decompressed_messages() {
+        let mut server = Deflate::new(PerMessageDeflate::default());
+        let compressed = server.compress(&[0; 100_000]).unwrap();
+
+        let mut client = Deflate::new(PerMessageDeflate::default());
+        assert_eq!(client.decompress(&compressed, 1000), Err(CloseFrame::MESSAGE_TOO_BIG));
+        assert_eq!(Deflate::new(PerMessageDeflate::default()).decompress(&[0xff; 8], 1000), Err(CloseFrame::INVALID_DATA));
+    }
+}
@@ -24,7 +24,91 @@ pub fn route(args: TokenStream, input: TokenStream) -> TokenStream {
     let method_name = syn::LitStr::new(&method.as_str(), fn_name.span());
     let path = syn::LitStr::new(&arguments.path, fn_name.span());
 
-    let uri_parser = UriParser::from(&arguments.path);
+    bind_path_params(&mut input_fn, &arguments.path);
+
+    TokenStream::from(quote!(
+        #input_fn
+
+        #[allow(non_camel_case_types)]
+        struct #fn_name {}
+
+        impl #fn_name {
+            pub fn route() -> Route {
+                Route {
+                    handler: #fn_name,
+                    method: HTTPMethod::from(#method_name).unwrap(),
+                    uri_parser: UriParser::from(#path)
+                }
+            }
+        }
+    ))
+}
+
+// Turns an async function taking the Request and a &mut WebSocket into a GET route that
+// accepts the WebSocket handshake, and then runs the function on the connection
+#[cfg(feature = "websocket")]
+#[proc_macro_attribute]
+pub fn websocket(args: TokenStream, input: TokenStream) -> TokenStream {
+    let Ok(mut input_fn) = syn::parse::<ItemFn>(input) else {
+        panic!("Could not parse function");
+    };
+
+    let path = parse_macro_input!(args as syn::LitStr);
+
+    if input_fn.sig.asyncness.is_none() {
+        panic!("expected an async function");
+    }
+
+    let fn_name = input_fn.sig.ident.clone();
+
+    bind_path_params(&mut input_fn, &path.value());
+
+    TokenStream::from(quote!(
+        #input_fn
+
+        #[allow(non_camel_case_types)]
+        struct #fn_name {}
+
+        impl #fn_name {
+            pub fn run<'a>(
+                request: Request,
+                socket: &'a mut WebSocket,
+            ) -> std::pin::Pin<Box<dyn std::future::Future<Output = ()> + Send + 'a>> {
+                Box::pin(#fn_name(request, socket))
+            }
+
+            fn handshake(request: &Request) -> Response {
+                Response::websocket(request, #fn_name::run)
+            }
+
+            pub fn route() -> Route {
+                Route {
+                    handler: #fn_name::handshake,
+                    method: HTTPMethod::GET,
+                    uri_parser: UriParser::from(#path)
+                }
+            }
+        }
+    ))
+}
+
+// Without the feature WebSocket, Response::websocket and the rest do not exist, so the
+// generated code would fail with errors that do not point at the cause
+#[cfg(not(feature = "websocket"))]
+#[proc_macro_attribute]
+pub fn websocket(_args: TokenStream, _input: TokenStream) -> TokenStream {
+    TokenStream::from(quote!(
+        compile_error!("#[websocket] requires the websocket feature of rust-http-framework");
+    ))
+}
+
+// Declares a String for every dynamic param of the path at the start of the function,
+// parsed from the uri of its request argument
+fn bind_path_params(input_fn: &mut ItemFn, path: &str) {
+    let fn_name = input_fn.sig.ident.clone();
+    let path_lit = syn::LitStr::new(path, fn_name.span());
+
+    let uri_parser = UriParser::from(path);
     let dynamic_params = uri_parser.dynamic_params.iter().map(|x| x.1);
 
     let mut dynamic_params_vec = dynamic_params.clone().collect::<Vec<&String>>();
@@ -55,7 +139,7 @@ pub fn route(args: TokenStream, input: TokenStream) -> TokenStream {
     input_fn.block.stmts.insert(
         0,
         parse_quote!(
-            let __parser = UriParser::from(#path);
+            let __parser = UriParser::from(#path_lit);
         ),
     );
 
@@ -92,21 +176,4 @@ pub fn route(args: TokenStream, input: TokenStream) -> TokenStream {
            std::mem::drop(__params);
         ),
     );
-
-    TokenStream::from(quote!(
-        #input_fn
-
-        #[allow(non_camel_case_types)]
-        struct #fn_name {}
-
-        impl #fn_name {
-            pub fn route() -> Route {
-                Route {
-                    handler: #fn_name,
-                    method: HTTPMethod::from(#method_name).unwrap(),
-                    uri_parser: UriParser::from(#path)
-                }
-            }
-        }
-    ))
 }
@@ -33,7 +33,11 @@ fn create_account(req: &Request) -> Response {
         }
     }
 
-    let Ok(parsed_body) = json::parse(&req.body) else {
+    let Ok(body) = req.text() else {
+        return Response::new(StatusCodes::BadRequest, HashMap::new(), "");
+    };
+
+    let Ok(parsed_body) = json::parse(body) else {
         return Response::new(StatusCodes::BadRequest, HashMap::new(), "");
     };
 
@@ -63,5 +67,7 @@ fn main() {
     server.add_routes(generate_routes![get_root, get_user, create_account]);
 
     // Initialize the server
-    server.start();
+    if let Err(e) = server.start() {
+        println!("Could not start the server: {}", e);
+    }
 }
@@ -0,0 +1,135 @@
+use std::collections::HashMap;
+
+use async_std::task;
+use http_types::{MultipartParser, Part, StatusCodes};
+
+use crate::ServerConfig;
+
+// How much of a multipart body is collected before the parser runs on it. The parser may write
+// to temporary files, so it runs on the blocking thread pool instead of once per read.
+const MULTIPART_BATCH_SIZE: usize = 64 * 1024;
+
+// Where the body of a request goes while it is read from the connection. Bodies are buffered
+// for the handlers, except multipart/form-data bodies, which are parsed as they arrive so that
+// file uploads never have to fit in memory at once.
+pub(crate) enum BodySink {
+    Buffer {
+        body: Vec<u8>,
+        limit: usize,
+    },
+    Multipart {
+        // Taken while it runs on the blocking thread pool
+        parser: Option<Box<MultipartParser>>,
+        pending: Vec<u8>,
+        parts: Vec<Part>,
+        limit: usize,
+    },
+}
+
+impl BodySink {
+    // Fails when the request is multipart/form-data without a usable boundary
+    pub(crate) fn new(
+        headers: &HashMap<String, String>,
+        has_body: bool,
+        config: &ServerConfig,
+    ) -> Result<BodySink, StatusCodes> {
+        let content_type = headers.get("content-type").map_or("", String::as_str);
+        let media_type = content_type.split(';').next().unwrap_or("").trim();
+
+        if !has_body || !media_type.eq_ignore_ascii_case("multipart/form-data") {
+            return Ok(BodySink::Buffer {
+                body: Vec::new(),
+                limit: config.max_body_size,
+            });
+        }
+
+        let limits = config.multipart_limits.clone();
+        let limit = limits.max_total_size;
+
+        Ok(BodySink::Multipart {
+            parser: Some(Box::new(MultipartParser::from_content_type(content_type, limits)?)),
+            pending: Vec::new(),
+            parts: Vec::new(),
+            limit,
+        })
+    }
+
+    // Largest body the sink takes, checked against the content-length before reading
+    pub(crate) fn limit(&self) -> usize {
+        match self {
+            BodySink::Buffer { limit, .. } | BodySink::Multipart { limit, .. } => *limit,
+        }
+    }
+
+    // Makes room for a body of known length, which is only kept in memory when it is buffered
+    pub(crate) fn reserve(&mut self, length: usize) {
+        if let BodySink::Buffer { body, .. } = self {
+            body.reserve(length);
+        }
+    }
+
+    pub(crate) async fn write(&mut self, chunk: &[u8]) -> Result<(), StatusCodes> {
+        match self {
+            BodySink::Buffer { body, limit } => {
+                if body.len() + chunk.len() > *limit {
+                    println!("Error(decode_request): body exceeds {} bytes", limit);
+                    return Err(StatusCodes::RequestEntityTooLarge);
+                }
+
+                body.extend_from_slice(chunk);
+            }
+            BodySink::Multipart { pending, .. } => {
+                pending.extend_from_slice(chunk);
+
+                if pending.len() >= MULTIPART_BATCH_SIZE {
+                    self.flush().await?;
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    // Returns the buffered body, or the parts of a multipart body once it ended properly
+    pub(crate) async fn finish(mut self) -> Result<(Vec<u8>, Option<Vec<Part>>), StatusCodes> {
+        self.flush().await?;
+
+        match self {
+            BodySink::Buffer { body, .. } => Ok((body, None)),
+            BodySink::Multipart { parser, parts, .. } => {
+                if let Some(parser) = parser {
+                    parser.finish()?;
+                }
+
+                Ok((Vec::new(), Some(parts)))
+            }
+        }
+    }
+
+    // Runs the parser on the data collected so far
+    async fn flush(&mut self) -> Result<(), StatusCodes> {
+        let BodySink::Multipart { parser, pending, parts, .. } = self else {
+            return Ok(());
+        };
+
+        if pending.is_empty() {
+            return Ok(());
+        }
+
+        let Some(mut taken) = parser.take() else {
+            return Ok(());
+        };
+
+        let data = std::mem::take(pending);
+        let (taken, completed) = task::spawn_blocking(move || {
+            let completed = taken.feed(&data);
+            (taken, completed)
+        })
+        .await;
+
+        *parser = Some(taken);
+        parts.extend(completed?);
+
+        Ok(())
+    }
+}
@@ -0,0 +1,919 @@
+use std::{
+    collections::HashMap,
+    fs,
+    io::{Error, ErrorKind},
+    net::{SocketAddr, ToSocketAddrs},
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use http_types::{ForwardedHeaders, IpCidr, MultipartLimits, TrustedProxies};
+
+// Prefix of the environment variables read by ServerConfig::load
+pub const ENV_PREFIX: &str = "APP_";
+
+// What to do with new connections and requests once a concurrency limit is reached
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OverloadPolicy {
+    // Stop accepting connections, or hold requests, until a slot frees up
+    Wait,
+    // Answer right away with 503 Service Unavailable and a Retry-After header
+    Reject,
+}
+
+impl OverloadPolicy {
+    pub fn from(policy: &str) -> Option<OverloadPolicy> {
+        match policy {
+            "wait" => Some(OverloadPolicy::Wait),
+            "reject" => Some(OverloadPolicy::Reject),
+            _ => None,
+        }
+    }
+}
+
+// Settings of a Server. Use ServerConfig::builder to create one,
+// or fill in the fields and call validate before using it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ServerConfig {
+    // Every address is bound by its own listener
+    pub addresses: Vec<SocketAddr>,
+    // Maximum number of connections waiting to be accepted by the OS
+    pub backlog: u32,
+    // How long in-flight connections are given to finish once the server is shutting down
+    pub shutdown_grace_period: Duration,
+    // Whether SIGINT and SIGTERM shut the server down gracefully
+    pub handle_signals: bool,
+    // Time allowed to receive the request line and headers, answered with 408 when exceeded
+    pub header_read_timeout: Duration,
+    // Time allowed to receive the body once the head was read, answered with 408 when exceeded
+    pub body_read_timeout: Duration,
+    // Time allowed to write the response, after which the connection is closed
+    pub write_timeout: Duration,
+    // Longest request line accepted, answered with 414 when exceeded
+    pub max_request_line_size: usize,
+    // Total size of the header lines, answered with 431 when exceeded
+    pub max_header_size: usize,
+    // Number of header lines, answered with 431 when exceeded
+    pub max_header_count: usize,
+    // Largest content-length accepted, answered with 413 when exceeded. Does not apply to
+    // multipart/form-data bodies, which are parsed while they are read, with multipart_limits.
+    pub max_body_size: usize,
+    pub multipart_limits: MultipartLimits,
+    // Number of open connections, unlimited if None
+    pub max_connections: Option<usize>,
+    // Number of requests being handled at once, unlimited if None. Connections that are
+    // still sending their request do not count towards this limit.
+    pub max_in_flight_requests: Option<usize>,
+    pub overload_policy: OverloadPolicy,
+    // Sent in the Retry-After header of rejected requests
+    pub retry_after: Duration,
+    // Peers whose forwarding headers are used by Request::client_ip, scheme and host, along with
+    // which of the Forwarded or X-Forwarded-* headers they set
+    pub trusted_proxies: TrustedProxies,
+    // Whether every connection starts with a PROXY protocol v1 or v2 header. Connections
+    // without a valid one are closed.
+    pub proxy_protocol: bool,
+    // HTTP/2 settings, used when the http2 feature is enabled.
+    // Streams a client can have open at once on a connection.
+    pub http2_max_concurrent_streams: u32,
+    // Bytes of request body a client can send on a stream, and on the whole connection,
+    // before it has to wait for the server to read them
+    pub http2_initial_stream_window_size: u32,
+    pub http2_initial_connection_window_size: u32,
+    // HTTP/2 connections without open streams are closed after this long
+    pub http2_idle_timeout: Duration,
+    // WebSocket settings, used when the websocket feature is enabled.
+    // Largest message a client can send, after reassembling its frames and decompressing it.
+    pub websocket_max_message_size: usize,
+    // Whether messages are compressed with permessage-deflate when the client offers it
+    pub websocket_compression: bool,
+    // Event streams send a comment when no event came for this long, so that clients and
+    // proxies keep the connection open
+    pub sse_keep_alive_interval: Duration,
+}
+
+// Largest flow-control window HTTP/2 allows, RFC 9113, section 6.9.1
+const HTTP2_MAX_WINDOW_SIZE: u32 = (1 << 31) - 1;
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        ServerConfig {
+            addresses: Vec::new(),
+            backlog: 1024,
+            shutdown_grace_period: Duration::from_secs(30),
+            handle_signals: true,
+            header_read_timeout: Duration::from_secs(10),
+            body_read_timeout: Duration::from_secs(30),
+            write_timeout: Duration::from_secs(30),
+            max_request_line_size: 8 * 1024,
+            max_header_size: 16 * 1024,
+            max_header_count: 100,
+            max_body_size: 10 * 1024 * 1024,
+            multipart_limits: MultipartLimits::default(),
+            max_connections: None,
+            max_in_flight_requests: None,
+            overload_policy: OverloadPolicy::Wait,
+            retry_after: Duration::from_secs(1),
+            trusted_proxies: TrustedProxies::default(),
+            proxy_protocol: false,
+            http2_max_concurrent_streams: 100,
+            http2_initial_stream_window_size: 65_535,
+            http2_initial_connection_window_size: 65_535,
+            http2_idle_timeout: Duration::from_secs(60),
+            websocket_max_message_size: 16 * 1024 * 1024,
+            websocket_compression: true,
+            sse_keep_alive_interval: Duration::from_secs(15),
+        }
+    }
+}
+
+impl ServerConfig {
+    pub fn builder() -> ServerConfigBuilder {
+        ServerConfigBuilder::new()
+    }
+
+    pub fn validate(&self) -> Result<(), Error> {
+        if self.addresses.is_empty() {
+            return Err(invalid_input("at least one listen address is required"));
+        }
+
+        for (idx, address) in self.addresses.iter().enumerate() {
+            if self.addresses[..idx].contains(address) {
+                return Err(invalid_input(&format!("listen address {} is used more than once", address)));
+            }
+        }
+
+        // Port 0 picks a random port per listener, so it can not be shared with another address
+        let uses_random_port = self.addresses.iter().any(|address| address.port() == 0);
+        if uses_random_port && self.addresses.len() > 1 {
+            return Err(invalid_input("port 0 can only be used with a single listen address"));
+        }
+
+        if self.backlog == 0 {
+            return Err(invalid_input("backlog must be greater than 0"));
+        }
+
+        let timeouts = [
+            ("header_read_timeout", self.header_read_timeout),
+            ("body_read_timeout", self.body_read_timeout),
+            ("write_timeout", self.write_timeout),
+            ("http2_idle_timeout", self.http2_idle_timeout),
+            ("sse_keep_alive_interval", self.sse_keep_alive_interval),
+        ];
+
+        for (name, timeout) in timeouts {
+            if timeout.is_zero() {
+                return Err(invalid_input(&format!("{} must be greater than 0", name)));
+            }
+        }
+
+        let limits = [
+            ("max_request_line_size", self.max_request_line_size),
+            ("max_header_size", self.max_header_size),
+            ("max_header_count", self.max_header_count),
+            ("multipart_max_part_size", self.multipart_limits.max_part_size),
+            ("multipart_max_total_size", self.multipart_limits.max_total_size),
+            ("http2_max_concurrent_streams", self.http2_max_concurrent_streams as usize),
+            ("websocket_max_message_size", self.websocket_max_message_size),
+        ];
+
+        let optional_limits = [
+            ("max_connections", self.max_connections),
+            ("max_in_flight_requests", self.max_in_flight_requests),
+        ];
+
+        let set_limits = optional_limits.into_iter().filter_map(|(name, limit)| Some((name, limit?)));
+
+        for (name, limit) in limits.into_iter().chain(set_limits) {
+            if limit == 0 {
+                return Err(invalid_input(&format!("{} must be greater than 0", name)));
+            }
+        }
+
+        let windows = [
+            ("http2_initial_stream_window_size", self.http2_initial_stream_window_size),
+            ("http2_initial_connection_window_size", self.http2_initial_connection_window_size),
+        ];
+
+        for (name, window) in windows {
+            if window == 0 || window > HTTP2_MAX_WINDOW_SIZE {
+                return Err(invalid_input(&format!("{} must be between 1 and {}", name, HTTP2_MAX_WINDOW_SIZE)));
+            }
+        }
+
+        Ok(())
+    }
+}
+
+// Loading from a TOML file and environment variables. Settings are applied in this order,
+// each one overriding the previous: defaults, the [server] table of the file, APP_* variables.
+//
+//     [server]
+//     listen = ["0.0.0.0:8080", "[::]:8080"]   # APP_LISTEN="0.0.0.0:8080,[::]:8080"
+//     backlog = 1024                           # APP_BACKLOG=1024
+//     shutdown_grace_period = "30s"            # APP_SHUTDOWN_GRACE_PERIOD=30s
+//     handle_signals = true                    # APP_HANDLE_SIGNALS=true
+//     header_read_timeout = "10s"              # APP_HEADER_READ_TIMEOUT=10s
+//     body_read_timeout = "30s"                # APP_BODY_READ_TIMEOUT=30s
+//     write_timeout = "30s"                    # APP_WRITE_TIMEOUT=30s
+//     max_request_line_size = 8192             # APP_MAX_REQUEST_LINE_SIZE=8192
+//     max_header_size = 16384                  # APP_MAX_HEADER_SIZE=16384
+//     max_header_count = 100                   # APP_MAX_HEADER_COUNT=100
+//     max_body_size = 10485760                 # APP_MAX_BODY_SIZE=10485760
+//     multipart_max_part_size = 10485760       # APP_MULTIPART_MAX_PART_SIZE=10485760
+//     multipart_max_total_size = 52428800      # APP_MULTIPART_MAX_TOTAL_SIZE=52428800
+//     multipart_memory_threshold = 262144      # APP_MULTIPART_MEMORY_THRESHOLD=262144
+//     multipart_temp_dir = "/var/tmp"          # APP_MULTIPART_TEMP_DIR=/var/tmp
+//     max_connections = 10000                  # APP_MAX_CONNECTIONS=10000
+//     max_in_flight_requests = 1000            # APP_MAX_IN_FLIGHT_REQUESTS=1000
+//     overload_policy = "reject"               # APP_OVERLOAD_POLICY=reject
+//     retry_after = "5s"                       # APP_RETRY_AFTER=5s
+//     trusted_proxies = ["10.0.0.0/8", "::1"]  # APP_TRUSTED_PROXIES=10.0.0.0/8,::1
+//     forwarded_headers = "x-forwarded"        # APP_FORWARDED_HEADERS=x-forwarded
+//     proxy_protocol = true                    # APP_PROXY_PROTOCOL=true
+//     http2_max_concurrent_streams = 100       # APP_HTTP2_MAX_CONCURRENT_STREAMS=100
+//     http2_initial_stream_window_size = 65535 # APP_HTTP2_INITIAL_STREAM_WINDOW_SIZE=65535
+//     http2_initial_connection_window_size = 65535  # APP_HTTP2_INITIAL_CONNECTION_WINDOW_SIZE=65535
+//     http2_idle_timeout = "60s"               # APP_HTTP2_IDLE_TIMEOUT=60s
+//     websocket_max_message_size = 16777216    # APP_WEBSOCKET_MAX_MESSAGE_SIZE=16777216
+//     websocket_compression = true             # APP_WEBSOCKET_COMPRESSION=true
+//     sse_keep_alive_interval = "15s"          # APP_SSE_KEEP_ALIVE_INTERVAL=15s
+//
+// Durations are either a number of seconds or a number followed by "ms", "s", "m" or "h".
+impl ServerConfig {
+    // Loads the file (if any) and the environment of the process, then validates the result
+    pub fn load(path: Option<&Path>) -> Result<ServerConfig, Error> {
+        let env: HashMap<String, String> = std::env::vars().collect();
+
+        let toml = match path {
+            Some(path) => Some((path.display().to_string(), fs::read_to_string(path)?)),
+            None => None,
+        };
+
+        ServerConfig::from_sources(toml.as_ref().map(|(name, contents)| (name.as_str(), contents.as_str())), &env)
+    }
+
+    // Same as load, with the file given as (name used in errors, contents) and the environment as a map
+    pub fn from_sources(toml: Option<(&str, &str)>, env: &HashMap<String, String>) -> Result<ServerConfig, Error> {
+        let mut config = ServerConfig::default();
+
+        if let Some((name, contents)) = toml {
+            config.apply_toml(name, contents)?;
+        }
+
+        config.apply_env(env)?;
+        config.validate()?;
+
+        Ok(config)
+    }
+
+    fn apply_toml(&mut self, name: &str, contents: &str) -> Result<(), Error> {
+        let table = match contents.parse::<toml::Table>() {
+            Ok(table) => table,
+            Err(e) => return Err(invalid_data(&format!("{}: {}", name, e))),
+        };
+
+        for (key, value) in &table {
+            let toml::Value::Table(server) = value else {
+                return Err(invalid_data(&format!("{}: unknown setting {}", name, key)));
+            };
+
+            if key != "server" {
+                return Err(invalid_data(&format!("{}: unknown table [{}]", name, key)));
+            }
+
+            for (key, value) in server {
+                self.apply(key, SettingValue::Toml(value))
+                    .map_err(|e| invalid_data(&format!("{}: server.{}: {}", name, key, e)))?;
+            }
+        }
+
+        Ok(())
+    }
+
+    // Unknown APP_* variables are ignored, since the application may use the prefix as well
+    fn apply_env(&mut self, env: &HashMap<String, String>) -> Result<(), Error> {
+        let mut variables: Vec<_> = env.iter().filter(|(name, _)| name.starts_with(ENV_PREFIX)).collect();
+        variables.sort();
+
+        for (name, value) in variables {
+            let key = name[ENV_PREFIX.len()..].to_lowercase();
+
+            if !ServerConfig::is_setting(&key) {
+                continue;
+            }
+
+            self.apply(&key, SettingValue::Env(value))
+                .map_err(|e| invalid_data(&format!("{}: {}", name, e)))?;
+        }
+
+        Ok(())
+    }
+
+    fn is_setting(key: &str) -> bool {
+        matches!(
+            key,
+            "listen"
+                | "backlog"
+                | "shutdown_grace_period"
+                | "handle_signals"
+                | "header_read_timeout"
+                | "body_read_timeout"
+                | "write_timeout"
+                | "max_request_line_size"
+                | "max_header_size"
+                | "max_header_count"
+                | "max_body_size"
+                | "multipart_max_part_size"
+                | "multipart_max_total_size"
+                | "multipart_memory_threshold"
+                | "multipart_temp_dir"
+                | "max_connections"
+                | "max_in_flight_requests"
+                | "overload_policy"
+                | "retry_after"
+                | "trusted_proxies"
+                | "forwarded_headers"
+                | "proxy_protocol"
+                | "http2_max_concurrent_streams"
+                | "http2_initial_stream_window_size"
+                | "http2_initial_connection_window_size"
+                | "http2_idle_timeout"
+                | "websocket_max_message_size"
+                | "websocket_compression"
+                | "sse_keep_alive_interval"
+        )
+    }
+
+    fn apply(&mut self, key: &str, value: SettingValue) -> Result<(), String> {
+        match key {
+            "listen" => self.addresses = value.as_addresses()?,
+            "backlog" => self.backlog = value.as_u32()?,
+            "shutdown_grace_period" => self.shutdown_grace_period = value.as_duration()?,
+            "handle_signals" => self.handle_signals = value.as_bool()?,
+            "header_read_timeout" => self.header_read_timeout = value.as_duration()?,
+            "body_read_timeout" => self.body_read_timeout = value.as_duration()?,
+            "write_timeout" => self.write_timeout = value.as_duration()?,
+            "max_request_line_size" => self.max_request_line_size = value.as_usize()?,
+            "max_header_size" => self.max_header_size = value.as_usize()?,
+            "max_header_count" => self.max_header_count = value.as_usize()?,
+            "max_body_size" => self.max_body_size = value.as_usize()?,
+            "multipart_max_part_size" => self.multipart_limits.max_part_size = value.as_usize()?,
+            "multipart_max_total_size" => self.multipart_limits.max_total_size = value.as_usize()?,
+            "multipart_memory_threshold" => self.multipart_limits.memory_threshold = value.as_usize()?,
+            "multipart_temp_dir" => self.multipart_limits.temp_dir = value.as_path()?,
+            "max_connections" => self.max_connections = Some(value.as_usize()?),
+            "max_in_flight_requests" => self.max_in_flight_requests = Some(value.as_usize()?),
+            "overload_policy" => self.overload_policy = value.as_overload_policy()?,
+            "retry_after" => self.retry_after = value.as_duration()?,
+            "trusted_proxies" => {
+                let headers = self.trusted_proxies.headers();
+                self.trusted_proxies = TrustedProxies::new(value.as_cidrs()?);
+                self.trusted_proxies.set_headers(headers);
+            }
+            "forwarded_headers" => self.trusted_proxies.set_headers(value.as_forwarded_headers()?),
+            "proxy_protocol" => self.proxy_protocol = value.as_bool()?,
+            "http2_max_concurrent_streams" => self.http2_max_concurrent_streams = value.as_u32()?,
+            "http2_initial_stream_window_size" => self.http2_initial_stream_window_size = value.as_u32()?,
+            "http2_initial_connection_window_size" => self.http2_initial_connection_window_size = value.as_u32()?,
+            "http2_idle_timeout" => self.http2_idle_timeout = value.as_duration()?,
+            "websocket_max_message_size" => self.websocket_max_message_size = value.as_usize()?,
+            "websocket_compression" => self.websocket_compression = value.as_bool()?,
+            "sse_keep_alive_interval" => self.sse_keep_alive_interval = value.as_duration()?,
+            _ => return Err("unknown setting".to_owned()),
+        }
+
+        Ok(())
+    }
+}
+
+enum SettingValue<'a> {
+    Toml(&'a toml::Value),
+    Env(&'a str),
+}
+
+impl<'a> SettingValue<'a> {
+    fn as_u32(&self) -> Result<u32, String> {
+        let parsed = match self {
+            SettingValue::Toml(toml::Value::Integer(value)) => u32::try_from(*value).ok(),
+            SettingValue::Env(value) => value.trim().parse().ok(),
+            _ => None,
+        };
+
+        parsed.ok_or_else(|| format!("expected an integer between 0 and {}, got {}", u32::MAX, self))
+    }
+
+    fn as_usize(&self) -> Result<usize, String> {
+        let parsed = match self {
+            SettingValue::Toml(toml::Value::Integer(value)) => usize::try_from(*value).ok(),
+            SettingValue::Env(value) => value.trim().parse().ok(),
+            _ => None,
+        };
+
+        parsed.ok_or_else(|| format!("expected a positive integer, got {}", self))
+    }
+
+    fn as_bool(&self) -> Result<bool, String> {
+        let parsed = match self {
+            SettingValue::Toml(toml::Value::Boolean(value)) => Some(*value),
+            SettingValue::Env(value) => match value.trim().to_lowercase().as_str() {
+                "true" | "1" | "yes" => Some(true),
+                "false" | "0" | "no" => Some(false),
+                _ => None,
+            },
+            _ => None,
+        };
+
+        parsed.ok_or_else(|| format!("expected true or false, got {}", self))
+    }
+
+    fn as_duration(&self) -> Result<Duration, String> {
+        let parsed = match self {
+            SettingValue::Toml(toml::Value::Integer(seconds)) => u64::try_from(*seconds).ok().map(Duration::from_secs),
+            SettingValue::Toml(toml::Value::String(value)) => parse_duration(value),
+            SettingValue::Env(value) => parse_duration(value),
+            _ => None,
+        };
+
+        parsed.ok_or_else(|| format!("expected a duration such as 30, \"30s\" or \"500ms\", got {}", self))
+    }
+
+    fn as_overload_policy(&self) -> Result<OverloadPolicy, String> {
+        let parsed = match self {
+            SettingValue::Toml(toml::Value::String(value)) => OverloadPolicy::from(value),
+            SettingValue::Env(value) => OverloadPolicy::from(value.trim()),
+            _ => None,
+        };
+
+        parsed.ok_or_else(|| format!("expected \"wait\" or \"reject\", got {}", self))
+    }
+
+    fn as_forwarded_headers(&self) -> Result<ForwardedHeaders, String> {
+        let parsed = match self {
+            SettingValue::Toml(toml::Value::String(value)) => ForwardedHeaders::from(value),
+            SettingValue::Env(value) => ForwardedHeaders::from(value.trim()),
+            _ => None,
+        };
+
+        parsed.ok_or_else(|| format!("expected \"forwarded\" or \"x-forwarded\", got {}", self))
+    }
+
+    fn as_path(&self) -> Result<PathBuf, String> {
+        match self {
+            SettingValue::Toml(toml::Value::String(value)) if !value.is_empty() => Ok(PathBuf::from(value)),
+            SettingValue::Env(value) if !value.trim().is_empty() => Ok(PathBuf::from(value.trim())),
+            _ => Err(format!("expected a path, got {}", self)),
+        }
+    }
+
+    // A TOML string or array of strings, or a comma-separated environment variable
+    fn as_list(&self, expected: &str) -> Result<Vec<&str>, String> {
+        match self {
+            SettingValue::Toml(toml::Value::String(value)) => Ok(vec![value.as_str()]),
+            SettingValue::Toml(toml::Value::Array(values)) => {
+                let mut entries = Vec::with_capacity(values.len());
+
+                for value in values {
+                    let toml::Value::String(entry) = value else {
+                        return Err(format!("expected a list of {}, got {}", expected, self));
+                    };
+                    entries.push(entry.as_str());
+                }
+
+                Ok(entries)
+            }
+            SettingValue::Env(value) => Ok(value.split(',').map(str::trim).filter(|entry| !entry.is_empty()).collect()),
+            _ => Err(format!("expected a list of {}, got {}", expected, self)),
+        }
+    }
+
+    fn as_cidrs(&self) -> Result<Vec<IpCidr>, String> {
+        self.as_list("addresses or CIDR ranges")?
+            .into_iter()
+            .map(|entry| IpCidr::from(entry).ok_or_else(|| format!("invalid address or CIDR range \"{}\"", entry)))
+            .collect()
+    }
+
+    fn as_addresses(&self) -> Result<Vec<SocketAddr>, String> {
+        let mut addresses = Vec::new();
+
+        for entry in self.as_list("addresses")? {
+            let resolved = entry
+                .to_socket_addrs()
+                .map_err(|e| format!("invalid address \"{}\": {}", entry, e))?;
+
+            for address in resolved {
+                if !addresses.contains(&address) {
+                    addresses.push(address);
+                }
+            }
+        }
+
+        Ok(addresses)
+    }
+}
+
+impl<'a> std::fmt::Display for SettingValue<'a> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SettingValue::Toml(value) => write!(f, "{}", value),
+            SettingValue::Env(value) => write!(f, "\"{}\"", value),
+        }
+    }
+}
+
+fn parse_duration(value: &str) -> Option<Duration> {
+    let value = value.trim();
+    let split_at = value.find(|c: char| !c.is_ascii_digit()).unwrap_or(value.len());
+    let (amount, unit) = value.split_at(split_at);
+    let amount: u64 = amount.parse().ok()?;
+
+    match unit.trim() {
+        "ms" => Some(Duration::from_millis(amount)),
+        "" | "s" => Some(Duration::from_secs(amount)),
+        "m" => Some(Duration::from_secs(amount.checked_mul(60)?)),
+        "h" => Some(Duration::from_secs(amount.checked_mul(60 * 60)?)),
+        _ => None,
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct ServerConfigBuilder {
+    config: ServerConfig,
+    // The first resolution error is reported by build, so that listen can be chained
+    error: Option<Error>,
+}
+
+impl ServerConfigBuilder {
+    pub fn new() -> ServerConfigBuilder {
+        ServerConfigBuilder::default()
+    }
+
+    // Adds every address the argument resolves to, e.g. "0.0.0.0:8080", "[::]:8080" or ("localhost", 8080)
+    pub fn listen(mut self, address: impl ToSocketAddrs) -> ServerConfigBuilder {
+        match address.to_socket_addrs() {
+            Ok(addresses) => {
+                for address in addresses {
+                    if !self.config.addresses.contains(&address) {
+                        self.config.addresses.push(address);
+                    }
+                }
+            }
+            Err(e) => {
+                self.error.get_or_insert(e);
+            }
+        }
+
+        self
+    }
+
+    pub fn backlog(mut self, backlog: u32) -> ServerConfigBuilder {
+        self.config.backlog = backlog;
+        self
+    }
+
+    pub fn shutdown_grace_period(mut self, grace_period: Duration) -> ServerConfigBuilder {
+        self.config.shutdown_grace_period = grace_period;
+        self
+    }
+
+    pub fn handle_signals(mut self, handle_signals: bool) -> ServerConfigBuilder {
+        self.config.handle_signals = handle_signals;
+        self
+    }
+
+    pub fn header_read_timeout(mut self, timeout: Duration) -> ServerConfigBuilder {
+        self.config.header_read_timeout = timeout;
+        self
+    }
+
+    pub fn body_read_timeout(mut self, timeout: Duration) -> ServerConfigBuilder {
+        self.config.body_read_timeout = timeout;
+        self
+    }
+
+    pub fn write_timeout(mut self, timeout: Duration) -> ServerConfigBuilder {
+        self.config.write_timeout = timeout;
+        self
+    }
+
+    pub fn max_request_line_size(mut self, size: usize) -> ServerConfigBuilder {
+        self.config.max_request_line_size = size;
+        self
+    }
+
+    pub fn max_header_size(mut self, size: usize) -> ServerConfigBuilder {
+        self.config.max_header_size = size;
+        self
+    }
+
+    pub fn max_header_count(mut self, count: usize) -> ServerConfigBuilder {
+        self.config.max_header_count = count;
+        self
+    }
+
+    pub fn max_body_size(mut self, size: usize) -> ServerConfigBuilder {
+        self.config.max_body_size = size;
+        self
+    }
+
+    // Limits of multipart/form-data bodies, and where file parts are spilled to
+    pub fn multipart_limits(mut self, limits: MultipartLimits) -> ServerConfigBuilder {
+        self.config.multipart_limits = limits;
+        self
+    }
+
+    pub fn max_connections(mut self, max: usize) -> ServerConfigBuilder {
+        self.config.max_connections = Some(max);
+        self
+    }
+
+    pub fn max_in_flight_requests(mut self, max: usize) -> ServerConfigBuilder {
+        self.config.max_in_flight_requests = Some(max);
+        self
+    }
+
+    pub fn overload_policy(mut self, policy: OverloadPolicy) -> ServerConfigBuilder {
+        self.config.overload_policy = policy;
+        self
+    }
+
+    pub fn retry_after(mut self, retry_after: Duration) -> ServerConfigBuilder {
+        self.config.retry_after = retry_after;
+        self
+    }
+
+    // Trusts the forwarding headers sent by peers in the range, e.g. "10.0.0.0/8" or "::1"
+    pub fn trust_proxy(mut self, cidr: &str) -> ServerConfigBuilder {
+        match IpCidr::from(cidr) {
+            Some(cidr) => self.config.trusted_proxies.add(cidr),
+            None => {
+                self.error
+                    .get_or_insert(invalid_input(&format!("invalid address or CIDR range \"{}\"", cidr)));
+            }
+        }
+
+        self
+    }
+
+    // Which forwarding headers the trusted proxies set, the other ones are ignored
+    pub fn forwarded_headers(mut self, headers: ForwardedHeaders) -> ServerConfigBuilder {
+        self.config.trusted_proxies.set_headers(headers);
+        self
+    }
+
+    pub fn proxy_protocol(mut self, proxy_protocol: bool) -> ServerConfigBuilder {
+        self.config.proxy_protocol = proxy_protocol;
+        self
+    }
+
+    pub fn http2_max_concurrent_streams(mut self, max: u32) -> ServerConfigBuilder {
+        self.config.http2_max_concurrent_streams = max;
+        self
+    }
+
+    pub fn http2_initial_stream_window_size(mut self, size: u32) -> ServerConfigBuilder {
+        self.config.http2_initial_stream_window_size = size;
+        self
+    }
+
+    pub fn http2_initial_connection_window_size(mut self, size: u32) -> ServerConfigBuilder {
+        self.config.http2_initial_connection_window_size = size;
+        self
+    }
+
+    pub fn http2_idle_timeout(mut self, timeout: Duration) -> ServerConfigBuilder {
+        self.config.http2_idle_timeout = timeout;
+        self
+    }
+
+    pub fn websocket_max_message_size(mut self, size: usize) -> ServerConfigBuilder {
+        self.config.websocket_max_message_size = size;
+        self
+    }
+
+    pub fn websocket_compression(mut self, compression: bool) -> ServerConfigBuilder {
+        self.config.websocket_compression = compression;
+        self
+    }
+
+    pub fn sse_keep_alive_interval(mut self, interval: Duration) -> ServerConfigBuilder {
+        self.config.sse_keep_alive_interval = interval;
+        self
+    }
+
+    pub fn build(self) -> Result<ServerConfig, Error> {
+        if let Some(error) = self.error {
+            return Err(error);
+        }
+
+        self.config.validate()?;
+
+        Ok(self.config)
+    }
+}
+
+fn invalid_input(message: &str) -> Error {
+    Error::new(ErrorKind::InvalidInput, message)
+}
+
+fn invalid_data(message: &str) -> Error {
+    Error::new(ErrorKind::InvalidData, message)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const FILE: &str = "config.toml";
+
+    fn env(variables: &[(&str, &str)]) -> HashMap<String, String> {
+        variables.iter().map(|(name, value)| (name.to_string(), value.to_string())).collect()
+    }
+
+    fn load(toml: &str, variables: &[(&str, &str)]) -> Result<ServerConfig, Error> {
+        ServerConfig::from_sources(Some((FILE, toml)), &env(variables))
+    }
+
+    fn error(toml: &str, variables: &[(&str, &str)]) -> (ErrorKind, String) {
+        let error = load(toml, variables).unwrap_err();
+        (error.kind(), error.to_string())
+    }
+
+    fn address(address: &str) -> SocketAddr {
+        address.parse().unwrap()
+    }
+
+    #[test]
+    fn uses_defaults_for_missing_settings() {
+        let config = ServerConfig::from_sources(None, &env(&[("APP_LISTEN", "127.0.0.1:8080")])).unwrap();
+
+        assert_eq!(
+            config,
+            ServerConfig {
+                addresses: vec![address("127.0.0.1:8080")],
+                ..ServerConfig::default()
+            }
+        );
+
+        let (kind, message) = error("", &[]);
+        assert_eq!(kind, ErrorKind::InvalidInput);
+        assert_eq!(message, "at least one listen address is required");
+    }
+
+    #[test]
+    fn reads_the_server_table() {
+        let toml = r#"
+            [server]
+            listen = ["127.0.0.1:8080", "[::1]:8080"]
+            backlog = 64
+            shutdown_grace_period = "2m"
+            header_read_timeout = 5
+            body_read_timeout = "500ms"
+            max_body_size = 1024
+            max_connections = 10
+            overload_policy = "reject"
+            trusted_proxies = ["10.0.0.0/8", "::1"]
+            forwarded_headers = "forwarded"
+            multipart_max_total_size = 2048
+            multipart_temp_dir = "/var/tmp"
+            websocket_compression = false
+        "#;
+
+        let config = load(toml, &[]).unwrap();
+
+        assert_eq!(config.addresses, [address("127.0.0.1:8080"), address("[::1]:8080")]);
+        assert_eq!(config.backlog, 64);
+        assert_eq!(config.shutdown_grace_period, Duration::from_secs(120));
+        assert_eq!(config.header_read_timeout, Duration::from_secs(5));
+        assert_eq!(config.body_read_timeout, Duration::from_millis(500));
+        assert_eq!(config.max_body_size, 1024);
+        assert_eq!(config.max_connections, Some(10));
+        assert_eq!(config.overload_policy, OverloadPolicy::Reject);
+        assert_eq!(config.trusted_proxies.cidrs(), [IpCidr::from("10.0.0.0/8").unwrap(), IpCidr::from("::1").unwrap()]);
+        assert_eq!(config.trusted_proxies.headers(), ForwardedHeaders::Forwarded);
+        assert_eq!(config.multipart_limits.max_total_size, 2048);
+        assert_eq!(config.multipart_limits.temp_dir, PathBuf::from("/var/tmp"));
+        assert!(!config.websocket_compression);
+
+        // Untouched settings keep their defaults
+        assert_eq!(config.write_timeout, ServerConfig::default().write_timeout);
+        assert_eq!(config.multipart_limits.max_part_size, MultipartLimits::default().max_part_size);
+    }
+
+    #[test]
+    fn environment_overrides_the_file() {
+        let toml = r#"
+            [server]
+            listen = "127.0.0.1:8080"
+            backlog = 64
+            max_body_size = 1024
+            trusted_proxies = "10.0.0.0/8"
+            forwarded_headers = "forwarded"
+        "#;
+
+        let config = load(
+            toml,
+            &[
+                ("APP_LISTEN", "127.0.0.1:9000, 127.0.0.2:9000"),
+                ("APP_BACKLOG", " 128 "),
+                ("APP_HANDLE_SIGNALS", "no"),
+                ("APP_TRUSTED_PROXIES", "192.168.0.0/16"),
+                ("APP_WRITE_TIMEOUT", "1h"),
+                // Other variables, with the prefix or without, are left alone
+                ("APP_DATABASE_URL", "postgres://localhost"),
+                ("BACKLOG", "1"),
+            ],
+        )
+        .unwrap();
+
+        assert_eq!(config.addresses, [address("127.0.0.1:9000"), address("127.0.0.2:9000")]);
+        assert_eq!(config.backlog, 128);
+        assert!(!config.handle_signals);
+        assert_eq!(config.write_timeout, Duration::from_secs(60 * 60));
+        assert_eq!(config.max_body_size, 1024);
+
+        // Replacing the proxies keeps the header family of the file
+        assert_eq!(config.trusted_proxies.cidrs(), [IpCidr::from("192.168.0.0/16").unwrap()]);
+        assert_eq!(config.trusted_proxies.headers(), ForwardedHeaders::Forwarded);
+    }
+
+    #[test]
+    fn rejects_unknown_settings() {
+        let listen = "[server]\nlisten = \"127.0.0.1:8080\"\n";
+
+        assert_eq!(
+            error(&format!("{}max_body = 1", listen), &[]),
+            (ErrorKind::InvalidData, "config.toml: server.max_body: unknown setting".to_owned())
+        );
+        assert_eq!(
+            error(&format!("{}[client]\ntimeout = 1", listen), &[]),
+            (ErrorKind::InvalidData, "config.toml: unknown table [client]".to_owned())
+        );
+        assert_eq!(
+            error(&format!("backlog = 1\n{}", listen), &[]),
+            (ErrorKind::InvalidData, "config.toml: unknown setting backlog".to_owned())
+        );
+
+        let (kind, message) = error("[server", &[]);
+        assert_eq!(kind, ErrorKind::InvalidData);
+        assert!(message.starts_with("config.toml: "), "{}", message);
+    }
+
+    #[test]
+    fn rejects_invalid_values() {
+        let listen = "[server]\nlisten = \"127.0.0.1:8080\"\n";
+        let cases = [
+            ("backlog = -1", "server.backlog: expected an integer between 0 and 4294967295, got -1"),
+            ("max_body_size = \"1MB\"", "server.max_body_size: expected a positive integer, got \"1MB\""),
+            ("handle_signals = \"yes\"", "server.handle_signals: expected true or false, got \"yes\""),
+            ("write_timeout = \"5 days\"", "server.write_timeout: expected a duration such as 30, \"30s\" or \"500ms\", got \"5 days\""),
+            ("overload_policy = \"drop\"", "server.overload_policy: expected \"wait\" or \"reject\", got \"drop\""),
+            ("forwarded_headers = \"x-real-ip\"", "server.forwarded_headers: expected \"forwarded\" or \"x-forwarded\", got \"x-real-ip\""),
+            ("trusted_proxies = [\"10.0.0.0/33\"]", "server.trusted_proxies: invalid address or CIDR range \"10.0.0.0/33\""),
+            ("trusted_proxies = [1]", "server.trusted_proxies: expected a list of addresses or CIDR ranges, got [1]"),
+            ("multipart_temp_dir = \"\"", "server.multipart_temp_dir: expected a path, got \"\""),
+        ];
+
+        for (setting, message) in cases {
+            let (kind, error) = error(&format!("{}{}", listen, setting), &[]);
+            assert_eq!(kind, ErrorKind::InvalidData);
+            assert_eq!(error, format!("config.toml: {}", message));
+        }
+
+        let variables = [
+            ("APP_BACKLOG", "lots", "APP_BACKLOG: expected an integer between 0 and 4294967295, got \"lots\""),
+            ("APP_PROXY_PROTOCOL", "maybe", "APP_PROXY_PROTOCOL: expected true or false, got \"maybe\""),
+            ("APP_RETRY_AFTER", "-5s", "APP_RETRY_AFTER: expected a duration such as 30, \"30s\" or \"500ms\", got \"-5s\""),
+        ];
+
+        for (name, value, message) in variables {
+            assert_eq!(error(listen, &[(name, value)]), (ErrorKind::InvalidData, message.to_owned()));
+        }
+
+        let (kind, message) = error("", &[("APP_LISTEN", "not an address")]);
+        assert_eq!(kind, ErrorKind::InvalidData);
+        assert!(message.starts_with("APP_LISTEN: invalid address \"not an address\""), "{}", message);
+    }
+
+    #[test]
+    fn validates_the_result() {
+        let listen = "[server]\nlisten = \"127.0.0.1:8080\"\n";
+        let cases = [
+            ("backlog = 0", "backlog must be greater than 0"),
+            ("body_read_timeout = 0", "body_read_timeout must be greater than 0"),
+            ("max_connections = 0", "max_connections must be greater than 0"),
+            ("multipart_max_part_size = 0", "multipart_max_part_size must be greater than 0"),
+            ("http2_initial_stream_window_size = 2147483648", "http2_initial_stream_window_size must be between 1 and 2147483647"),
+            ("listen = [\"127.0.0.1:0\", \"127.0.0.2:0\"]", "port 0 can only be used with a single listen address"),
+        ];
+
+        for (setting, message) in cases {
+            let toml = if setting.starts_with("listen") { format!("[server]\n{}", setting) } else { format!("{}{}", listen, setting) };
+            assert_eq!(error(&toml, &[]), (ErrorKind::InvalidInput, message.to_owned()));
+        }
+
+        // Validation runs after the environment, which can fix the file
+        assert!(load(&format!("{}backlog = 0", listen), &[("APP_BACKLOG", "1")]).is_ok());
+    }
+}
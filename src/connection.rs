@@ -0,0 +1,163 @@
+use std::{
+    io::Error,
+    net::SocketAddr,
+    pin::Pin,
+    sync::Arc,
+    task::{Context, Poll},
+};
+
+use async_std::{
+    io::{Read, Write},
+    net::TcpStream,
+};
+use http_types::ClientCertificate;
+
+enum Stream {
+    Plain(TcpStream),
+    #[cfg(feature = "tls")]
+    Tls(Box<futures_rustls::server::TlsStream<TcpStream>>),
+}
+
+// What is known about the client once the connection is open, handed to every request made on it
+#[derive(Debug, Clone, Default)]
+pub(crate) struct ConnectionInfo {
+    // Taken from the PROXY header when there is one
+    pub remote_addr: Option<SocketAddr>,
+    pub local_addr: Option<SocketAddr>,
+    pub secure: bool,
+    // Verified during the handshake, when the server requires client certificates
+    pub client_certificate: Option<Arc<ClientCertificate>>,
+}
+
+// An accepted connection, once the PROXY header was read and the TLS handshake completed
+pub(crate) struct Connection {
+    stream: Stream,
+    // Bytes that were read ahead and are returned before reading from the stream again
+    unread: Vec<u8>,
+    pub info: ConnectionInfo,
+}
+
+impl Connection {
+    pub fn plain(stream: TcpStream, remote_addr: Option<SocketAddr>, local_addr: Option<SocketAddr>) -> Connection {
+        Connection {
+            stream: Stream::Plain(stream),
+            unread: Vec::new(),
+            info: ConnectionInfo {
+                remote_addr,
+                local_addr,
+                ..ConnectionInfo::default()
+            },
+        }
+    }
+
+    #[cfg(feature = "tls")]
+    pub fn tls(
+        stream: futures_rustls::server::TlsStream<TcpStream>,
+        remote_addr: Option<SocketAddr>,
+        local_addr: Option<SocketAddr>,
+    ) -> Connection {
+        let info = ConnectionInfo {
+            remote_addr,
+            local_addr,
+            secure: true,
+            client_certificate: crate::tls::client_certificate(&stream),
+        };
+
+        Connection {
+            stream: Stream::Tls(Box::new(stream)),
+            unread: Vec::new(),
+            info,
+        }
+    }
+
+    // The protocol agreed on through ALPN during the TLS handshake
+    #[cfg(feature = "http2")]
+    pub fn alpn_protocol(&self) -> Option<&[u8]> {
+        match &self.stream {
+            Stream::Plain(_) => None,
+            #[cfg(feature = "tls")]
+            Stream::Tls(stream) => stream.get_ref().1.alpn_protocol(),
+        }
+    }
+
+    // Puts bytes back in front of whatever is read next
+    #[cfg(feature = "http2")]
+    pub fn unread(&mut self, mut bytes: Vec<u8>) {
+        bytes.append(&mut self.unread);
+        self.unread = bytes;
+    }
+}
+
+impl Read for Connection {
+    fn poll_read(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut [u8]) -> Poll<Result<usize, Error>> {
+        let connection = self.get_mut();
+
+        if !connection.unread.is_empty() {
+            let len = buf.len().min(connection.unread.len());
+            buf[..len].copy_from_slice(&connection.unread[..len]);
+            connection.unread.drain(..len);
+            return Poll::Ready(Ok(len));
+        }
+
+        match &mut connection.stream {
+            Stream::Plain(stream) => Pin::new(stream).poll_read(cx, buf),
+            #[cfg(feature = "tls")]
+            Stream::Tls(stream) => Pin::new(stream).poll_read(cx, buf),
+        }
+    }
+}
+
+impl Write for Connection {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<Result<usize, Error>> {
+        match &mut self.get_mut().stream {
+            Stream::Plain(stream) => Pin::new(stream).poll_write(cx, buf),
+            #[cfg(feature = "tls")]
+            Stream::Tls(stream) => Pin::new(stream).poll_write(cx, buf),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Error>> {
+        match &mut self.get_mut().stream {
+            Stream::Plain(stream) => Pin::new(stream).poll_flush(cx),
+            #[cfg(feature = "tls")]
+            Stream::Tls(stream) => Pin::new(stream).poll_flush(cx),
+        }
+    }
+
+    fn poll_close(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Error>> {
+        match &mut self.get_mut().stream {
+            Stream::Plain(stream) => Pin::new(stream).poll_close(cx),
+            #[cfg(feature = "tls")]
+            Stream::Tls(stream) => Pin::new(stream).poll_close(cx),
+        }
+    }
+}
+
+// The h2 crate works on tokio's IO traits
+#[cfg(feature = "http2")]
+impl tokio::io::AsyncRead for Connection {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut tokio::io::ReadBuf<'_>,
+    ) -> Poll<Result<(), Error>> {
+        let read = std::task::ready!(Read::poll_read(self, cx, buf.initialize_unfilled()))?;
+        buf.advance(read);
+        Poll::Ready(Ok(()))
+    }
+}
+
+#[cfg(feature = "http2")]
+impl tokio::io::AsyncWrite for Connection {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<Result<usize, Error>> {
+        Write::poll_write(self, cx, buf)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Error>> {
+        Write::poll_flush(self, cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Error>> {
+        Write::poll_close(self, cx)
+    }
+}
@@ -0,0 +1,541 @@
+use std::{
+    collections::HashMap,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+};
+
+use async_std::{future::timeout, io::ReadExt, task};
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use bytes::Bytes;
+use futures_lite::future;
+use h2::{
+    server::{Builder, SendResponse},
+    Reason, RecvStream, SendStream,
+};
+use http_types::{EventStream, HTTPMethod, HTTPVersion, Request, Response, StatusCodes};
+
+use crate::{
+    body::BodySink,
+    connection::{Connection, ConnectionInfo},
+    ConnectionContext, Server, ServerConfig,
+};
+
+// Sent by clients before anything else on an HTTP/2 connection, RFC 9113, section 3.4
+const PREFACE: &[u8; 24] = b"PRI * HTTP/2.0\r\n\r\nSM\r\n\r\n";
+const FRAME_HEADER_SIZE: usize = 9;
+// Largest frame a client may send before the server announced a larger one
+const DEFAULT_MAX_FRAME_SIZE: usize = 16_384;
+const FRAME_TYPE_HEADERS: u8 = 0x1;
+const FRAME_TYPE_SETTINGS: u8 = 0x4;
+const FLAG_END_STREAM: u8 = 0x1;
+const FLAG_END_HEADERS: u8 = 0x4;
+
+// Settings whose values are restricted, RFC 9113, section 6.5.2
+const SETTING_SIZE: usize = 6;
+const SETTINGS_ENABLE_PUSH: u16 = 0x2;
+const SETTINGS_INITIAL_WINDOW_SIZE: u16 = 0x4;
+const SETTINGS_MAX_FRAME_SIZE: u16 = 0x5;
+const MAX_WINDOW_SIZE: u32 = (1 << 31) - 1;
+const MAX_FRAME_SIZE: u32 = (1 << 24) - 1;
+
+// Whether the client chose HTTP/2, either through ALPN or by sending the connection preface
+// right away (prior knowledge). Whatever was read to find out is left for the next reader.
+pub(crate) async fn is_http2(connection: &mut Connection, config: &ServerConfig) -> bool {
+    if connection.alpn_protocol() == Some(b"h2") {
+        return true;
+    }
+
+    let mut buf = Vec::with_capacity(PREFACE.len());
+
+    // HTTP/1 requests differ from the preface within the first bytes, so this rarely waits for more
+    let read = async {
+        loop {
+            if buf[..] != PREFACE[..buf.len()] {
+                return false;
+            }
+
+            if buf.len() == PREFACE.len() {
+                return true;
+            }
+
+            let mut chunk = [0; PREFACE.len()];
+
+            match connection.read(&mut chunk[..PREFACE.len() - buf.len()]).await {
+                Ok(0) | Err(_) => return false,
+                Ok(read) => buf.extend_from_slice(&chunk[..read]),
+            }
+        }
+    };
+
+    let is_preface = timeout(config.header_read_timeout, read).await.unwrap_or(false);
+    connection.unread(buf);
+
+    is_preface
+}
+
+// Whether an HTTP/1.1 request asks to continue the connection as HTTP/2 over cleartext, RFC 7540, section 3.2
+pub(crate) fn is_upgrade(request: &Request, connection: &Connection) -> bool {
+    let has_token = |name: &str, token: &str| {
+        request
+            .headers
+            .get(name)
+            .is_some_and(|value| value.split(',').any(|item| item.trim().eq_ignore_ascii_case(token)))
+    };
+
+    !connection.info.secure
+        && request.version() == HTTPVersion::HTTP1_1
+        && request.method != HTTPMethod::CONNECT
+        && has_token("upgrade", "h2c")
+        && has_token("connection", "http2-settings")
+        && request.headers.get("http2-settings").is_some_and(|settings| is_valid_settings(settings))
+        // Otherwise the request is answered over HTTP/1.1, as servers may ignore the upgrade
+        && headers_block(request).len() <= DEFAULT_MAX_FRAME_SIZE
+}
+
+// The HTTP2-Settings header holds the payload of a SETTINGS frame in base64url, RFC 7540, section 3.2.1.
+// Clients send their SETTINGS again after the preface, which is what the h2 crate goes by, so the
+// header is only checked.
+fn is_valid_settings(value: &str) -> bool {
+    // The header is a token68, which allows padding even though base64url leaves it out
+    let Ok(payload) = URL_SAFE_NO_PAD.decode(value.trim().trim_end_matches('=')) else {
+        return false;
+    };
+
+    payload.len() % SETTING_SIZE == 0
+        && payload.chunks(SETTING_SIZE).all(|setting| {
+            let id = u16::from_be_bytes([setting[0], setting[1]]);
+            let value = u32::from_be_bytes([setting[2], setting[3], setting[4], setting[5]]);
+
+            match id {
+                SETTINGS_ENABLE_PUSH => value <= 1,
+                SETTINGS_INITIAL_WINDOW_SIZE => value <= MAX_WINDOW_SIZE,
+                SETTINGS_MAX_FRAME_SIZE => (DEFAULT_MAX_FRAME_SIZE as u32..=MAX_FRAME_SIZE).contains(&value),
+                // Unknown settings are ignored
+                _ => true,
+            }
+        })
+}
+
+// Switches to HTTP/2 and answers the request that asked for it on stream 1
+pub(crate) async fn upgrade(connection: &mut Connection, context: &Arc<ConnectionContext>, mut request: Request) {
+    let mut headers = HashMap::new();
+    headers.insert("connection".to_owned(), "Upgrade".to_owned());
+    headers.insert("upgrade".to_owned(), "h2c".to_owned());
+
+    let response = Response::new(StatusCodes::SwitchingProtocols, headers, "");
+    Server::send_response(&response, connection, &context.config).await;
+
+    // The h2 crate has no way to take over a request that arrived over HTTP/1.1, so a HEADERS frame
+    // for stream 1 is slipped in after the client's preface and SETTINGS. Its fields are literals that
+    // leave the HPACK tables alone, and the request already read is what gets routed for stream 1.
+    let client_start = timeout(context.config.header_read_timeout, read_client_start(connection)).await;
+
+    let mut start = match client_start {
+        Ok(Ok(start)) => start,
+        Ok(Err(e)) => {
+            println!("Error(upgrade): {}", e);
+            return;
+        }
+        Err(_) => {
+            println!("Error(upgrade): timed out reading the HTTP/2 connection preface");
+            return;
+        }
+    };
+
+    start.extend(headers_frame(&request));
+    connection.unread(start);
+
+    request.set_version(HTTPVersion::HTTP2);
+    serve(connection, context, Some(request)).await;
+}
+
+// Reads the connection preface and the SETTINGS frame that must follow it
+async fn read_client_start(connection: &mut Connection) -> Result<Vec<u8>, String> {
+    let mut start = vec![0; PREFACE.len() + FRAME_HEADER_SIZE];
+    connection.read_exact(&mut start).await.map_err(|e| e.to_string())?;
+
+    if start[..PREFACE.len()] != PREFACE[..] {
+        return Err("invalid HTTP/2 connection preface".to_owned());
+    }
+
+    let header = &start[PREFACE.len()..];
+    let len = u32::from_be_bytes([0, header[0], header[1], header[2]]) as usize;
+
+    if header[3] != FRAME_TYPE_SETTINGS || len > DEFAULT_MAX_FRAME_SIZE {
+        return Err("the HTTP/2 connection preface is not followed by SETTINGS".to_owned());
+    }
+
+    let settings_start = start.len();
+    start.resize(settings_start + len, 0);
+    connection.read_exact(&mut start[settings_start..]).await.map_err(|e| e.to_string())?;
+
+    Ok(start)
+}
+
+// A HEADERS frame on stream 1 with the pseudo-header fields of the request, which ends the stream
+fn headers_frame(request: &Request) -> Vec<u8> {
+    let block = headers_block(request);
+    let len = (block.len() as u32).to_be_bytes();
+
+    let mut frame = vec![len[1], len[2], len[3], FRAME_TYPE_HEADERS, FLAG_END_STREAM | FLAG_END_HEADERS];
+    frame.extend_from_slice(&1u32.to_be_bytes());
+    frame.extend(block);
+    frame
+}
+
+// The pseudo-header fields of the request, encoded for a HEADERS frame. It has to fit in a single
+// frame of the default maximum size, since the client's SETTINGS may not have arrived yet.
+fn headers_block(request: &Request) -> Vec<u8> {
+    let mut fields = vec![(":method", request.method.as_str()), (":scheme", "http")];
+
+    // Host becomes :authority, RFC 9113, section 8.3.1
+    if let Some(host) = request.headers.get("host") {
+        fields.push((":authority", host));
+    }
+
+    fields.push((":path", &request.uri));
+
+    let mut block = Vec::new();
+
+    for (name, value) in fields {
+        // Literal header field without indexing, with a literal name and no Huffman coding, RFC 7541, section 6.2.2
+        block.push(0x00);
+        encode_string(&mut block, name);
+        encode_string(&mut block, value);
+    }
+
+    block
+}
+
+// A string literal, its length as an integer with a 7-bit prefix followed by its bytes, RFC 7541, section 5
+fn encode_string(block: &mut Vec<u8>, value: &str) {
+    let mut len = value.len();
+
+    if len < 0x7F {
+        block.push(len as u8);
+    } else {
+        block.push(0x7F);
+        len -= 0x7F;
+
+        while len >= 0x80 {
+            block.push((len % 0x80) as u8 | 0x80);
+            len /= 0x80;
+        }
+
+        block.push(len as u8);
+    }
+
+    block.extend_from_slice(value.as_bytes());
+}
+
+// Serves the connection over HTTP/2, each stream in its own task, until the client closes it, it
+// stays idle for too long or the server shuts down. upgraded is the request to answer on stream 1.
+pub(crate) async fn serve(connection: &mut Connection, context: &Arc<ConnectionContext>, mut upgraded: Option<Request>) {
+    let config = &context.config;
+
+    let mut builder = Builder::new();
+    builder
+        .max_concurrent_streams(config.http2_max_concurrent_streams)
+        .initial_window_size(config.http2_initial_stream_window_size)
+        .initial_connection_window_size(config.http2_initial_connection_window_size)
+        .max_header_list_size(config.max_header_size.try_into().unwrap_or(u32::MAX));
+
+    let info = connection.info.clone();
+
+    let mut h2 = match timeout(config.header_read_timeout, builder.handshake::<_, Bytes>(connection)).await {
+        Ok(Ok(h2)) => h2,
+        Ok(Err(e)) => {
+            println!("Error(serve): HTTP/2 handshake failed: {}", e);
+            return;
+        }
+        Err(_) => {
+            println!("Error(serve): timed out during the HTTP/2 handshake");
+            return;
+        }
+    };
+
+    let open_streams = Arc::new(AtomicUsize::new(0));
+    let mut closing = false;
+
+    loop {
+        // Accepting is also what reads and writes the frames of the open streams, so it goes on while closing
+        let accepted = if closing {
+            // Bounded, as closing waits for the client to acknowledge a PING
+            match timeout(config.shutdown_grace_period, h2.accept()).await {
+                Ok(accepted) => accepted,
+                Err(_) => break,
+            }
+        } else {
+            let accept = async { Some(timeout(config.http2_idle_timeout, h2.accept()).await) };
+
+            let next = future::or(accept, async {
+                context.shutdown.wait().await;
+                None
+            })
+            .await;
+
+            match next {
+                Some(Ok(accepted)) => accepted,
+                Some(Err(_)) if open_streams.load(Ordering::SeqCst) > 0 => continue,
+                // Idle for too long, or the server is shutting down. Streams already open are
+                // finished, the client opens new ones on another connection.
+                Some(Err(_)) | None => {
+                    h2.graceful_shutdown();
+                    closing = true;
+                    continue;
+                }
+            }
+        };
+
+        match accepted {
+            Some(Ok((request, respond))) => {
+                // Stream 1 of an upgraded connection is the request that asked for the upgrade
+                let upgraded = match respond.stream_id().as_u32() {
+                    1 => upgraded.take(),
+                    _ => None,
+                };
+
+                let context = context.clone();
+                let info = info.clone();
+                let open_streams = open_streams.clone();
+
+                open_streams.fetch_add(1, Ordering::SeqCst);
+
+                task::spawn(async move {
+                    handle_stream(request, respond, upgraded, &info, &context).await;
+                    open_streams.fetch_sub(1, Ordering::SeqCst);
+                });
+            }
+            Some(Err(e)) => {
+                // Clients going away is how HTTP/2 connections usually end
+                if !e.is_go_away() && !e.is_io() {
+                    println!("Error(serve): {}", e);
+                }
+                break;
+            }
+            None => break,
+        }
+    }
+}
+
+async fn handle_stream(
+    request: http::Request<RecvStream>,
+    respond: SendResponse<Bytes>,
+    upgraded: Option<Request>,
+    info: &ConnectionInfo,
+    context: &ConnectionContext,
+) {
+    let request = match upgraded {
+        Some(request) => Ok(request),
+        None => decode_request(request, &context.config).await,
+    };
+
+    let mut request = match request {
+        Ok(request) => request,
+        Err(status) => {
+            let response = Response::new(status, HashMap::new(), "");
+            return send_response(&response, respond, &context.config).await;
+        }
+    };
+
+    Server::prepare_request(&mut request, info, context);
+
+    // Held until the response is sent
+    let Some(_request_permit) = Server::acquire(&context.request_limit, context.config.overload_policy).await else {
+        let response = Server::overloaded_response(&context.config);
+        return send_response(&response, respond, &context.config).await;
+    };
+
+    // A stream carries a single response, when several routes match only the first one runs
+    let mut response = Server::route_first(&mut request, context).await;
+    response.set_version(HTTPVersion::HTTP2);
+
+    // Event streams go on for as long as they have events, without counting as a request in flight
+    if let Some(events) = response.take_events() {
+        drop(_request_permit);
+        return send_events(&response, events, respond, context).await;
+    }
+
+    send_response(&response, respond, &context.config).await;
+}
+
+// Turns the stream into a Request, with the same limits as requests over HTTP/1
+async fn decode_request(request: http::Request<RecvStream>, config: &ServerConfig) -> Result<Request, StatusCodes> {
+    let (parts, mut body) = request.into_parts();
+
+    // Same as over HTTP/1, unknown methods are answered with 501
+    let Some(method) = HTTPMethod::from(parts.method.as_str()) else {
+        println!("Error(decode_request): unknown method {}", parts.method);
+        return Err(StatusCodes::NotImplemented);
+    };
+
+    if parts.headers.len() > config.max_header_count {
+        println!("Error(decode_request): more than {} headers", config.max_header_count);
+        return Err(StatusCodes::RequestHeaderFieldsTooLarge);
+    }
+
+    let mut headers: HashMap<String, String> = HashMap::with_capacity(parts.headers.len());
+
+    for (name, value) in &parts.headers {
+        let Ok(value) = std::str::from_utf8(value.as_bytes()) else {
+            println!("Error(decode_request): header {} is not valid UTF-8", name);
+            return Err(StatusCodes::BadRequest);
+        };
+
+        // Cookies may be split into several fields to compress better, RFC 9113, section 8.2.3
+        let separator = if name == http::header::COOKIE { "; " } else { ", " };
+
+        headers
+            .entry(name.as_str().to_owned())
+            .and_modify(|values| {
+                values.push_str(separator);
+                values.push_str(value);
+            })
+            .or_insert_with(|| value.to_owned());
+    }
+
+    // The :authority pseudo-header takes the place of host, RFC 9113, section 8.3.1
+    if let Some(authority) = parts.uri.authority() {
+        headers.insert("host".to_owned(), authority.to_string());
+    }
+
+    let uri = parts.uri.path_and_query().map_or("/", |path| path.as_str()).to_owned();
+
+    let mut sink = BodySink::new(&headers, !body.is_end_stream(), config)?;
+
+    // Checked before reading, a body that turns out longer is cut off below. The h2 crate
+    // makes sure the body matches the content-length.
+    let content_length = headers.get("content-length").and_then(|length| length.parse::<usize>().ok());
+    if content_length.is_some_and(|length| length > sink.limit()) {
+        println!("Error(decode_request): body exceeds {} bytes", sink.limit());
+        return Err(StatusCodes::RequestEntityTooLarge);
+    }
+
+    sink.reserve(content_length.unwrap_or(0));
+
+    let read_body = async {
+        while let Some(chunk) = body.data().await {
+            let chunk = chunk.map_err(|e| {
+                println!("Error(decode_request): {}", e);
+                StatusCodes::BadRequest
+            })?;
+
+            // Lets the client send more
+            let _ = body.flow_control().release_capacity(chunk.len());
+
+            sink.write(&chunk).await?;
+        }
+
+        Ok(())
+    };
+
+    match timeout(config.body_read_timeout, read_body).await {
+        Ok(result) => result?,
+        Err(_) => {
+            println!("Error(decode_request): timed out reading the request body");
+            return Err(StatusCodes::RequestTimeout);
+        }
+    }
+
+    let (body, parts) = sink.finish().await?;
+    Server::build_request(method, uri, headers, body, parts, HTTPVersion::HTTP2)
+}
+
+async fn send_response(response: &Response, mut respond: SendResponse<Bytes>, config: &ServerConfig) {
+    let Some(head) = response_head(response, &mut respond) else {
+        return;
+    };
+
+    let body = Bytes::copy_from_slice(response.body().as_bytes());
+
+    let send = async {
+        let mut stream = respond.send_response(head, body.is_empty())?;
+        send_body(&mut stream, body, true).await
+    };
+
+    match timeout(config.write_timeout, send).await {
+        Ok(Ok(())) => {}
+        Ok(Err(e)) => println!("Error(send_response): {}", e),
+        Err(_) => println!("Error(send_response): timed out writing the response"),
+    }
+}
+
+// Sends the head, then the events as they come, until the stream ends, the client resets it
+// or the server shuts down
+async fn send_events(
+    response: &Response,
+    mut events: EventStream,
+    mut respond: SendResponse<Bytes>,
+    context: &ConnectionContext,
+) {
+    let Some(head) = response_head(response, &mut respond) else {
+        return;
+    };
+
+    let mut stream = match respond.send_response(head, false) {
+        Ok(stream) => stream,
+        Err(e) => {
+            println!("Error(send_events): {}", e);
+            return;
+        }
+    };
+
+    while let Some(chunk) = Server::next_event(&mut events, context).await {
+        let send = send_body(&mut stream, Bytes::from(chunk), false);
+
+        if !matches!(timeout(context.config.write_timeout, send).await, Ok(Ok(()))) {
+            stream.send_reset(Reason::CANCEL);
+            return;
+        }
+    }
+
+    let _ = stream.send_data(Bytes::new(), true);
+}
+
+// Resets the stream when the response can't be turned into a valid HTTP/2 head
+fn response_head(response: &Response, respond: &mut SendResponse<Bytes>) -> Option<http::Response<()>> {
+    let mut head = http::Response::builder().status(response.status().as_code() as u16);
+
+    for (name, value) in response.headers() {
+        // Fields about the connection itself are not allowed, RFC 9113, section 8.2.2
+        if matches!(name.as_str(), "connection" | "keep-alive" | "proxy-connection" | "transfer-encoding" | "upgrade") {
+            continue;
+        }
+
+        head = head.header(name.as_str(), value.as_str());
+    }
+
+    for cookie in response.cookies() {
+        head = head.header(http::header::SET_COOKIE, cookie.to_header_value());
+    }
+
+    match head.body(()) {
+        Ok(head) => Some(head),
+        Err(e) => {
+            println!("Error(send_response): {}", e);
+            respond.send_reset(Reason::INTERNAL_ERROR);
+            None
+        }
+    }
+}
+
+// Sends the body as fast as the flow-control windows of the client allow, ending the stream
+// with it unless more is sent afterwards
+async fn send_body(stream: &mut SendStream<Bytes>, mut body: Bytes, end_of_stream: bool) -> Result<(), h2::Error> {
+    while !body.is_empty() {
+        stream.reserve_capacity(body.len());
+
+        let capacity = match future::poll_fn(|cx| stream.poll_capacity(cx)).await {
+            Some(capacity) => capacity?,
+            // The stream was reset by the client
+            None => return Err(Reason::CANCEL.into()),
+        };
+
+        let chunk = body.split_to(capacity.min(body.len()));
+        stream.send_data(chunk, end_of_stream && body.is_empty())?;
+    }
+
+    Ok(())
+}
@@ -1,174 +1,945 @@
+mod body;
+mod config;
+mod connection;
+#[cfg(feature = "http2")]
+mod http2;
+mod proxy_protocol;
+mod shutdown;
+#[cfg(feature = "tls")]
+mod tls;
+#[cfg(feature = "websocket")]
+mod websocket;
+
 // Re-export the local crates as part of this library
 pub use http_types::*;
 pub use route_attribute_macro::*;
+pub use config::*;
 pub use routes::*;
+pub use shutdown::*;
+#[cfg(feature = "tls")]
+pub use tls::TlsConfig;
 
+use async_lock::{Semaphore, SemaphoreGuardArc};
 use async_std::{
-    io::{prelude::BufReadExt, BufReader, ReadExt},
-    net::{TcpListener, TcpStream},
-    stream::StreamExt,
-    task,
+    channel,
+    future::timeout,
+    io::ReadExt,
+    net::{SocketAddr, TcpListener, TcpStream},
+    task::{self, JoinHandle},
+};
+use body::BodySink;
+use connection::{Connection, ConnectionInfo};
+use futures_lite::{future, AsyncWriteExt, StreamExt};
+use socket2::{Domain, Protocol, Socket, Type};
+use std::{
+    collections::HashMap,
+    io::{Error, ErrorKind},
+    sync::Arc,
+    time::Duration,
+    vec,
 };
-use std::{collections::HashMap, io::Error, vec};
+
+// How much is read from a connection at once while waiting for the head of a request
+const READ_CHUNK_SIZE: usize = 4096;
+// How much of a body with a content-length is read at once
+const BODY_CHUNK_SIZE: usize = 64 * 1024;
+// Connections over max_connections that are being answered with 503 at once. Past this they are
+// closed right away, so that a flood of them can't pile up PROXY reads and TLS handshakes.
+const MAX_REJECTED_CONNECTIONS: usize = 64;
 
 pub struct Server {
-    address: String,
-    port: String,
-    listener: Option<TcpListener>,
+    config: ServerConfig,
+    listeners: Vec<TcpListener>,
+    local_addrs: Vec<SocketAddr>,
     routes: Vec<Route>,
+    cookie_keys: Arc<CookieKeys>,
+    sessions: Option<SessionManager>,
+    shutdown: ShutdownHandle,
+    #[cfg(feature = "tls")]
+    tls: Option<Arc<tls::TlsAcceptor>>,
+}
+
+// State shared by every connection while the server is running
+struct ConnectionContext {
+    config: ServerConfig,
+    // Frozen when the server starts, routes added afterwards are only used by the next run
+    routes: Vec<Route>,
+    cookie_keys: Arc<CookieKeys>,
+    sessions: Option<SessionManager>,
+    trusted_proxies: Arc<TrustedProxies>,
+    connection_limit: Option<Arc<Semaphore>>,
+    request_limit: Option<Arc<Semaphore>>,
+    #[cfg(feature = "tls")]
+    tls: Option<Arc<tls::TlsAcceptor>>,
+    // Event streams end, HTTP/2 connections stop taking new streams, and WebSockets are closed,
+    // once the server shuts down
+    shutdown: ShutdownHandle,
 }
 
 impl Server {
     // Public functions
-    async fn decode_request(stream: &TcpStream) -> Result<Request, StatusCodes> {
-        let mut reader = BufReader::new(stream);
-        let mut buf = Vec::new();
+    async fn decode_request(reader: &mut Connection, config: &ServerConfig) -> Result<Request, StatusCodes> {
+        let mut buf = Vec::with_capacity(READ_CHUNK_SIZE);
+        let mut parser = RequestParser::new(ParserLimits {
+            max_request_line_size: config.max_request_line_size,
+            max_header_size: config.max_header_size,
+            max_header_count: config.max_header_count,
+        });
 
-        loop {
-            let bytes_read = reader.read_until(b'\n', &mut buf).await;
+        // The whole head has to arrive within the timeout, not each read of it
+        let Ok(head_size) = timeout(config.header_read_timeout, Server::read_head(reader, &mut buf, &mut parser)).await
+        else {
+            println!("Error(decode_request): timed out reading the request head");
+            return Err(StatusCodes::RequestTimeout);
+        };
+        let head_size = head_size?;
 
-            match bytes_read {
-                Err(e) => {
-                    println!("Error(decode_request): {}", e);
-                    return Err(StatusCodes::BadRequest);
-                }
-                Ok(bytes_read) => {
-                    if bytes_read == 0 {
-                        // This means an abrupt ending to the stream.
-                        return Err(StatusCodes::BadRequest);
-                    }
+        let Some(head) = parser.head(&buf) else {
+            return Err(StatusCodes::BadRequest);
+        };
 
-                    let index = buf.len() - 1;
-                    if index >= 3 && &buf[index - 3..=index] == b"\r\n\r\n" {
-                        // Gracefully ended, break out of the loop
-                        break;
-                    }
-                }
-            }
+        // A method the server does not know, as opposed to one the resource does not support,
+        // RFC 9110, section 15.6.2
+        let Some(method) = HTTPMethod::from(head.method()) else {
+            println!("Error(decode_request): unknown method {}", head.method());
+            return Err(StatusCodes::NotImplemented);
+        };
+
+        // HTTP/1.x versions newer than 1.1 are handled as 1.1, RFC 9110, section 6.2
+        let version = HTTPVersion::from(head.version()).unwrap_or(HTTPVersion::HTTP1_1);
+
+        let mut headers = HashMap::with_capacity(head.header_count());
+
+        for (name, value) in head.headers() {
+            let Ok(value) = std::str::from_utf8(value) else {
+                println!("Error(decode_request): header {} is not valid UTF-8", name);
+                return Err(StatusCodes::BadRequest);
+            };
+
+            headers.insert(name.to_ascii_lowercase(), value.to_owned());
         }
 
-        let Ok(request_string) = String::from_utf8(buf) else {
-            println!("Error(decode_request): could not parse buffer into String");
-            return Err(StatusCodes::BadRequest);
-        };
+        // Requests sent to a proxy use the absolute-form. The authority in it replaces the
+        // host header, and routing only looks at the rest, RFC 9112, section 3.2.2.
+        let uri = match head.target().split_once("://") {
+            Some((_, rest)) if !head.target().starts_with('/') => {
+                let (authority, path) = rest.split_at(rest.find(['/', '?']).unwrap_or(rest.len()));
+                headers.insert("host".to_owned(), authority.to_owned());
 
-        let (request_line, headers_string, _) = Request::get_sections(&request_string)?;
-        let headers = parse_headers(headers_string)?;
+                if path.starts_with('/') {
+                    path.to_owned()
+                } else {
+                    format!("/{}", path)
+                }
+            }
+            _ => head.target().to_owned(),
+        };
 
         let content_length_string = match headers.get("content-length") {
             Some(content_length) => content_length,
             None => "0",
         };
 
-        let Ok(content_length) = content_length_string.parse::<usize>() else {
-            println!("Error(decode_request): invalid content-length header format");
-            return Err(StatusCodes::BadRequest);
+        // Only digits, since parse would also take a leading plus sign
+        let content_length = match content_length_string.parse::<usize>() {
+            Ok(content_length) if content_length_string.bytes().all(|b| b.is_ascii_digit()) => content_length,
+            _ => {
+                println!("Error(decode_request): invalid content-length header format");
+                return Err(StatusCodes::BadRequest);
+            }
         };
 
-        let mut body_buffer = vec![0; content_length];
+        // The parser already made sure Content-Length is not sent along with chunked
+        let mut sink = BodySink::new(&headers, head.is_chunked() || content_length > 0, config)?;
 
-        let Ok(_) = reader.read_exact(&mut body_buffer).await else {
-            println!("Error(decode_request): could not read body");
-            return Err(StatusCodes::BadRequest);
+        if head.is_chunked() {
+            Server::read_chunked_body(reader, &buf[head_size..], &mut sink, config).await?;
+        } else {
+            // Checked before allocating, since the length comes straight from the client
+            if content_length > sink.limit() {
+                println!("Error(decode_request): body of {} bytes exceeds {} bytes", content_length, sink.limit());
+                return Err(StatusCodes::RequestEntityTooLarge);
+            }
+
+            Server::read_body(reader, &buf[head_size..], content_length, &mut sink, config).await?;
+        }
+
+        let (body, parts) = sink.finish().await?;
+        Server::build_request(method, uri, headers, body, parts, version)
+    }
+
+    // Reads a body of known length into the sink, starting with the part that was read along with
+    // the head. The whole body has to arrive within the timeout, not each read of it.
+    async fn read_body(
+        reader: &mut Connection,
+        buffered: &[u8],
+        content_length: usize,
+        sink: &mut BodySink,
+        config: &ServerConfig,
+    ) -> Result<(), StatusCodes> {
+        let buffered = &buffered[..buffered.len().min(content_length)];
+        let mut remaining = content_length - buffered.len();
+
+        sink.reserve(content_length);
+
+        let read = async {
+            sink.write(buffered).await?;
+
+            let mut chunk = vec![0; remaining.min(BODY_CHUNK_SIZE)];
+
+            while remaining > 0 {
+                let size = remaining.min(chunk.len());
+
+                let read = match reader.read(&mut chunk[..size]).await {
+                    Ok(0) | Err(_) => {
+                        println!("Error(decode_request): could not read body");
+                        return Err(StatusCodes::BadRequest);
+                    }
+                    Ok(read) => read,
+                };
+
+                sink.write(&chunk[..read]).await?;
+                remaining -= read;
+            }
+
+            Ok(())
         };
 
-        let Ok(body) = String::from_utf8(body_buffer) else {
-            println!("Error(decode_request): could not parse body to a string");
-            return Err(StatusCodes::BadRequest);
+        match timeout(config.body_read_timeout, read).await {
+            Ok(result) => result,
+            Err(_) => {
+                println!("Error(decode_request): timed out reading the request body");
+                Err(StatusCodes::RequestTimeout)
+            }
+        }
+    }
+
+    // Decodes a body sent in chunks into the sink, starting with the part that was read along with
+    // the head. The whole body has to arrive within the timeout, not each read of it.
+    async fn read_chunked_body(
+        reader: &mut Connection,
+        buffered: &[u8],
+        sink: &mut BodySink,
+        config: &ServerConfig,
+    ) -> Result<(), StatusCodes> {
+        let mut decoder = ChunkedDecoder::new(sink.limit(), config.max_header_size);
+
+        let read = async {
+            let mut chunk = vec![0; READ_CHUNK_SIZE];
+            let mut input = buffered;
+
+            loop {
+                let status = decoder.decode(input);
+                sink.write(&decoder.take_decoded()).await?;
+
+                match status {
+                    Ok(ChunkedStatus::Complete(_)) => return Ok(()),
+                    Ok(ChunkedStatus::Partial) => {}
+                    Err(e) => {
+                        println!("Error(decode_request): {}", e);
+                        return Err(e.status());
+                    }
+                }
+
+                input = match reader.read(&mut chunk).await {
+                    Ok(0) | Err(_) => {
+                        println!("Error(decode_request): could not read body");
+                        return Err(StatusCodes::BadRequest);
+                    }
+                    Ok(read) => &chunk[..read],
+                };
+            }
         };
-        let (method, uri) = Request::parse_request_line(request_line)?;
-        let search_params = SearchParams::from(UriParser::split_search(&uri).1);
 
-        Ok(Request::new(method, uri, headers, body, search_params))
+        match timeout(config.body_read_timeout, read).await {
+            Ok(result) => result,
+            Err(_) => {
+                println!("Error(decode_request): timed out reading the request body");
+                Err(StatusCodes::RequestTimeout)
+            }
+        }
+    }
+
+    // Checks the target and puts the request together, once the protocol delivered all of it
+    fn build_request(
+        method: HTTPMethod,
+        uri: String,
+        headers: HashMap<String, String>,
+        body: Vec<u8>,
+        parts: Option<Vec<Part>>,
+        version: HTTPVersion,
+    ) -> Result<Request, StatusCodes> {
+        let (path, search_string) = UriParser::split_search(UriParser::extract_fragment(&uri));
+
+        // Reject malformed escapes up front so that handlers can always decode the path
+        if percent_decode(path).is_none() {
+            println!("Error(decode_request): invalid percent-encoding in path.\n{}\n", path);
+            return Err(StatusCodes::BadRequest);
+        }
+
+        let search_params = SearchParams::from(search_string)?;
+
+        let mut request = Request::new(method, uri, headers, body, search_params);
+        request.set_version(version);
+
+        if let Some(parts) = parts {
+            request.set_multipart(parts);
+        }
+
+        Ok(request)
+    }
+
+    // Reads into buf until the parser has the whole head of the request and returns its size.
+    // The parser enforces the size limits, so buf never grows past them by more than a read.
+    async fn read_head(
+        reader: &mut Connection,
+        buf: &mut Vec<u8>,
+        parser: &mut RequestParser,
+    ) -> Result<usize, StatusCodes> {
+        loop {
+            match parser.parse(buf) {
+                Ok(ParseStatus::Complete(head_size)) => return Ok(head_size),
+                Ok(ParseStatus::Partial) => {}
+                Err(e) => {
+                    println!("Error(decode_request): {}", e);
+                    return Err(e.status());
+                }
+            }
+
+            let filled = buf.len();
+            buf.resize(filled + READ_CHUNK_SIZE, 0);
+
+            let read = match reader.read(&mut buf[filled..]).await {
+                Err(e) => {
+                    println!("Error(decode_request): {}", e);
+                    return Err(StatusCodes::BadRequest);
+                }
+                // This means an abrupt ending to the stream.
+                Ok(0) => return Err(StatusCodes::BadRequest),
+                Ok(read) => read,
+            };
+
+            buf.truncate(filled + read);
+        }
     }
 
+    // Listens on 127.0.0.1 with the given port
     pub fn new(port: &str) -> Result<Server, Error> {
-        Ok(Server {
-            address: "127.0.0.1".to_owned(),
-            port: port.to_owned(),
-            listener: None,
-            routes: Vec::new(),
-        })
+        Server::with_address("127.0.0.1", port)
     }
 
     pub fn with_address(address: &str, port: &str) -> Result<Server, Error> {
+        let Ok(port) = port.parse::<u16>() else {
+            return Err(Error::new(ErrorKind::InvalidInput, format!("invalid port: {}", port)));
+        };
+
+        Server::from_config(ServerConfig::builder().listen((address, port)).build()?)
+    }
+
+    pub fn from_config(config: ServerConfig) -> Result<Server, Error> {
+        config.validate()?;
+
         Ok(Server {
-            address: address.to_owned(),
-            port: port.to_owned(),
-            listener: None,
+            config,
+            listeners: Vec::new(),
+            local_addrs: Vec::new(),
             routes: Vec::new(),
+            cookie_keys: CookieKeys::ephemeral(),
+            sessions: None,
+            shutdown: ShutdownHandle::new(),
+            #[cfg(feature = "tls")]
+            tls: None,
         })
     }
 
     // Private functions
-    async fn handle_connection(mut stream: TcpStream, routes: Vec<Route>) {
-        let request = Server::decode_request(&stream).await;
+    // Reads the PROXY header and completes the TLS handshake, when they are enabled.
+    // Returns None when the connection has to be closed without a response.
+    async fn open_connection(stream: TcpStream, context: &ConnectionContext) -> Option<Connection> {
+        let mut remote_addr = stream.peer_addr().ok();
+        let mut local_addr = stream.local_addr().ok();
 
-        match request {
-            Ok(request) => {
-                let route_handlers: Vec<_> = routes
-                    .iter()
-                    .filter(|route| {
-                        route.method == request.method && route.uri_parser.matches(&request.uri)
-                    })
-                    .collect();
+        // The load balancer tells where the connection really came from before the request starts
+        if context.config.proxy_protocol {
+            let header = timeout(context.config.header_read_timeout, proxy_protocol::read_header(&stream));
+
+            match header.await {
+                Ok(Ok(header)) => {
+                    remote_addr = header.source.or(remote_addr);
+                    local_addr = header.destination.or(local_addr);
+                }
+                Ok(Err(e)) => {
+                    println!("Error(open_connection): {}", e);
+                    return None;
+                }
+                Err(_) => {
+                    println!("Error(open_connection): timed out reading the PROXY header");
+                    return None;
+                }
+            }
+        }
 
-                if route_handlers.len() == 0 {
-                    let response = Response::new(StatusCodes::NotFound, HashMap::new(), "");
-                    response.send(&mut stream).await;
-                    return;
+        #[cfg(feature = "tls")]
+        if let Some(tls) = &context.tls {
+            return match timeout(context.config.header_read_timeout, tls.accept(stream)).await {
+                Ok(Ok(stream)) => Some(Connection::tls(stream, remote_addr, local_addr)),
+                Ok(Err(e)) => {
+                    println!("Error(open_connection): TLS handshake failed: {}", e);
+                    None
                 }
+                Err(_) => {
+                    println!("Error(open_connection): timed out during the TLS handshake");
+                    None
+                }
+            };
+        }
+
+        Some(Connection::plain(stream, remote_addr, local_addr))
+    }
+
+    async fn handle_connection(stream: TcpStream, context: Arc<ConnectionContext>) {
+        let Some(connection) = Server::open_connection(stream, &context).await else {
+            return;
+        };
+
+        // WebSockets close the connections they take over themselves
+        let Some(mut connection) = Server::handle_request(connection, &context).await else {
+            return;
+        };
+
+        // Lets TLS clients know the response is complete before the socket closes
+        let _ = timeout(context.config.write_timeout, connection.close()).await;
+    }
+
+    // Returns the connection once the response is sent, or None when a WebSocket took it over
+    async fn handle_request(mut connection: Connection, context: &Arc<ConnectionContext>) -> Option<Connection> {
+        // Clients pick HTTP/2 through ALPN or by starting with its connection preface
+        #[cfg(feature = "http2")]
+        if http2::is_http2(&mut connection, &context.config).await {
+            http2::serve(&mut connection, context, None).await;
+            return Some(connection);
+        }
+
+        let request = Server::decode_request(&mut connection, &context.config).await;
+
+        match request {
+            Ok(mut request) => {
+                #[cfg(feature = "http2")]
+                if http2::is_upgrade(&request, &connection) {
+                    http2::upgrade(&mut connection, context, request).await;
+                    return Some(connection);
+                }
+
+                Server::prepare_request(&mut request, &connection.info, context);
 
-                for route in route_handlers {
-                    let response = (route.handler)(&request);
+                // Responses use the version of the request in their status line
+                let version = request.version();
 
-                    response.send(&mut stream).await;
+                // Held until the response is sent
+                let policy = context.config.overload_policy;
+                let Some(_request_permit) = Server::acquire(&context.request_limit, policy).await else {
+                    let mut response = Server::overloaded_response(&context.config);
+                    response.set_version(version);
+                    Server::send_response(&response, &mut connection, &context.config).await;
+                    return Some(connection);
+                };
+
+                for mut response in Server::route_request(&mut request, context).await {
+                    response.set_version(version);
+
+                    // Accepting a WebSocket handshake hands it the rest of the connection,
+                    // which no longer counts as a request in flight
+                    #[cfg(feature = "websocket")]
+                    if let Some(handler) = response.websocket_handler() {
+                        drop(_request_permit);
+                        websocket::serve(connection, request, response, handler, context).await;
+                        return None;
+                    }
+
+                    // Event streams go on for as long as they have events, without counting as
+                    // a request in flight
+                    if let Some(events) = response.take_events() {
+                        drop(_request_permit);
+                        Server::send_response(&response, &mut connection, &context.config).await;
+                        Server::send_events(events, &mut connection, context).await;
+                        return Some(connection);
+                    }
+
+                    Server::send_response(&response, &mut connection, &context.config).await;
                 }
             }
             Err(status) => {
                 let response = Response::new(status, HashMap::new(), "");
 
-                response.send(&mut stream).await;
+                Server::send_response(&response, &mut connection, &context.config).await;
+            }
+        };
+
+        Some(connection)
+    }
+
+    // Gives the request what it needs from the connection and the server
+    fn prepare_request(request: &mut Request, info: &ConnectionInfo, context: &ConnectionContext) {
+        request.cookie_keys = context.cookie_keys.clone();
+        request.set_addrs(info.remote_addr, info.local_addr);
+        request.set_secure(info.secure);
+        request.set_client_certificate(info.client_certificate.clone());
+        request.trusted_proxies = context.trusted_proxies.clone();
+    }
+
+    // Runs the request through every matching route and returns their responses in order,
+    // or a 404 when no route matches
+    async fn route_request(request: &mut Request, context: &ConnectionContext) -> Vec<Response> {
+        Server::load_session(request, context).await;
+
+        let route_handlers: Vec<_> = context
+            .routes
+            .iter()
+            .filter(|route| {
+                route.method == request.method && route.uri_parser.matches(&request.uri)
+            })
+            .collect();
+
+        if route_handlers.is_empty() {
+            return vec![Response::new(StatusCodes::NotFound, HashMap::new(), "")];
+        }
+
+        let mut responses = Vec::with_capacity(route_handlers.len());
+
+        for route in route_handlers {
+            responses.push(Server::run_route(route, request, context).await);
+        }
+
+        responses
+    }
+
+    // Runs the request through the first matching route only, or returns a 404 when no route
+    // matches. Used where a request can only get a single response.
+    #[cfg(feature = "http2")]
+    async fn route_first(request: &mut Request, context: &ConnectionContext) -> Response {
+        Server::load_session(request, context).await;
+
+        let route = context
+            .routes
+            .iter()
+            .find(|route| route.method == request.method && route.uri_parser.matches(&request.uri));
+
+        match route {
+            Some(route) => Server::run_route(route, request, context).await,
+            None => Response::new(StatusCodes::NotFound, HashMap::new(), ""),
+        }
+    }
+
+    async fn run_route(route: &Route, request: &mut Request, context: &ConnectionContext) -> Response {
+        request.path_params = route.uri_parser.parse(&request.uri).unwrap_or_default();
+
+        let response = (route.handler)(request);
+
+        Server::save_session(request, response, context).await
+    }
+
+    // Session stores may block on I/O, as FileStore does, so they are called on the blocking thread pool
+    async fn load_session(request: &mut Request, context: &ConnectionContext) {
+        let Some(sessions) = &context.sessions else {
+            return;
+        };
+
+        let session = match sessions.session_id(request) {
+            Some(id) => {
+                let sessions = sessions.clone();
+                task::spawn_blocking(move || sessions.load_id(&id)).await
             }
+            None => Session::new(),
+        };
+
+        request.session = Some(session);
+    }
+
+    async fn save_session(request: &mut Request, mut response: Response, context: &ConnectionContext) -> Response {
+        let (Some(sessions), Some(session)) = (&context.sessions, request.session.take()) else {
+            return response;
         };
+
+        let sessions = sessions.clone();
+        let keys = request.cookie_keys.clone();
+
+        let (session, response) = task::spawn_blocking(move || {
+            sessions.save(&session, &mut response, &keys);
+            (session, response)
+        })
+        .await;
+
+        request.session = Some(session);
+        response
+    }
+
+    // Takes a slot from the limit, waiting for one or giving up right away depending on the policy.
+    // Returns None when the request has to be rejected, and Some(None) when there is no limit.
+    async fn acquire(limit: &Option<Arc<Semaphore>>, policy: OverloadPolicy) -> Option<Option<SemaphoreGuardArc>> {
+        let Some(limit) = limit else {
+            return Some(None);
+        };
+
+        match policy {
+            OverloadPolicy::Wait => Some(Some(limit.acquire_arc().await)),
+            OverloadPolicy::Reject => limit.try_acquire_arc().map(Some),
+        }
+    }
+
+    fn overloaded_response(config: &ServerConfig) -> Response {
+        // Retry-After is given in whole seconds
+        let retry_after = config.retry_after.as_secs_f64().ceil().max(1.0) as u64;
+
+        let mut headers = HashMap::new();
+        headers.insert("retry-after".to_owned(), retry_after.to_string());
+
+        Response::new(StatusCodes::ServiceUnavailable, headers, "")
+    }
+
+    // Gives up on clients that do not read the response in time. The connection is closed afterwards.
+    async fn send_response(response: &Response, stream: &mut Connection, config: &ServerConfig) {
+        if timeout(config.write_timeout, response.send(stream)).await.is_err() {
+            println!("Error(send_response): timed out writing the response");
+        }
+    }
+
+    // Writes the events as they come, until the stream ends, the client stops reading or the
+    // server shuts down
+    async fn send_events(mut events: EventStream, stream: &mut Connection, context: &ConnectionContext) {
+        while let Some(chunk) = Server::next_event(&mut events, context).await {
+            let write = async {
+                stream.write_all(chunk.as_bytes()).await?;
+                stream.flush().await
+            };
+
+            if !matches!(timeout(context.config.write_timeout, write).await, Ok(Ok(()))) {
+                return;
+            }
+        }
+    }
+
+    // The next chunk of an event stream, None once it ended or the server shuts down
+    async fn next_event(events: &mut EventStream, context: &ConnectionContext) -> Option<String> {
+        let keep_alive = context.config.sse_keep_alive_interval;
+
+        future::or(events.next_chunk(keep_alive), async {
+            context.shutdown.wait().await;
+            None
+        })
+        .await
+    }
+
+    async fn sweep_sessions(sessions: SessionManager, shutdown: ShutdownHandle) {
+        // Sweep every time the interval elapses without the server shutting down
+        while timeout(sessions.config.sweep_interval, shutdown.wait()).await.is_err() {
+            let store = sessions.store.clone();
+            if let Err(e) = task::spawn_blocking(move || store.sweep()).await {
+                println!("Error(sweep_sessions): {}", e);
+            }
+        }
+    }
+
+    // Public methods
+
+    // Binds every listen address without accepting connections yet, returning the bound addresses.
+    // Useful with port 0, which lets the OS pick a free port.
+    pub async fn bind(&mut self) -> Result<Vec<SocketAddr>, Error> {
+        let mut listeners = Vec::with_capacity(self.config.addresses.len());
+        let mut local_addrs = Vec::with_capacity(self.config.addresses.len());
+
+        for address in &self.config.addresses {
+            let listener = Server::bind_listener(*address, self.config.backlog)?;
+
+            local_addrs.push(listener.local_addr()?);
+            listeners.push(listener);
+        }
+
+        self.listeners = listeners;
+        self.local_addrs = local_addrs.clone();
+
+        Ok(local_addrs)
+    }
+
+    fn bind_listener(address: SocketAddr, backlog: u32) -> Result<TcpListener, Error> {
+        let socket = Socket::new(Domain::for_address(address), Type::STREAM, Some(Protocol::TCP))?;
+
+        // Allow restarting right away while connections of the previous process are in TIME_WAIT
+        #[cfg(unix)]
+        socket.set_reuse_address(true)?;
+
+        // Keep IPv6 listeners from taking the IPv4 port as well, so that both can be bound
+        if address.is_ipv6() {
+            socket.set_only_v6(true)?;
+        }
+
+        socket.bind(&address.into())?;
+        socket.listen(backlog.min(i32::MAX as u32) as i32)?;
+        socket.set_nonblocking(true)?;
+
+        Ok(TcpListener::from(std::net::TcpListener::from(socket)))
     }
 
-    // Private methods
-    async fn _init(&mut self) -> Result<(), Error> {
-        self.listener = Some(TcpListener::bind(format!("{}:{}", self.address, self.port)).await?);
+    // The first address the server is bound to, once bind, run or start was called
+    pub fn local_addr(&self) -> Option<SocketAddr> {
+        self.local_addrs.first().copied()
+    }
+
+    pub fn local_addrs(&self) -> &[SocketAddr] {
+        &self.local_addrs
+    }
+
+    pub fn config(&self) -> &ServerConfig {
+        &self.config
+    }
+
+    // Serves requests until the server is shut down, either through a ShutdownHandle or SIGINT/SIGTERM,
+    // and every in-flight connection finished or the grace period elapsed. Binds first if needed.
+    pub async fn run(&mut self) -> Result<(), Error> {
+        if self.listeners.is_empty() {
+            self.bind().await?;
+        }
+
+        #[cfg(unix)]
+        let signals = if self.config.handle_signals {
+            Some(shutdown_on_signals(self.shutdown.clone())?)
+        } else {
+            None
+        };
 
         self.listen().await;
 
+        #[cfg(unix)]
+        if let Some(signals) = signals {
+            signals.close();
+        }
+
         Ok(())
     }
 
-    // Public methods
-    pub fn start(&mut self) {
-        match task::block_on(self._init()) {
-            Ok(_) => {}
-            Err(e) => {
-                println!("Error(init): {}", e);
-            }
+    // Same as run, but blocks the current thread
+    pub fn start(&mut self) -> Result<(), Error> {
+        task::block_on(self.run())
+    }
+
+    // Binds and runs the server in a background task
+    pub async fn spawn(mut self) -> Result<SpawnedServer, Error> {
+        if self.listeners.is_empty() {
+            self.bind().await?;
         }
+
+        let local_addrs = self.local_addrs.clone();
+        let shutdown = self.shutdown.clone();
+        let task = task::spawn(async move { self.run().await });
+
+        Ok(SpawnedServer {
+            local_addrs,
+            shutdown,
+            task,
+        })
+    }
+
+    pub fn shutdown_handle(&self) -> ShutdownHandle {
+        self.shutdown.clone()
+    }
+
+    // How long in-flight connections are given to finish once the server is shutting down
+    pub fn set_shutdown_grace_period(&mut self, grace_period: Duration) {
+        self.config.shutdown_grace_period = grace_period;
+    }
+
+    // Whether SIGINT and SIGTERM shut the server down gracefully. Enabled by default.
+    pub fn set_handle_signals(&mut self, handle_signals: bool) {
+        self.config.handle_signals = handle_signals;
+    }
+
+    // Expects every connection to start with a PROXY protocol header, as sent by load balancers
+    // such as HAProxy, and uses the addresses in it for the request. Disabled by default.
+    pub fn set_proxy_protocol(&mut self, proxy_protocol: bool) {
+        self.config.proxy_protocol = proxy_protocol;
+    }
+
+    // Trusts the forwarding headers sent by these peers, of the family set on trusted_proxies. Untrusted by default.
+    pub fn set_trusted_proxies(&mut self, trusted_proxies: TrustedProxies) {
+        self.config.trusted_proxies = trusted_proxies;
+    }
+
+    // Serves HTTPS instead of HTTP. The certificates are loaded right away, so that missing or
+    // invalid files are reported here rather than on the first connection.
+    #[cfg(feature = "tls")]
+    pub fn set_tls(&mut self, config: TlsConfig) -> Result<(), Error> {
+        self.tls = Some(Arc::new(tls::TlsAcceptor::new(config)?));
+        Ok(())
+    }
+
+    // Sets the keys used for signed and private cookies. Random keys are used if none are set.
+    pub fn set_cookie_keys(&mut self, keys: CookieKeys) {
+        self.cookie_keys = Arc::new(keys);
+    }
+
+    // Enables sessions, which are then available to handlers as Request::session
+    pub fn set_session_store(&mut self, store: impl SessionStore + 'static, config: SessionConfig) {
+        self.sessions = Some(SessionManager::new(Arc::new(store), config));
     }
 
     pub fn add_routes(&mut self, routes: &mut Vec<Route>) {
         self.routes.append(routes)
     }
 
-    async fn listen(&self) {
-        let mut incoming = self.listener.as_ref().unwrap().incoming();
+    async fn listen(&mut self) {
+        let listeners = std::mem::take(&mut self.listeners);
+
+        // Accept from every listener as connections come in
+        let Some(mut incoming) = listeners
+            .iter()
+            .map(|listener| listener.incoming().boxed())
+            .reduce(|merged, incoming| merged.or(incoming).boxed())
+        else {
+            return;
+        };
+
+        if let Some(sessions) = &self.sessions {
+            task::spawn(Server::sweep_sessions(sessions.clone(), self.shutdown.clone()));
+        }
+
+        #[cfg(feature = "tls")]
+        if let Some(tls) = &self.tls {
+            task::spawn(tls::watch_certificates(tls.clone(), self.shutdown.clone()));
+        }
+
+        let context = Arc::new(ConnectionContext {
+            config: self.config.clone(),
+            routes: self.routes.clone(),
+            cookie_keys: self.cookie_keys.clone(),
+            sessions: self.sessions.clone(),
+            trusted_proxies: Arc::new(self.config.trusted_proxies.clone()),
+            connection_limit: self.config.max_connections.map(|max| Arc::new(Semaphore::new(max))),
+            request_limit: self.config.max_in_flight_requests.map(|max| Arc::new(Semaphore::new(max))),
+            #[cfg(feature = "tls")]
+            tls: self.tls.clone(),
+            shutdown: self.shutdown.clone(),
+        });
+
+        // Every connection task holds a sender, so the receiver is closed once all of them finished
+        let (connections, drained) = channel::bounded::<()>(1);
+        let rejections = Arc::new(Semaphore::new(MAX_REJECTED_CONNECTIONS));
+
+        loop {
+            // When waiting for a free slot, stop accepting until there is one
+            let waiting_permit = match (&context.connection_limit, self.config.overload_policy) {
+                (Some(limit), OverloadPolicy::Wait) => {
+                    let permit = future::or(async { Some(limit.acquire_arc().await) }, async {
+                        self.shutdown.wait().await;
+                        None
+                    })
+                    .await;
+
+                    let Some(permit) = permit else {
+                        break;
+                    };
+
+                    Some(permit)
+                }
+                _ => None,
+            };
+
+            let stream = future::or(async { incoming.next().await }, async {
+                self.shutdown.wait().await;
+                None
+            })
+            .await;
+
+            let Some(stream) = stream else {
+                break;
+            };
 
-        while let Some(stream) = incoming.next().await {
             match stream {
                 Ok(stream) => {
-                    let handlers_clone = self.routes.clone();
-                    task::spawn(Server::handle_connection(stream, handlers_clone));
+                    let connection_guard = connections.clone();
+
+                    let permit = match waiting_permit {
+                        Some(permit) => Some(permit),
+                        None => match &context.connection_limit {
+                            Some(limit) => limit.try_acquire_arc(),
+                            None => None,
+                        },
+                    };
+
+                    // Only possible when rejecting, as waiting already got a permit
+                    if permit.is_none() && context.connection_limit.is_some() {
+                        let Some(rejection) = rejections.try_acquire_arc() else {
+                            continue;
+                        };
+
+                        let context = context.clone();
+
+                        task::spawn(async move {
+                            if let Some(mut connection) = Server::open_connection(stream, &context).await {
+                                let response = Server::overloaded_response(&context.config);
+                                Server::send_response(&response, &mut connection, &context.config).await;
+                                let _ = timeout(context.config.write_timeout, connection.close()).await;
+                            }
+
+                            drop(rejection);
+                            drop(connection_guard);
+                        });
+
+                        continue;
+                    }
+
+                    let connection = Server::handle_connection(stream, context.clone());
+
+                    task::spawn(async move {
+                        connection.await;
+                        drop(permit);
+                        drop(connection_guard);
+                    });
                 }
                 Err(error) => {
                     println!("Error(listen): {}", error);
                 }
             };
         }
+
+        // Close the socket right away so that new clients are refused instead of left waiting
+        drop(incoming);
+        drop(listeners);
+        drop(connections);
+
+        if timeout(self.config.shutdown_grace_period, drained.recv()).await.is_err() {
+            println!("Error(listen): grace period elapsed with connections still open");
+        }
+    }
+}
+
+// A server running in a background task, returned by Server::spawn
+pub struct SpawnedServer {
+    local_addrs: Vec<SocketAddr>,
+    shutdown: ShutdownHandle,
+    task: JoinHandle<Result<(), Error>>,
+}
+
+impl SpawnedServer {
+    // The first address the server is bound to
+    pub fn local_addr(&self) -> SocketAddr {
+        self.local_addrs[0]
+    }
+
+    pub fn local_addrs(&self) -> &[SocketAddr] {
+        &self.local_addrs
+    }
+
+    pub fn shutdown_handle(&self) -> ShutdownHandle {
+        self.shutdown.clone()
+    }
+
+    // Shuts the server down and waits for it to drain
+    pub async fn shutdown(self) -> Result<(), Error> {
+        self.shutdown.shutdown();
+        self.task.await
+    }
+
+    // Waits for the server to stop without asking it to
+    pub async fn join(self) -> Result<(), Error> {
+        self.task.await
     }
 }
@@ -0,0 +1,366 @@
+use std::{
+    io::{Error, ErrorKind},
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+};
+
+use async_std::{io::ReadExt, net::TcpStream};
+
+const V2_SIGNATURE: &[u8; 12] = b"\r\n\r\n\0\r\nQUIT\n";
+// The longest possible v1 header, "PROXY TCP6" with two full IPv6 addresses and ports
+const V1_MAX_SIZE: usize = 107;
+const V2_HEADER_SIZE: usize = 16;
+
+// Addresses of the original connection, as reported by the load balancer. Both are None
+// for health checks and connections the load balancer makes on its own behalf.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct ProxyHeader {
+    pub source: Option<SocketAddr>,
+    pub destination: Option<SocketAddr>,
+}
+
+// Reads the PROXY protocol header that precedes the request, either the v1 text or the v2
+// binary format. Nothing past the header is read, so a TLS handshake can follow it.
+pub(crate) async fn read_header(mut stream: &TcpStream) -> Result<ProxyHeader, Error> {
+    let mut buf = vec![0; 6];
+    stream.read_exact(&mut buf).await?;
+
+    if buf == b"PROXY " {
+        // Byte by byte, as the line is short and anything after it belongs to the connection
+        while !buf.ends_with(b"\r\n") {
+            if buf.len() >= V1_MAX_SIZE {
+                return Err(invalid_header("PROXY v1 header too long"));
+            }
+
+            let mut byte = [0];
+            stream.read_exact(&mut byte).await?;
+            buf.push(byte[0]);
+        }
+
+        return parse_v1(&buf);
+    }
+
+    if buf[..] != V2_SIGNATURE[..6] {
+        return Err(invalid_header("missing PROXY header"));
+    }
+
+    buf.resize(V2_HEADER_SIZE, 0);
+    stream.read_exact(&mut buf[6..]).await?;
+
+    let len = u16::from_be_bytes([buf[14], buf[15]]) as usize;
+    buf.resize(V2_HEADER_SIZE + len, 0);
+    stream.read_exact(&mut buf[V2_HEADER_SIZE..]).await?;
+
+    parse_v2(&buf)
+}
+
+// PROXY TCP4 192.0.2.1 198.51.100.1 56324 443\r\n
+fn parse_v1(buf: &[u8]) -> Result<ProxyHeader, Error> {
+    if buf.len() > V1_MAX_SIZE {
+        return Err(invalid_header("PROXY v1 header too long"));
+    }
+
+    let Some(line) = buf.strip_suffix(b"\r\n") else {
+        return Err(invalid_header("truncated PROXY v1 header"));
+    };
+
+    let Ok(line) = std::str::from_utf8(line) else {
+        return Err(invalid_header("PROXY v1 header is not ASCII"));
+    };
+
+    let parts: Vec<&str> = line.split(' ').collect();
+
+    let header = match parts.as_slice() {
+        // The rest of the line is to be ignored for UNKNOWN
+        ["PROXY", "UNKNOWN", ..] => ProxyHeader {
+            source: None,
+            destination: None,
+        },
+        ["PROXY", protocol @ ("TCP4" | "TCP6"), source, destination, source_port, destination_port] => {
+            let parse = |ip: &str, port: &str| -> Option<SocketAddr> {
+                let ip: IpAddr = match *protocol {
+                    "TCP4" => IpAddr::V4(ip.parse().ok()?),
+                    _ => IpAddr::V6(ip.parse().ok()?),
+                };
+
+                // Digits only, without leading zeros
+                if port.is_empty() || !port.bytes().all(|b| b.is_ascii_digit()) || (port.len() > 1 && port.starts_with('0'))
+                {
+                    return None;
+                }
+
+                Some(SocketAddr::new(ip, port.parse().ok()?))
+            };
+
+            let (Some(source), Some(destination)) = (parse(source, source_port), parse(destination, destination_port))
+            else {
+                return Err(invalid_header("invalid address in PROXY v1 header"));
+            };
+
+            ProxyHeader {
+                source: Some(source),
+                destination: Some(destination),
+            }
+        }
+        _ => return Err(invalid_header("malformed PROXY v1 header")),
+    };
+
+    Ok(header)
+}
+
+// 12 bytes of signature, version and command, address family and protocol, the length of the
+// rest in big endian, then the addresses and optional TLVs, which are skipped
+fn parse_v2(buf: &[u8]) -> Result<ProxyHeader, Error> {
+    if !buf.starts_with(V2_SIGNATURE) {
+        return Err(invalid_header("missing PROXY header"));
+    }
+
+    if buf.len() < V2_HEADER_SIZE {
+        return Err(invalid_header("truncated PROXY v2 header"));
+    }
+
+    let version = buf[12] >> 4;
+    let command = buf[12] & 0x0F;
+    let family = buf[13];
+    let len = u16::from_be_bytes([buf[14], buf[15]]) as usize;
+
+    if version != 2 {
+        return Err(invalid_header("unsupported PROXY protocol version"));
+    }
+
+    if buf.len() != V2_HEADER_SIZE + len {
+        return Err(invalid_header("truncated PROXY v2 header"));
+    }
+
+    let addresses = &buf[V2_HEADER_SIZE..V2_HEADER_SIZE + len];
+    let port = |offset: usize| u16::from_be_bytes([addresses[offset], addresses[offset + 1]]);
+
+    let header = match (command, family) {
+        // LOCAL, the addresses are to be ignored
+        (0x0, _) => ProxyHeader {
+            source: None,
+            destination: None,
+        },
+        // PROXY over TCP or UDP on IPv4
+        (0x1, 0x11 | 0x12) if len >= 12 => {
+            let source = Ipv4Addr::from(<[u8; 4]>::try_from(&addresses[0..4]).unwrap_or_default());
+            let destination = Ipv4Addr::from(<[u8; 4]>::try_from(&addresses[4..8]).unwrap_or_default());
+
+            ProxyHeader {
+                source: Some(SocketAddr::new(source.into(), port(8))),
+                destination: Some(SocketAddr::new(destination.into(), port(10))),
+            }
+        }
+        // PROXY over TCP or UDP on IPv6
+        (0x1, 0x21 | 0x22) if len >= 36 => {
+            let source = Ipv6Addr::from(<[u8; 16]>::try_from(&addresses[0..16]).unwrap_or_default());
+            let destination = Ipv6Addr::from(<[u8; 16]>::try_from(&addresses[16..32]).unwrap_or_default());
+
+            ProxyHeader {
+                source: Some(SocketAddr::new(source.into(), port(32))),
+                destination: Some(SocketAddr::new(destination.into(), port(34))),
+            }
+        }
+        // Unix sockets and unspecified families carry no usable address
+        (0x1, 0x00 | 0x31 | 0x32) => ProxyHeader {
+            source: None,
+            destination: None,
+        },
+        _ => return Err(invalid_header("malformed PROXY v2 header")),
+    };
+
+    Ok(header)
+}
+
+fn invalid_header(message: &str) -> Error {
+    Error::new(ErrorKind::InvalidData, message)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use async_std::{io::WriteExt, net::TcpListener, task};
+
+    fn addr(addr: &str) -> Option<SocketAddr> {
+        Some(addr.parse().unwrap())
+    }
+
+    // A v2 header with the command, family and the bytes that follow the fixed part
+    fn v2(command: u8, family: u8, rest: &[u8]) -> Vec<u8> {
+        let mut buf = V2_SIGNATURE.to_vec();
+        buf.push(0x20 | command);
+        buf.push(family);
+        buf.extend_from_slice(&(rest.len() as u16).to_be_bytes());
+        buf.extend_from_slice(rest);
+        buf
+    }
+
+    #[test]
+    fn parses_v1_headers() {
+        let header = parse_v1(b"PROXY TCP4 192.0.2.1 198.51.100.1 56324 443\r\n").unwrap();
+        assert_eq!(header.source, addr("192.0.2.1:56324"));
+        assert_eq!(header.destination, addr("198.51.100.1:443"));
+
+        let header = parse_v1(b"PROXY TCP6 2001:db8::1 2001:db8::2 65535 0\r\n").unwrap();
+        assert_eq!(header.source, addr("[2001:db8::1]:65535"));
+        assert_eq!(header.destination, addr("[2001:db8::2]:0"));
+
+        // Whatever follows UNKNOWN is ignored
+        for line in [b"PROXY UNKNOWN\r\n".as_slice(), b"PROXY UNKNOWN ffff::1 ffff::2 1 2\r\n"] {
+            let header = parse_v1(line).unwrap();
+            assert_eq!((header.source, header.destination), (None, None));
+        }
+    }
+
+    #[test]
+    fn rejects_malformed_v1_headers() {
+        for line in [
+            b"PROXY TCP4 192.0.2.1 198.51.100.1 56324\r\n".as_slice(),
+            b"PROXY TCP4 192.0.2.1 198.51.100.1 56324 443 extra\r\n",
+            b"PROXY TCP4 2001:db8::1 198.51.100.1 56324 443\r\n",
+            b"PROXY TCP6 192.0.2.1 2001:db8::2 56324 443\r\n",
+            b"PROXY TCP4 192.0.2.1 198.51.100.1 056324 443\r\n",
+            b"PROXY TCP4 192.0.2.1 198.51.100.1 65536 443\r\n",
+            b"PROXY TCP4 192.0.2.1 198.51.100.1 +1 443\r\n",
+            b"PROXY TCP4  192.0.2.1 198.51.100.1 56324 443\r\n",
+            b"PROXY UDP4 192.0.2.1 198.51.100.1 56324 443\r\n",
+            b"PROXY TCP4 192.0.2.1 198.51.100.1 56324 443\n",
+            b"PROXY TCP4 192.0.2.1 198.51.100.1 56324 443",
+            b"\r\n",
+            b"",
+        ] {
+            assert!(parse_v1(line).is_err(), "{}", String::from_utf8_lossy(line));
+        }
+
+        let mut long = b"PROXY UNKNOWN ".to_vec();
+        long.resize(V1_MAX_SIZE, b'a');
+        long.extend_from_slice(b"\r\n");
+        assert!(parse_v1(&long).is_err());
+    }
+
+    #[test]
+    fn parses_v2_headers() {
+        let mut ipv4 = vec![192, 0, 2, 1, 198, 51, 100, 1];
+        ipv4.extend_from_slice(&56324u16.to_be_bytes());
+        ipv4.extend_from_slice(&443u16.to_be_bytes());
+
+        let header = parse_v2(&v2(0x1, 0x11, &ipv4)).unwrap();
+        assert_eq!(header.source, addr("192.0.2.1:56324"));
+        assert_eq!(header.destination, addr("198.51.100.1:443"));
+
+        let mut ipv6 = "2001:db8::1".parse::<Ipv6Addr>().unwrap().octets().to_vec();
+        ipv6.extend_from_slice(&"2001:db8::2".parse::<Ipv6Addr>().unwrap().octets());
+        ipv6.extend_from_slice(&[0x12, 0x34, 0x01, 0xBB]);
+
+        let header = parse_v2(&v2(0x1, 0x21, &ipv6)).unwrap();
+        assert_eq!(header.source, addr("[2001:db8::1]:4660"));
+        assert_eq!(header.destination, addr("[2001:db8::2]:443"));
+    }
+
+    #[test]
+    fn skips_v2_tlvs() {
+        // An ALPN and an authority TLV after the IPv4 addresses
+        let mut rest = vec![10, 0, 0, 1, 10, 0, 0, 2, 0x1F, 0x90, 0x00, 0x50];
+        rest.extend_from_slice(&[0x01, 0x00, 0x02, b'h', b'2']);
+        rest.extend_from_slice(&[0x02, 0x00, 0x0B]);
+        rest.extend_from_slice(b"example.com");
+
+        let header = parse_v2(&v2(0x1, 0x11, &rest)).unwrap();
+        assert_eq!(header.source, addr("10.0.0.1:8080"));
+        assert_eq!(header.destination, addr("10.0.0.2:80"));
+    }
+
+    #[test]
+    fn ignores_v2_local_addresses() {
+        // Health checks from the load balancer itself, with or without addresses
+        for header in [v2(0x0, 0x00, &[]), v2(0x0, 0x11, &[192, 0, 2, 1, 198, 51, 100, 1, 0, 1, 0, 2])] {
+            let header = parse_v2(&header).unwrap();
+            assert_eq!((header.source, header.destination), (None, None));
+        }
+
+        let header = parse_v2(&v2(0x1, 0x00, &[])).unwrap();
+        assert_eq!((header.source, header.destination), (None, None));
+    }
+
+    #[test]
+    fn rejects_malformed_v2_headers() {
+        // Addresses shorter than the family calls for
+        assert!(parse_v2(&v2(0x1, 0x11, &[192, 0, 2, 1])).is_err());
+        assert!(parse_v2(&v2(0x1, 0x21, &[0; 35])).is_err());
+
+        // Unknown command or family
+        assert!(parse_v2(&v2(0x2, 0x11, &[0; 12])).is_err());
+        assert!(parse_v2(&v2(0x1, 0x41, &[0; 12])).is_err());
+
+        // Version 1 in the binary format
+        let mut header = v2(0x1, 0x11, &[0; 12]);
+        header[12] = 0x11;
+        assert!(parse_v2(&header).is_err());
+
+        // Bad signature
+        let mut header = v2(0x1, 0x11, &[0; 12]);
+        header[4] = b'X';
+        assert!(parse_v2(&header).is_err());
+
+        // Cut off before the end of the fixed part or of the addresses
+        let header = v2(0x1, 0x11, &[0; 12]);
+        assert!(parse_v2(&header[..14]).is_err());
+        assert!(parse_v2(&header[..20]).is_err());
+        assert!(parse_v2(&header[..8]).is_err());
+    }
+
+    // Writes the bytes from a client and reads the header on the server side of the connection
+    async fn read_from(bytes: impl Into<Vec<u8>>) -> (Result<ProxyHeader, Error>, Vec<u8>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        let bytes = bytes.into();
+        let client = task::spawn(async move {
+            let mut client = async_std::net::TcpStream::connect(addr).await.unwrap();
+            client.write_all(&bytes).await.unwrap();
+        });
+
+        let (stream, _) = listener.accept().await.unwrap();
+        client.await;
+
+        let header = read_header(&stream).await;
+
+        // What the connection reads next
+        let mut rest = Vec::new();
+        let _ = (&stream).read_to_end(&mut rest).await;
+
+        (header, rest)
+    }
+
+    #[test]
+    fn reads_only_the_header() {
+        task::block_on(async {
+            let (header, rest) = read_from(b"PROXY TCP4 192.0.2.1 198.51.100.1 56324 443\r\nGET / HTTP/1.1\r\n").await;
+            assert_eq!(header.unwrap().source, addr("192.0.2.1:56324"));
+            assert_eq!(rest, b"GET / HTTP/1.1\r\n");
+
+            let (header, rest) = read_from(b"\r\n\r\n\0\r\nQUIT\n\x20\x00\x00\x00\x16\x03\x01").await;
+            assert_eq!(header.unwrap().source, None);
+            assert_eq!(rest, b"\x16\x03\x01");
+        });
+    }
+
+    #[test]
+    fn rejects_oversized_and_truncated_streams() {
+        task::block_on(async {
+            let (header, _) = read_from(b"GET / HTTP/1.1\r\n").await;
+            assert!(header.is_err());
+
+            // No line break within the longest possible v1 header
+            let (header, _) = read_from([b"PROXY ".as_slice(), &[b'a'; 200]].concat()).await;
+            assert!(header.is_err());
+
+            let (header, _) = read_from(b"PROXY TCP4 192.0.2.1").await;
+            assert!(header.is_err());
+
+            // Announces 12 bytes of addresses and sends 4
+            let (header, _) = read_from(b"\r\n\r\n\0\r\nQUIT\n\x21\x11\x00\x0C\xC0\x00\x02\x01").await;
+            assert!(header.is_err());
+        });
+    }
+}
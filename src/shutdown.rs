@@ -0,0 +1,63 @@
+use async_std::channel::{self, Receiver, Sender};
+
+// Handle used to stop a running server. Once shutdown is called the server stops
+// accepting connections, waits for the in-flight ones to finish and Server::start returns.
+#[derive(Debug, Clone)]
+pub struct ShutdownHandle {
+    // Nothing is ever sent through the channel, closing it is what signals the shutdown
+    sender: Sender<()>,
+    receiver: Receiver<()>,
+}
+
+impl ShutdownHandle {
+    pub fn new() -> ShutdownHandle {
+        let (sender, receiver) = channel::bounded(1);
+
+        ShutdownHandle { sender, receiver }
+    }
+
+    pub fn shutdown(&self) {
+        self.sender.close();
+    }
+
+    pub fn is_shutting_down(&self) -> bool {
+        self.sender.is_closed()
+    }
+
+    // Resolves once shutdown has been called
+    pub async fn wait(&self) {
+        let _ = self.receiver.recv().await;
+    }
+}
+
+impl Default for ShutdownHandle {
+    fn default() -> Self {
+        ShutdownHandle::new()
+    }
+}
+
+// Calls shutdown on the first SIGINT or SIGTERM, and exits the process right away on the second one.
+// The returned handle unregisters the signal handlers when closed.
+#[cfg(unix)]
+pub(crate) fn shutdown_on_signals(
+    shutdown: ShutdownHandle,
+) -> Result<signal_hook::iterator::Handle, std::io::Error> {
+    use signal_hook::consts::{SIGINT, SIGTERM};
+
+    let mut signals = signal_hook::iterator::Signals::new([SIGINT, SIGTERM])?;
+    let handle = signals.handle();
+
+    std::thread::spawn(move || {
+        for signal in signals.forever() {
+            if shutdown.is_shutting_down() {
+                println!("Received signal {} again, exiting", signal);
+                std::process::exit(128 + signal);
+            }
+
+            println!("Received signal {}, shutting down", signal);
+            shutdown.shutdown();
+        }
+    });
+
+    Ok(handle)
+}
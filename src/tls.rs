@@ -0,0 +1,330 @@
+use std::{
+    collections::HashMap,
+    fs::{self, File},
+    io::{BufReader, Error, ErrorKind},
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex, RwLock},
+    time::{Duration, SystemTime},
+};
+
+use async_std::{future::timeout, net::TcpStream, task};
+use futures_rustls::{
+    rustls::{
+        crypto::ring::{default_provider, sign::any_supported_type},
+        pki_types::CertificateDer,
+        server::{ClientHello, ResolvesServerCert, WebPkiClientVerifier},
+        sign::CertifiedKey,
+        RootCertStore, ServerConfig,
+    },
+    server::TlsStream,
+};
+use http_types::ClientCertificate;
+use x509_parser::{certificate::X509Certificate, extensions::GeneralName, prelude::FromDer};
+
+use crate::shutdown::ShutdownHandle;
+
+#[cfg(feature = "http2")]
+const DEFAULT_ALPN_PROTOCOLS: &[&str] = &["h2", "http/1.1"];
+#[cfg(not(feature = "http2"))]
+const DEFAULT_ALPN_PROTOCOLS: &[&str] = &["http/1.1"];
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct CertificatePaths {
+    cert: PathBuf,
+    key: PathBuf,
+}
+
+// Certificates and protocols used to terminate TLS. Each certificate is a PEM file with the
+// chain, leaf first, and a PEM file with its private key. The files are watched and
+// reloaded when they change, so certificates can be renewed without a restart.
+#[derive(Debug, Clone)]
+pub struct TlsConfig {
+    // Used when the client sends no server name, or one without a certificate of its own
+    default_certificate: Option<CertificatePaths>,
+    // Selected by the server name the client sends (SNI)
+    sni_certificates: Vec<(String, CertificatePaths)>,
+    // PEM bundle of the CAs client certificates must be issued by, for mutual TLS
+    client_ca: Option<PathBuf>,
+    alpn_protocols: Vec<String>,
+    reload_interval: Duration,
+}
+
+impl Default for TlsConfig {
+    fn default() -> Self {
+        TlsConfig {
+            default_certificate: None,
+            sni_certificates: Vec::new(),
+            client_ca: None,
+            alpn_protocols: DEFAULT_ALPN_PROTOCOLS.iter().map(|protocol| protocol.to_string()).collect(),
+            reload_interval: Duration::from_secs(60),
+        }
+    }
+}
+
+impl TlsConfig {
+    // Uses the certificate for every connection
+    pub fn new(cert_path: impl Into<PathBuf>, key_path: impl Into<PathBuf>) -> TlsConfig {
+        TlsConfig::default().certificate(cert_path, key_path)
+    }
+
+    pub fn certificate(mut self, cert_path: impl Into<PathBuf>, key_path: impl Into<PathBuf>) -> TlsConfig {
+        self.default_certificate = Some(CertificatePaths {
+            cert: cert_path.into(),
+            key: key_path.into(),
+        });
+        self
+    }
+
+    // Uses the certificate for clients asking for the hostname. A leading "*." matches
+    // a single label, e.g. "*.example.com" matches "api.example.com".
+    pub fn sni_certificate(
+        mut self,
+        hostname: &str,
+        cert_path: impl Into<PathBuf>,
+        key_path: impl Into<PathBuf>,
+    ) -> TlsConfig {
+        let paths = CertificatePaths {
+            cert: cert_path.into(),
+            key: key_path.into(),
+        };
+
+        self.sni_certificates.push((hostname.to_ascii_lowercase(), paths));
+        self
+    }
+
+    // Requires every client to present a certificate issued by one of the CAs in the bundle.
+    // Handshakes without one, or with one that doesn't verify, fail before any request is read.
+    pub fn require_client_certificate(mut self, ca_bundle_path: impl Into<PathBuf>) -> TlsConfig {
+        self.client_ca = Some(ca_bundle_path.into());
+        self
+    }
+
+    // Protocols offered through ALPN, in order of preference. Defaults to "http/1.1",
+    // preceded by "h2" with the http2 feature.
+    pub fn alpn_protocols(mut self, protocols: &[&str]) -> TlsConfig {
+        self.alpn_protocols = protocols.iter().map(|protocol| protocol.to_string()).collect();
+        self
+    }
+
+    // How often the certificate files are checked for changes
+    pub fn reload_interval(mut self, reload_interval: Duration) -> TlsConfig {
+        self.reload_interval = reload_interval;
+        self
+    }
+
+    fn paths(&self) -> impl Iterator<Item = &PathBuf> {
+        self.default_certificate
+            .iter()
+            .chain(self.sni_certificates.iter().map(|(_, paths)| paths))
+            .flat_map(|paths| [&paths.cert, &paths.key])
+            .chain(self.client_ca.iter())
+    }
+}
+
+#[derive(Debug, Default)]
+struct Certificates {
+    default: Option<Arc<CertifiedKey>>,
+    by_name: HashMap<String, Arc<CertifiedKey>>,
+}
+
+// Picks the certificate for the server name of each handshake
+#[derive(Debug)]
+struct CertificateResolver {
+    certificates: Certificates,
+}
+
+impl ResolvesServerCert for CertificateResolver {
+    fn resolve(&self, client_hello: ClientHello<'_>) -> Option<Arc<CertifiedKey>> {
+        let certificates = &self.certificates;
+
+        let by_name = client_hello.server_name().and_then(|name| {
+            let name = name.to_ascii_lowercase();
+            let wildcard = name.split_once('.').map(|(_, parent)| format!("*.{}", parent));
+
+            certificates
+                .by_name
+                .get(&name)
+                .or_else(|| certificates.by_name.get(&wildcard?))
+        });
+
+        by_name.or(certificates.default.as_ref()).cloned()
+    }
+}
+
+pub(crate) struct TlsAcceptor {
+    config: TlsConfig,
+    // Replaced when the files are reloaded, handshakes already under way keep the previous one
+    acceptor: RwLock<futures_rustls::TlsAcceptor>,
+    // Modification times of the files when they were last loaded
+    modified: Mutex<Vec<Option<SystemTime>>>,
+}
+
+impl TlsAcceptor {
+    pub fn new(config: TlsConfig) -> Result<TlsAcceptor, Error> {
+        let modified = modification_times(&config);
+        let acceptor = load_acceptor(&config)?;
+
+        Ok(TlsAcceptor {
+            config,
+            acceptor: RwLock::new(acceptor),
+            modified: Mutex::new(modified),
+        })
+    }
+
+    pub async fn accept(&self, stream: TcpStream) -> Result<TlsStream<TcpStream>, Error> {
+        let acceptor = self.acceptor.read().unwrap_or_else(|e| e.into_inner()).clone();
+        acceptor.accept(stream).await
+    }
+
+    // Loads the files again if any of them changed. A failed reload keeps the current
+    // certificates and is tried again on the next check, e.g. if a file was half-written.
+    fn reload_if_changed(&self) {
+        let modified = modification_times(&self.config);
+        let mut last_modified = self.modified.lock().unwrap_or_else(|e| e.into_inner());
+
+        if *last_modified == modified {
+            return;
+        }
+
+        match load_acceptor(&self.config) {
+            Ok(acceptor) => {
+                *self.acceptor.write().unwrap_or_else(|e| e.into_inner()) = acceptor;
+                *last_modified = modified;
+                println!("Reloaded TLS certificates");
+            }
+            Err(e) => println!("Error(reload_if_changed): {}", e),
+        }
+    }
+}
+
+// Periodically reloads the certificates that changed on disk, until the server shuts down
+pub(crate) async fn watch_certificates(tls: Arc<TlsAcceptor>, shutdown: ShutdownHandle) {
+    while timeout(tls.config.reload_interval, shutdown.wait()).await.is_err() {
+        let tls = tls.clone();
+        task::spawn_blocking(move || tls.reload_if_changed()).await;
+    }
+}
+
+// The verified certificate of the client, for connections that required one
+pub(crate) fn client_certificate(stream: &TlsStream<TcpStream>) -> Option<Arc<ClientCertificate>> {
+    let der = stream.get_ref().1.peer_certificates()?.first()?;
+
+    let Ok((_, certificate)) = X509Certificate::from_der(der) else {
+        println!("Error(client_certificate): could not parse the client certificate");
+        return None;
+    };
+
+    let mut client_certificate = ClientCertificate {
+        subject: certificate.subject().to_string(),
+        common_name: certificate.subject().iter_common_name().next().and_then(|cn| cn.as_str().ok()).map(str::to_owned),
+        serial_number: certificate.raw_serial_as_string(),
+        der: der.to_vec(),
+        ..ClientCertificate::default()
+    };
+
+    if let Ok(Some(alt_names)) = certificate.subject_alternative_name() {
+        for name in &alt_names.value.general_names {
+            match name {
+                GeneralName::DNSName(dns_name) => client_certificate.dns_names.push(dns_name.to_string()),
+                GeneralName::URI(uri) => client_certificate.uris.push(uri.to_string()),
+                GeneralName::RFC822Name(email) => client_certificate.emails.push(email.to_string()),
+                GeneralName::IPAddress(ip) => match ip.len() {
+                    4 => client_certificate.ip_addresses.push(<[u8; 4]>::try_from(*ip).unwrap_or_default().into()),
+                    16 => client_certificate.ip_addresses.push(<[u8; 16]>::try_from(*ip).unwrap_or_default().into()),
+                    _ => {}
+                },
+                _ => {}
+            }
+        }
+    }
+
+    Some(Arc::new(client_certificate))
+}
+
+fn load_acceptor(config: &TlsConfig) -> Result<futures_rustls::TlsAcceptor, Error> {
+    let provider = Arc::new(default_provider());
+    let resolver = Arc::new(CertificateResolver {
+        certificates: load_certificates(config)?,
+    });
+
+    let builder = ServerConfig::builder_with_provider(provider.clone())
+        .with_safe_default_protocol_versions()
+        .map_err(Error::other)?;
+
+    let builder = match &config.client_ca {
+        Some(ca_path) => {
+            let mut roots = RootCertStore::empty();
+
+            for ca in load_pem_certificates(ca_path)? {
+                roots.add(ca).map_err(|e| with_path(ca_path, Error::new(ErrorKind::InvalidData, e)))?;
+            }
+
+            let verifier = WebPkiClientVerifier::builder_with_provider(Arc::new(roots), provider)
+                .build()
+                .map_err(|e| with_path(ca_path, Error::new(ErrorKind::InvalidData, e)))?;
+
+            builder.with_client_cert_verifier(verifier)
+        }
+        None => builder.with_no_client_auth(),
+    };
+
+    let mut server_config = builder.with_cert_resolver(resolver);
+    server_config.alpn_protocols = config.alpn_protocols.iter().map(|p| p.as_bytes().to_vec()).collect();
+
+    Ok(futures_rustls::TlsAcceptor::from(Arc::new(server_config)))
+}
+
+fn modification_times(config: &TlsConfig) -> Vec<Option<SystemTime>> {
+    config
+        .paths()
+        .map(|path| fs::metadata(path).and_then(|metadata| metadata.modified()).ok())
+        .collect()
+}
+
+fn load_certificates(config: &TlsConfig) -> Result<Certificates, Error> {
+    if config.default_certificate.is_none() && config.sni_certificates.is_empty() {
+        return Err(Error::new(ErrorKind::InvalidInput, "no TLS certificate configured"));
+    }
+
+    let mut certificates = Certificates::default();
+
+    if let Some(paths) = &config.default_certificate {
+        certificates.default = Some(load_certified_key(paths)?);
+    }
+
+    for (hostname, paths) in &config.sni_certificates {
+        certificates.by_name.insert(hostname.clone(), load_certified_key(paths)?);
+    }
+
+    Ok(certificates)
+}
+
+fn load_certified_key(paths: &CertificatePaths) -> Result<Arc<CertifiedKey>, Error> {
+    let chain = load_pem_certificates(&paths.cert)?;
+
+    let mut key_reader = BufReader::new(File::open(&paths.key).map_err(|e| with_path(&paths.key, e))?);
+    let Some(key) = rustls_pemfile::private_key(&mut key_reader).map_err(|e| with_path(&paths.key, e))? else {
+        return Err(with_path(&paths.key, Error::new(ErrorKind::InvalidData, "no private key found")));
+    };
+
+    let signing_key = any_supported_type(&key).map_err(|e| with_path(&paths.key, Error::new(ErrorKind::InvalidData, e)))?;
+
+    Ok(Arc::new(CertifiedKey::new(chain, signing_key)))
+}
+
+fn load_pem_certificates(path: &Path) -> Result<Vec<CertificateDer<'static>>, Error> {
+    let mut reader = BufReader::new(File::open(path).map_err(|e| with_path(path, e))?);
+    let certificates = rustls_pemfile::certs(&mut reader)
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|e| with_path(path, e))?;
+
+    if certificates.is_empty() {
+        return Err(with_path(path, Error::new(ErrorKind::InvalidData, "no certificate found")));
+    }
+
+    Ok(certificates)
+}
+
+fn with_path(path: &Path, e: Error) -> Error {
+    Error::new(e.kind(), format!("{}: {}", path.display(), e))
+}
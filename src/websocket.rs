@@ -0,0 +1,50 @@
+use futures_lite::future;
+use http_types::{CloseFrame, PerMessageDeflate, Request, Response, WebSocket, WebSocketHandler};
+
+use crate::{connection::Connection, ConnectionContext, Server};
+
+// Sends the 101 response accepting the handshake, negotiating permessage-deflate when the
+// client offers it, then runs the handler on the connection until it returns or the server
+// shuts down
+pub(crate) async fn serve(
+    mut connection: Connection,
+    request: Request,
+    mut response: Response,
+    handler: WebSocketHandler,
+    context: &ConnectionContext,
+) {
+    let config = &context.config;
+
+    let deflate = match request.headers.get("sec-websocket-extensions") {
+        Some(offers) if config.websocket_compression => PerMessageDeflate::negotiate(offers),
+        _ => None,
+    };
+
+    if let Some(deflate) = &deflate {
+        response.set_header("sec-websocket-extensions", &deflate.to_header_value());
+    }
+
+    Server::send_response(&response, &mut connection, config).await;
+
+    let mut socket = WebSocket::new(
+        Box::new(connection),
+        deflate,
+        config.websocket_max_message_size,
+        config.write_timeout,
+    );
+
+    let code = future::or(
+        async {
+            handler(request, &mut socket).await;
+            CloseFrame::NORMAL
+        },
+        async {
+            context.shutdown.wait().await;
+            CloseFrame::GOING_AWAY
+        },
+    )
+    .await;
+
+    // Completes the close handshake unless the handler already did
+    socket.close(code, "").await;
+}
@@ -0,0 +1,261 @@
+use std::{collections::HashMap, net::SocketAddr, path::Path, time::Duration};
+
+use async_std::{future::timeout, io::ReadExt, net::TcpStream, task};
+use futures_lite::AsyncWriteExt;
+use rust_http_framework::*;
+
+#[route("POST", "/echo")]
+fn echo(req: &Request) -> Response {
+    Response::new(StatusCodes::OK, HashMap::new(), req.text().unwrap_or_default())
+}
+
+// Lists the parts as "name size storage", one per line
+#[route("POST", "/upload")]
+fn upload(req: &Request) -> Response {
+    let parts = match req.multipart() {
+        Ok(parts) => parts,
+        Err(status) => return Response::new(status, HashMap::new(), ""),
+    };
+
+    let lines: Vec<String> = parts
+        .iter()
+        .map(|part| {
+            let storage = match part.data {
+                PartData::Memory(_) => "memory",
+                PartData::File(_) => "file",
+            };
+            format!("{} {} {}", part.name, part.bytes().unwrap().len(), storage)
+        })
+        .collect();
+
+    Response::new(StatusCodes::OK, HashMap::new(), &lines.join("\n"))
+}
+
+async fn spawn_server() -> SpawnedServer {
+    spawn_server_with(ServerConfig::builder()).await
+}
+
+async fn spawn_server_with(builder: ServerConfigBuilder) -> SpawnedServer {
+    let config = builder.listen("127.0.0.1:0").handle_signals(false).build().unwrap();
+    let mut server = Server::from_config(config).unwrap();
+    server.add_routes(generate_routes![echo, upload]);
+
+    server.spawn().await.unwrap()
+}
+
+// Bodies are limited to 1 KiB, multipart bodies to 1 MiB with files over 4 KiB written to temp_dir
+async fn spawn_upload_server(temp_dir: &Path) -> SpawnedServer {
+    let limits = MultipartLimits {
+        max_part_size: 512 * 1024,
+        max_total_size: 1024 * 1024,
+        memory_threshold: 4 * 1024,
+        temp_dir: temp_dir.to_owned(),
+    };
+
+    spawn_server_with(ServerConfig::builder().max_body_size(1024).multipart_limits(limits)).await
+}
+
+fn multipart_body(file_size: usize) -> Vec<u8> {
+    let mut body = b"--XyZ\r\nContent-Disposition: form-data; name=\"title\"\r\n\r\nholiday\r\n\
+        --XyZ\r\nContent-Disposition: form-data; name=\"photo\"; filename=\"a.jpg\"\r\n\r\n"
+        .to_vec();
+    body.resize(body.len() + file_size, b'x');
+    body.extend_from_slice(b"\r\n--XyZ--\r\n");
+    body
+}
+
+fn upload_request(body: &[u8]) -> Vec<u8> {
+    let mut request = format!(
+        "POST /upload HTTP/1.1\r\nHost: a\r\nContent-Type: multipart/form-data; boundary=XyZ\r\nContent-Length: {}\r\n\r\n",
+        body.len()
+    )
+    .into_bytes();
+    request.extend_from_slice(body);
+    request
+}
+
+// Sends the body in chunks of 1000 bytes
+fn chunked_upload_request(body: &[u8]) -> Vec<u8> {
+    let mut request =
+        b"POST /upload HTTP/1.1\r\nHost: a\r\nContent-Type: multipart/form-data; boundary=XyZ\r\nTransfer-Encoding: chunked\r\n\r\n"
+            .to_vec();
+
+    for chunk in body.chunks(1000) {
+        request.extend_from_slice(format!("{:x}\r\n", chunk.len()).as_bytes());
+        request.extend_from_slice(chunk);
+        request.extend_from_slice(b"\r\n");
+    }
+
+    request.extend_from_slice(b"0\r\n\r\n");
+    request
+}
+
+// Sends the raw request and reads the response until the server closes the connection
+async fn send(addr: SocketAddr, request: &[u8]) -> String {
+    let mut stream = TcpStream::connect(addr).await.unwrap();
+    stream.write_all(request).await.unwrap();
+
+    let mut response = String::new();
+    stream.read_to_string(&mut response).await.unwrap();
+
+    response
+}
+
+// Same as send, for requests that are rejected before their body was read. The server closes the
+// connection with data still unread, so writing may fail and the response ends with a reset.
+async fn send_rejected(addr: SocketAddr, request: &[u8]) -> String {
+    let mut stream = TcpStream::connect(addr).await.unwrap();
+    let _ = stream.write_all(request).await;
+
+    let mut response = Vec::new();
+    let mut chunk = [0; 1024];
+
+    while let Ok(read @ 1..) = stream.read(&mut chunk).await {
+        response.extend_from_slice(&chunk[..read]);
+    }
+
+    String::from_utf8(response).unwrap()
+}
+
+#[test]
+fn decodes_chunked_bodies() {
+    task::block_on(async {
+        let server = spawn_server().await;
+        let request = b"POST /echo HTTP/1.1\r\nHost: a\r\nTransfer-Encoding: chunked\r\n\r\n5\r\nhello\r\n7\r\n, world\r\n0\r\n\r\n";
+        let response = send(server.local_addr(), request).await;
+
+        assert!(response.starts_with("HTTP/1.1 200"), "{}", response);
+        assert!(response.ends_with("hello, world"), "{}", response);
+
+        server.shutdown().await.unwrap();
+    });
+}
+
+#[test]
+fn rejects_ambiguous_framing() {
+    task::block_on(async {
+        let server = spawn_server().await;
+
+        let request = b"POST /echo HTTP/1.1\r\nHost: a\r\nContent-Length: 3\r\nTransfer-Encoding: chunked\r\n\r\n0\r\n\r\n";
+        assert!(send(server.local_addr(), request).await.starts_with("HTTP/1.1 400"));
+
+        let request = b"POST /echo HTTP/1.1\r\nHost: a\r\nTransfer-Encoding: gzip, chunked\r\n\r\n";
+        assert!(send(server.local_addr(), request).await.starts_with("HTTP/1.1 501"));
+
+        let request = b"POST /echo HTTP/1.1\r\nContent-Length: 0\r\n\r\n";
+        assert!(send(server.local_addr(), request).await.starts_with("HTTP/1.1 400"));
+
+        server.shutdown().await.unwrap();
+    });
+}
+
+#[test]
+fn rejects_unknown_methods() {
+    task::block_on(async {
+        let server = spawn_server().await;
+
+        // Not a method the server knows, which is not the same as one the route does not take
+        let request = b"BREW /echo HTTP/1.1\r\nHost: a\r\nContent-Length: 0\r\n\r\n";
+        let response = send(server.local_addr(), request).await;
+        assert!(response.starts_with("HTTP/1.1 501"), "{}", response);
+
+        // Methods are case-sensitive, RFC 9110, section 9.1
+        let request = b"post /echo HTTP/1.1\r\nHost: a\r\nContent-Length: 0\r\n\r\n";
+        assert!(send(server.local_addr(), request).await.starts_with("HTTP/1.1 501"));
+
+        server.shutdown().await.unwrap();
+    });
+}
+
+#[test]
+fn streams_multipart_uploads() {
+    task::block_on(async {
+        let dir = tempfile::TempDir::new().unwrap();
+        let server = spawn_upload_server(dir.path()).await;
+
+        // Larger than max_body_size, but within the multipart limits
+        let body = multipart_body(300 * 1024);
+
+        for request in [upload_request(&body), chunked_upload_request(&body)] {
+            let response = send(server.local_addr(), &request).await;
+
+            assert!(response.starts_with("HTTP/1.1 200"), "{}", response);
+            assert!(response.ends_with("title 7 memory\nphoto 307200 file"), "{}", response);
+        }
+
+        // The temporary files are gone along with the request
+        assert_eq!(std::fs::read_dir(dir.path()).unwrap().count(), 0);
+
+        // Other bodies are still limited by max_body_size
+        let request = b"POST /echo HTTP/1.1\r\nHost: a\r\nContent-Length: 1025\r\n\r\n";
+        assert!(send(server.local_addr(), request).await.starts_with("HTTP/1.1 413"));
+
+        server.shutdown().await.unwrap();
+    });
+}
+
+#[test]
+fn rejects_invalid_multipart_uploads() {
+    task::block_on(async {
+        let dir = tempfile::TempDir::new().unwrap();
+        let server = spawn_upload_server(dir.path()).await;
+
+        // Part and body limits, checked as the body arrives when it is chunked
+        for request in [
+            upload_request(&multipart_body(600 * 1024)),
+            upload_request(&multipart_body(1024 * 1024)),
+            chunked_upload_request(&multipart_body(600 * 1024)),
+            chunked_upload_request(&multipart_body(1024 * 1024)),
+        ] {
+            let response = send_rejected(server.local_addr(), &request).await;
+            assert!(response.starts_with("HTTP/1.1 413"), "{}", response);
+        }
+
+        // Cut off before the closing boundary
+        let body = multipart_body(10);
+        let response = send(server.local_addr(), &upload_request(&body[..body.len() - 9])).await;
+        assert!(response.starts_with("HTTP/1.1 400"), "{}", response);
+
+        // Without a boundary
+        let request = b"POST /upload HTTP/1.1\r\nHost: a\r\nContent-Type: multipart/form-data\r\nContent-Length: 2\r\n\r\n--";
+        assert!(send(server.local_addr(), request).await.starts_with("HTTP/1.1 400"));
+
+        // Only multipart bodies have parts
+        let request = b"POST /upload HTTP/1.1\r\nHost: a\r\nContent-Length: 2\r\n\r\nhi";
+        assert!(send(server.local_addr(), request).await.starts_with("HTTP/1.1 415"));
+
+        server.shutdown().await.unwrap();
+    });
+}
+
+#[test]
+fn closes_connections_over_the_rejection_cap() {
+    task::block_on(async {
+        // Connections wait for a PROXY header, which keeps the rejected ones busy as well
+        let builder = ServerConfig::builder()
+            .max_connections(1)
+            .overload_policy(OverloadPolicy::Reject)
+            .proxy_protocol(true)
+            .shutdown_grace_period(Duration::from_secs(1));
+        let server = spawn_server_with(builder).await;
+
+        let _accepted = TcpStream::connect(server.local_addr()).await.unwrap();
+
+        let request = b"PROXY TCP4 10.0.0.1 10.0.0.2 1111 80\r\nGET /echo HTTP/1.1\r\nHost: a\r\n\r\n";
+        assert!(send_rejected(server.local_addr(), request).await.starts_with("HTTP/1.1 503"));
+
+        // The server answers 64 rejected connections at once
+        let mut rejected = Vec::new();
+        for _ in 0..64 {
+            rejected.push(TcpStream::connect(server.local_addr()).await.unwrap());
+        }
+
+        let mut stream = TcpStream::connect(server.local_addr()).await.unwrap();
+        let mut response = Vec::new();
+        let read = timeout(Duration::from_secs(5), stream.read_to_end(&mut response)).await;
+        assert!(read.is_ok_and(|read| read.is_err() || response.is_empty()));
+
+        drop(rejected);
+        server.shutdown().await.unwrap();
+    });
+}
@@ -0,0 +1,292 @@
+#![cfg(feature = "http2")]
+
+use std::{
+    collections::HashMap,
+    sync::atomic::{AtomicUsize, Ordering},
+    time::Duration,
+};
+
+use async_compat::Compat;
+use async_std::{io::ReadExt, net::TcpStream, task};
+use futures_lite::{AsyncRead, AsyncWrite, AsyncWriteExt};
+use rust_http_framework::*;
+
+const PREFACE: &[u8] = b"PRI * HTTP/2.0\r\n\r\nSM\r\n\r\n";
+const FRAME_TYPE_DATA: u8 = 0x0;
+const FRAME_TYPE_HEADERS: u8 = 0x1;
+const FLAG_END_STREAM: u8 = 0x1;
+const FLAG_END_HEADERS: u8 = 0x4;
+
+// SETTINGS_MAX_CONCURRENT_STREAMS of 100 and SETTINGS_INITIAL_WINDOW_SIZE of 65535
+const UPGRADE_SETTINGS: &str = "AAMAAABkAAQAAP__";
+
+// Counts the calls of the second route for /hello, which HTTP/2 streams never reach. Requests
+// over HTTP/1.1 run every matching route, so they go to /version instead.
+static SHADOWED_CALLS: AtomicUsize = AtomicUsize::new(0);
+
+#[route("GET", "/hello")]
+fn hello(req: &Request) -> Response {
+    let body = format!("hello {:?} {}", req.version(), req.host().unwrap_or_default());
+    Response::new(StatusCodes::OK, HashMap::new(), &body)
+}
+
+#[route("GET", "/hello")]
+fn shadowed(_req: &Request) -> Response {
+    SHADOWED_CALLS.fetch_add(1, Ordering::SeqCst);
+    Response::new(StatusCodes::OK, HashMap::new(), "shadowed")
+}
+
+#[route("GET", "/version")]
+fn version(req: &Request) -> Response {
+    Response::new(StatusCodes::OK, HashMap::new(), &format!("{:?}", req.version()))
+}
+
+fn server() -> Server {
+    let config = ServerConfig::builder()
+        .listen("127.0.0.1:0")
+        .handle_signals(false)
+        .shutdown_grace_period(Duration::from_secs(1))
+        .max_request_line_size(32 * 1024)
+        .build()
+        .unwrap();
+
+    let mut server = Server::from_config(config).unwrap();
+    server.add_routes(generate_routes![hello, shadowed, version]);
+    server
+}
+
+async fn h2_get(io: impl AsyncRead + AsyncWrite + Unpin + Send + 'static, path: &str) -> (u16, String) {
+    h2_request(io, "GET", path).await
+}
+
+// Sends a request without a body with the h2 client, returns the status and body of the response
+async fn h2_request(io: impl AsyncRead + AsyncWrite + Unpin + Send + 'static, method: &str, path: &str) -> (u16, String) {
+    let (client, connection) = h2::client::handshake(Compat::new(io)).await.unwrap();
+    task::spawn(async move {
+        let _ = connection.await;
+    });
+
+    let mut client = client.ready().await.unwrap();
+    let request = http::Request::builder()
+        .method(method)
+        .uri(format!("http://localhost{}", path))
+        .body(())
+        .unwrap();
+    let (response, _) = client.send_request(request, true).unwrap();
+
+    let response = response.await.unwrap();
+    let status = response.status().as_u16();
+    let mut body = response.into_body();
+    let mut data = Vec::new();
+
+    while let Some(chunk) = body.data().await {
+        let chunk = chunk.unwrap();
+        body.flow_control().release_capacity(chunk.len()).unwrap();
+        data.extend_from_slice(&chunk);
+    }
+
+    (status, String::from_utf8(data).unwrap())
+}
+
+// Reads an HTTP/1.1 response head and its body, leaving whatever follows on the connection
+async fn read_http1_response(stream: &mut TcpStream) -> String {
+    let mut response = Vec::new();
+    let mut byte = [0];
+
+    while !response.ends_with(b"\r\n\r\n") {
+        stream.read_exact(&mut byte).await.unwrap();
+        response.push(byte[0]);
+    }
+
+    let head = String::from_utf8(response).unwrap();
+    let length = head
+        .lines()
+        .find_map(|line| line.to_ascii_lowercase().strip_prefix("content-length:").map(|length| length.trim().parse().unwrap()))
+        .unwrap_or(0);
+
+    let mut body = vec![0; length];
+    stream.read_exact(&mut body).await.unwrap();
+
+    head + &String::from_utf8(body).unwrap()
+}
+
+fn upgrade_request(path: &str, settings: &str) -> String {
+    format!(
+        "GET {} HTTP/1.1\r\nHost: localhost\r\nConnection: Upgrade, HTTP2-Settings\r\nUpgrade: h2c\r\nHTTP2-Settings: {}\r\n\r\n",
+        path, settings
+    )
+}
+
+fn frame(frame_type: u8, flags: u8, stream_id: u32, payload: &[u8]) -> Vec<u8> {
+    let len = (payload.len() as u32).to_be_bytes();
+
+    let mut frame = vec![len[1], len[2], len[3], frame_type, flags];
+    frame.extend_from_slice(&stream_id.to_be_bytes());
+    frame.extend_from_slice(payload);
+    frame
+}
+
+// Header fields as literals without indexing, short enough for a single length byte
+fn headers_block(fields: &[(&str, &str)]) -> Vec<u8> {
+    let mut block = Vec::new();
+
+    for (name, value) in fields {
+        block.push(0x00);
+        block.push(name.len() as u8);
+        block.extend_from_slice(name.as_bytes());
+        block.push(value.len() as u8);
+        block.extend_from_slice(value.as_bytes());
+    }
+
+    block
+}
+
+// Reads frames until the stream ends, returns the first byte of its header block and its data
+async fn read_stream(stream: &mut TcpStream, stream_id: u32) -> (u8, String) {
+    let mut status = None;
+    let mut data = Vec::new();
+
+    loop {
+        let mut header = [0; 9];
+        stream.read_exact(&mut header).await.unwrap();
+
+        let len = u32::from_be_bytes([0, header[0], header[1], header[2]]) as usize;
+        let id = u32::from_be_bytes([header[5], header[6], header[7], header[8]]) & 0x7fff_ffff;
+
+        let mut payload = vec![0; len];
+        stream.read_exact(&mut payload).await.unwrap();
+
+        if id != stream_id {
+            continue;
+        }
+
+        match header[3] {
+            FRAME_TYPE_HEADERS => status = Some(payload[0]),
+            FRAME_TYPE_DATA => data.extend_from_slice(&payload),
+            _ => {}
+        }
+
+        if header[4] & FLAG_END_STREAM != 0 {
+            return (status.unwrap(), String::from_utf8(data).unwrap());
+        }
+    }
+}
+
+#[test]
+fn serves_clients_with_prior_knowledge() {
+    task::block_on(async {
+        let server = server().spawn().await.unwrap();
+        let stream = TcpStream::connect(server.local_addr()).await.unwrap();
+
+        assert_eq!(h2_get(stream, "/hello").await, (200, "hello HTTP2 localhost".to_owned()));
+        assert_eq!(h2_get(TcpStream::connect(server.local_addr()).await.unwrap(), "/missing").await.0, 404);
+
+        let stream = TcpStream::connect(server.local_addr()).await.unwrap();
+        assert_eq!(h2_request(stream, "BREW", "/hello").await.0, 501);
+
+        // Only the first matching route answers a stream
+        assert_eq!(SHADOWED_CALLS.load(Ordering::SeqCst), 0);
+
+        server.shutdown().await.unwrap();
+    });
+}
+
+#[test]
+fn upgrades_cleartext_connections() {
+    task::block_on(async {
+        let server = server().spawn().await.unwrap();
+        let mut stream = TcpStream::connect(server.local_addr()).await.unwrap();
+
+        stream.write_all(upgrade_request("/hello", UPGRADE_SETTINGS).as_bytes()).await.unwrap();
+        let response = read_http1_response(&mut stream).await;
+        assert!(response.starts_with("HTTP/1.1 101"), "{}", response);
+
+        // The h2 client can't take over a connection that started as HTTP/1.1, since stream 1 is
+        // already open, so the frames are written and read by hand
+        stream.write_all(PREFACE).await.unwrap();
+        stream.write_all(&frame(0x4, 0, 0, &[])).await.unwrap();
+
+        // The request that asked for the upgrade is answered on stream 1, 0x88 being :status 200
+        assert_eq!(read_stream(&mut stream, 1).await, (0x88, "hello HTTP2 localhost".to_owned()));
+
+        // The HPACK state survived the HEADERS frame that was slipped in for stream 1
+        let block = headers_block(&[(":method", "GET"), (":scheme", "http"), (":authority", "other"), (":path", "/hello")]);
+        stream.write_all(&frame(FRAME_TYPE_HEADERS, FLAG_END_STREAM | FLAG_END_HEADERS, 3, &block)).await.unwrap();
+        assert_eq!(read_stream(&mut stream, 3).await, (0x88, "hello HTTP2 other".to_owned()));
+
+        assert_eq!(SHADOWED_CALLS.load(Ordering::SeqCst), 0);
+
+        drop(stream);
+        server.shutdown().await.unwrap();
+    });
+}
+
+#[test]
+fn ignores_upgrades_it_can_not_take() {
+    task::block_on(async {
+        let server = server().spawn().await.unwrap();
+
+        // Settings that are not base64url, that are cut short, or that enable push with a value of 2.
+        // Then a request too large for the HEADERS frame of stream 1.
+        let long_path = format!("/version?q={}", "a".repeat(17_000));
+
+        for (path, settings) in [
+            ("/version", "not base64!"),
+            ("/version", "AAMAAABkAA"),
+            ("/version", "AAIAAAAC"),
+            (long_path.as_str(), UPGRADE_SETTINGS),
+        ] {
+            let mut stream = TcpStream::connect(server.local_addr()).await.unwrap();
+            stream.write_all(upgrade_request(path, settings).as_bytes()).await.unwrap();
+
+            let response = read_http1_response(&mut stream).await;
+            assert!(response.starts_with("HTTP/1.1 200"), "{}", settings);
+            assert!(response.ends_with("HTTP1_1"), "{}", response);
+        }
+
+        server.shutdown().await.unwrap();
+    });
+}
+
+#[cfg(feature = "tls")]
+#[test]
+fn negotiates_http2_over_tls() {
+    use std::sync::Arc;
+
+    use futures_rustls::{
+        rustls::{crypto::ring::default_provider, pki_types::ServerName, ClientConfig, RootCertStore},
+        TlsConnector,
+    };
+
+    task::block_on(async {
+        let dir = tempfile::TempDir::new().unwrap();
+        let certified = rcgen::generate_simple_self_signed(vec!["localhost".to_owned()]).unwrap();
+        let (cert, key) = (dir.path().join("server.crt"), dir.path().join("server.key"));
+        std::fs::write(&cert, certified.cert.pem()).unwrap();
+        std::fs::write(&key, certified.key_pair.serialize_pem()).unwrap();
+
+        let mut server = server();
+        server.set_tls(TlsConfig::new(cert, key)).unwrap();
+        let server = server.spawn().await.unwrap();
+
+        let mut roots = RootCertStore::empty();
+        roots.add(certified.cert.der().clone()).unwrap();
+
+        let mut config = ClientConfig::builder_with_provider(Arc::new(default_provider()))
+            .with_safe_default_protocol_versions()
+            .unwrap()
+            .with_root_certificates(roots)
+            .with_no_client_auth();
+        config.alpn_protocols = vec![b"h2".to_vec(), b"http/1.1".to_vec()];
+
+        let stream = TcpStream::connect(server.local_addr()).await.unwrap();
+        let server_name = ServerName::try_from("localhost").unwrap();
+        let stream = TlsConnector::from(Arc::new(config)).connect(server_name, stream).await.unwrap();
+        assert_eq!(stream.get_ref().1.alpn_protocol(), Some(b"h2".as_slice()));
+
+        assert_eq!(h2_get(stream, "/hello").await, (200, "hello HTTP2 localhost".to_owned()));
+        assert_eq!(SHADOWED_CALLS.load(Ordering::SeqCst), 0);
+
+        server.shutdown().await.unwrap();
+    });
+}
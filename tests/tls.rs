@@ -0,0 +1,402 @@
+#![cfg(feature = "tls")]
+
+use std::{
+    collections::HashMap,
+    fs,
+    io::Error,
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use async_std::{io::ReadExt, net::TcpStream, task};
+use futures_lite::AsyncWriteExt;
+use futures_rustls::{
+    client::TlsStream,
+    rustls::{
+        crypto::ring::default_provider,
+        pki_types::{CertificateDer, PrivatePkcs8KeyDer, ServerName},
+        ClientConfig, RootCertStore,
+    },
+    TlsConnector,
+};
+use rcgen::{BasicConstraints, CertificateParams, DnType, ExtendedKeyUsagePurpose, IsCa, KeyPair, SanType};
+use rust_http_framework::*;
+use tempfile::TempDir;
+
+#[route("GET", "/")]
+fn index(req: &Request) -> Response {
+    Response::new(StatusCodes::OK, HashMap::new(), &format!("{} {}", req.scheme(), req.is_secure()))
+}
+
+#[route("GET", "/whoami")]
+fn whoami(req: &Request) -> Response {
+    let Some(certificate) = req.client_certificate() else {
+        return Response::new(StatusCodes::Unauthorized, HashMap::new(), "");
+    };
+
+    let body = [
+        certificate.subject.clone(),
+        format!("{:?}", certificate.common_name),
+        certificate.dns_names.join(","),
+        certificate.uris.join(","),
+        certificate.emails.join(","),
+        format!("{:?}", certificate.ip_addresses),
+        format!("{}", certificate.has_identity("spiffe://example.org/billing")),
+        format!("{}", certificate.has_identity("BILLING.internal")),
+        format!("{}", certificate.has_identity("billing")),
+        format!("{}", certificate.has_identity("spiffe://example.org/other")),
+    ];
+
+    Response::new(StatusCodes::OK, HashMap::new(), &body.join("\n"))
+}
+
+// Issues the certificates used by the tests, generated on the fly
+struct Ca {
+    certificate: rcgen::Certificate,
+    key: KeyPair,
+}
+
+impl Ca {
+    fn new(name: &str) -> Ca {
+        let mut params = CertificateParams::new(Vec::<String>::new()).unwrap();
+        params.distinguished_name.push(DnType::CommonName, name);
+        params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+
+        let key = KeyPair::generate().unwrap();
+        let certificate = params.self_signed(&key).unwrap();
+
+        Ca { certificate, key }
+    }
+
+    fn issue(&self, params: CertificateParams) -> (rcgen::Certificate, KeyPair) {
+        let key = KeyPair::generate().unwrap();
+        let certificate = params.signed_by(&key, &self.certificate, &self.key).unwrap();
+
+        (certificate, key)
+    }
+
+    // Writes a server certificate for the names to dir
+    fn server_certificate(&self, dir: &Path, file_name: &str, names: &[&str]) -> Issued {
+        let params = CertificateParams::new(names.iter().map(|name| name.to_string()).collect::<Vec<_>>()).unwrap();
+        let (certificate, key) = self.issue(params);
+
+        write_pair(dir, file_name, &certificate, &key)
+    }
+
+    // A certificate for clients to authenticate with
+    fn client_certificate(&self, common_name: &str, alt_names: Vec<SanType>) -> (Vec<CertificateDer<'static>>, KeyPair) {
+        let mut params = CertificateParams::new(Vec::<String>::new()).unwrap();
+        params.distinguished_name.push(DnType::CommonName, common_name);
+        params.distinguished_name.push(DnType::OrganizationName, "Example Corp");
+        params.subject_alt_names = alt_names;
+        params.extended_key_usages = vec![ExtendedKeyUsagePurpose::ClientAuth];
+
+        let (certificate, key) = self.issue(params);
+
+        (vec![certificate.der().clone()], key)
+    }
+
+    // Writes the CA certificate to dir, as the bundle client certificates are verified against
+    fn write_bundle(&self, dir: &Path) -> PathBuf {
+        let path = dir.join("clients-ca.crt");
+        fs::write(&path, self.certificate.pem()).unwrap();
+        path
+    }
+
+    fn roots(&self) -> RootCertStore {
+        let mut roots = RootCertStore::empty();
+        roots.add(self.certificate.der().clone()).unwrap();
+        roots
+    }
+}
+
+// A certificate written to disk as PEM files
+struct Issued {
+    cert: PathBuf,
+    key: PathBuf,
+    der: CertificateDer<'static>,
+}
+
+fn write_pair(dir: &Path, file_name: &str, certificate: &rcgen::Certificate, key: &KeyPair) -> Issued {
+    let cert = dir.join(format!("{}.crt", file_name));
+    let key_path = dir.join(format!("{}.key", file_name));
+
+    fs::write(&cert, certificate.pem()).unwrap();
+    fs::write(&key_path, key.serialize_pem()).unwrap();
+
+    Issued {
+        cert,
+        key: key_path,
+        der: certificate.der().clone(),
+    }
+}
+
+fn connector(config: ClientConfig) -> TlsConnector {
+    TlsConnector::from(Arc::new(config))
+}
+
+fn client_config(roots: RootCertStore) -> ClientConfig {
+    ClientConfig::builder_with_provider(Arc::new(default_provider()))
+        .with_safe_default_protocol_versions()
+        .unwrap()
+        .with_root_certificates(roots)
+        .with_no_client_auth()
+}
+
+fn client_config_with_certificate(roots: RootCertStore, chain: Vec<CertificateDer<'static>>, key: &KeyPair) -> ClientConfig {
+    let key = PrivatePkcs8KeyDer::from(key.serialize_der());
+
+    ClientConfig::builder_with_provider(Arc::new(default_provider()))
+        .with_safe_default_protocol_versions()
+        .unwrap()
+        .with_root_certificates(roots)
+        .with_client_auth_cert(chain, key.into())
+        .unwrap()
+}
+
+async fn spawn_server(tls: TlsConfig) -> SpawnedServer {
+    let config = ServerConfig::builder().listen("127.0.0.1:0").handle_signals(false).build().unwrap();
+    let mut server = Server::from_config(config).unwrap();
+    server.add_routes(generate_routes![index, whoami]);
+    server.set_tls(tls).unwrap();
+
+    server.spawn().await.unwrap()
+}
+
+async fn handshake(connector: &TlsConnector, addr: SocketAddr, server_name: &str) -> Result<TlsStream<TcpStream>, Error> {
+    let stream = TcpStream::connect(addr).await?;
+    let server_name = ServerName::try_from(server_name.to_owned()).unwrap();
+
+    connector.connect(server_name, stream).await
+}
+
+// Sends a GET request for the path and reads the response until the server closes the connection
+async fn get(connector: &TlsConnector, addr: SocketAddr, path: &str) -> Result<String, Error> {
+    let mut stream = handshake(connector, addr, "localhost").await?;
+    let request = format!("GET {} HTTP/1.1\r\nHost: localhost\r\n\r\n", path);
+    stream.write_all(request.as_bytes()).await?;
+
+    let mut response = String::new();
+    stream.read_to_string(&mut response).await?;
+
+    Ok(response)
+}
+
+// The certificate the server presents for the name, the connection is closed right away
+async fn served_certificate(connector: &TlsConnector, addr: SocketAddr, server_name: &str) -> CertificateDer<'static> {
+    let stream = handshake(connector, addr, server_name).await.unwrap();
+    stream.get_ref().1.peer_certificates().unwrap()[0].clone().into_owned()
+}
+
+#[test]
+fn serves_requests_over_tls() {
+    task::block_on(async {
+        let dir = TempDir::new().unwrap();
+        let ca = Ca::new("Test CA");
+        let issued = ca.server_certificate(dir.path(), "server", &["localhost"]);
+
+        let server = spawn_server(TlsConfig::new(issued.cert, issued.key)).await;
+        let response = get(&connector(client_config(ca.roots())), server.local_addr(), "/").await.unwrap();
+
+        assert!(response.starts_with("HTTP/1.1 200"), "{}", response);
+        assert!(response.ends_with("https true"), "{}", response);
+
+        server.shutdown().await.unwrap();
+    });
+}
+
+#[test]
+fn reports_missing_and_invalid_files() {
+    let dir = TempDir::new().unwrap();
+    let ca = Ca::new("Test CA");
+    let Issued { cert, key, .. } = ca.server_certificate(dir.path(), "server", &["localhost"]);
+
+    let mut server = Server::new("0").unwrap();
+
+    let error = server.set_tls(TlsConfig::new(dir.path().join("missing.crt"), &key)).unwrap_err();
+    assert!(error.to_string().contains("missing.crt"), "{}", error);
+
+    // The certificate file holds no private key
+    let error = server.set_tls(TlsConfig::new(&cert, &cert)).unwrap_err();
+    assert!(error.to_string().contains("no private key found"), "{}", error);
+
+    assert!(server.set_tls(TlsConfig::default()).is_err());
+    assert!(server.set_tls(TlsConfig::new(&cert, &key)).is_ok());
+}
+
+#[test]
+fn negotiates_the_protocol_through_alpn() {
+    task::block_on(async {
+        let dir = TempDir::new().unwrap();
+        let ca = Ca::new("Test CA");
+        let issued = ca.server_certificate(dir.path(), "server", &["localhost"]);
+
+        let server = spawn_server(TlsConfig::new(issued.cert, issued.key)).await;
+
+        let mut config = client_config(ca.roots());
+        config.alpn_protocols = vec![b"h2".to_vec(), b"http/1.1".to_vec()];
+
+        let stream = handshake(&connector(config), server.local_addr(), "localhost").await.unwrap();
+        let expected: &[u8] = if cfg!(feature = "http2") { b"h2" } else { b"http/1.1" };
+
+        assert_eq!(stream.get_ref().1.alpn_protocol(), Some(expected));
+
+        drop(stream);
+        server.shutdown().await.unwrap();
+    });
+}
+
+#[test]
+fn selects_the_certificate_by_server_name() {
+    task::block_on(async {
+        let dir = TempDir::new().unwrap();
+        let ca = Ca::new("Test CA");
+        let default = ca.server_certificate(dir.path(), "default", &["other.test", "a.b.example.com"]);
+        let exact = ca.server_certificate(dir.path(), "exact", &["exact.example.com"]);
+        let wildcard = ca.server_certificate(dir.path(), "wildcard", &["*.example.com"]);
+
+        let tls = TlsConfig::new(&default.cert, &default.key)
+            .sni_certificate("Exact.Example.com", &exact.cert, &exact.key)
+            .sni_certificate("*.example.com", &wildcard.cert, &wildcard.key);
+        let server = spawn_server(tls).await;
+
+        let connector = connector(client_config(ca.roots()));
+
+        // A leading "*." matches a single label only
+        for (server_name, expected) in [
+            ("exact.example.com", &exact),
+            ("api.example.com", &wildcard),
+            ("a.b.example.com", &default),
+            ("other.test", &default),
+        ] {
+            let served = served_certificate(&connector, server.local_addr(), server_name).await;
+            assert_eq!(served, expected.der, "{}", server_name);
+        }
+
+        server.shutdown().await.unwrap();
+    });
+}
+
+#[test]
+fn reloads_certificates_that_changed() {
+    task::block_on(async {
+        let dir = TempDir::new().unwrap();
+        let ca = Ca::new("Test CA");
+        let first = ca.server_certificate(dir.path(), "server", &["localhost"]);
+
+        let tls = TlsConfig::new(&first.cert, &first.key).reload_interval(Duration::from_millis(20));
+        let server = spawn_server(tls).await;
+        let connector = connector(client_config(ca.roots()));
+
+        assert_eq!(served_certificate(&connector, server.local_addr(), "localhost").await, first.der);
+
+        // A file that can't be loaded, e.g. one that is half-written, keeps the current certificate
+        fs::write(&first.cert, "").unwrap();
+        task::sleep(Duration::from_millis(100)).await;
+
+        assert_eq!(served_certificate(&connector, server.local_addr(), "localhost").await, first.der);
+
+        // Written elsewhere and moved in place, so that the files are never seen half-written
+        let second = ca.server_certificate(dir.path(), "renewed", &["localhost"]);
+        fs::rename(&second.key, &first.key).unwrap();
+        fs::rename(&second.cert, &first.cert).unwrap();
+
+        let deadline = Instant::now() + Duration::from_secs(5);
+
+        loop {
+            if served_certificate(&connector, server.local_addr(), "localhost").await == second.der {
+                break;
+            }
+
+            assert!(Instant::now() < deadline, "the renewed certificate was not loaded");
+            task::sleep(Duration::from_millis(20)).await;
+        }
+
+        server.shutdown().await.unwrap();
+    });
+}
+
+// A server that requires client certificates issued by client_ca
+async fn spawn_mtls_server(dir: &Path, ca: &Ca, client_ca: &Ca) -> SpawnedServer {
+    let issued = ca.server_certificate(dir, "server", &["localhost"]);
+    let tls = TlsConfig::new(issued.cert, issued.key).require_client_certificate(client_ca.write_bundle(dir));
+
+    spawn_server(tls).await
+}
+
+#[test]
+fn rejects_clients_without_a_certificate() {
+    task::block_on(async {
+        let dir = TempDir::new().unwrap();
+        let ca = Ca::new("Test CA");
+        let server = spawn_mtls_server(dir.path(), &ca, &ca).await;
+
+        // With TLS 1.3 the client only learns about it once it reads
+        let error = get(&connector(client_config(ca.roots())), server.local_addr(), "/whoami").await.unwrap_err();
+        assert!(error.to_string().contains("CertificateRequired"), "{}", error);
+
+        server.shutdown().await.unwrap();
+    });
+}
+
+#[test]
+fn rejects_certificates_from_other_cas() {
+    task::block_on(async {
+        let dir = TempDir::new().unwrap();
+        let ca = Ca::new("Test CA");
+        let server = spawn_mtls_server(dir.path(), &ca, &ca).await;
+
+        let untrusted = Ca::new("Untrusted CA");
+        let (chain, key) = untrusted.client_certificate("billing", Vec::new());
+        let connector = connector(client_config_with_certificate(ca.roots(), chain, &key));
+
+        let error = get(&connector, server.local_addr(), "/whoami").await.unwrap_err();
+        assert!(error.to_string().contains("UnknownCA"), "{}", error);
+
+        server.shutdown().await.unwrap();
+    });
+}
+
+#[test]
+fn exposes_the_client_certificate() {
+    task::block_on(async {
+        let dir = TempDir::new().unwrap();
+        let ca = Ca::new("Test CA");
+        let server = spawn_mtls_server(dir.path(), &ca, &ca).await;
+
+        let alt_names = vec![
+            SanType::DnsName("billing.internal".try_into().unwrap()),
+            SanType::URI("spiffe://example.org/billing".try_into().unwrap()),
+            SanType::Rfc822Name("billing@example.org".try_into().unwrap()),
+            SanType::IpAddress([10, 0, 0, 7].into()),
+        ];
+        let (chain, key) = ca.client_certificate("billing", alt_names);
+        let connector = connector(client_config_with_certificate(ca.roots(), chain, &key));
+
+        let response = get(&connector, server.local_addr(), "/whoami").await.unwrap();
+        let (head, body) = response.split_once("\r\n\r\n").unwrap();
+        let lines: Vec<_> = body.lines().collect();
+
+        assert!(head.starts_with("HTTP/1.1 200"), "{}", response);
+        assert_eq!(
+            lines,
+            [
+                "CN=billing, O=Example Corp",
+                "Some(\"billing\")",
+                "billing.internal",
+                "spiffe://example.org/billing",
+                "billing@example.org",
+                "[10.0.0.7]",
+                // Matched by URI, DNS name ignoring case and common name, but not by anything else
+                "true",
+                "true",
+                "true",
+                "false",
+            ]
+        );
+
+        server.shutdown().await.unwrap();
+    });
+}